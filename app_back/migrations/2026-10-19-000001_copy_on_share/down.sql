@@ -0,0 +1,5 @@
+ALTER TABLE pictures
+    DROP FOREIGN KEY FK_pictures_blob,
+    DROP COLUMN blob_id;
+
+DROP TABLE IF EXISTS blobs;
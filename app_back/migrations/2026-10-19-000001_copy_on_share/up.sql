@@ -0,0 +1,12 @@
+CREATE TABLE blobs
+(
+    CONSTRAINT PK_blobs PRIMARY KEY (id),
+    id        BIGINT UNSIGNED AUTO_INCREMENT,
+    hash      BINARY(32)      NOT NULL UNIQUE,
+    size_ko   BIGINT UNSIGNED NOT NULL,
+    ref_count INT UNSIGNED    NOT NULL DEFAULT 0
+);
+
+ALTER TABLE pictures
+    ADD COLUMN blob_id BIGINT UNSIGNED DEFAULT NULL AFTER author_id,
+    ADD CONSTRAINT FK_pictures_blob FOREIGN KEY (blob_id) REFERENCES blobs (id);
@@ -0,0 +1,13 @@
+DELETE FROM totp_secrets WHERE confirmed = FALSE;
+DELETE t1 FROM totp_secrets t1
+    INNER JOIN totp_secrets t2 ON t1.user_id = t2.user_id AND t1.id > t2.id;
+
+ALTER TABLE totp_secrets
+    DROP PRIMARY KEY,
+    DROP COLUMN id,
+    DROP COLUMN name,
+    DROP COLUMN confirmed,
+    ADD CONSTRAINT PK_totp_secrets PRIMARY KEY (user_id);
+
+ALTER TABLE totp_secrets
+    DROP INDEX IDX_totp_secrets_user_id;
@@ -0,0 +1,14 @@
+-- The foreign key on user_id must keep an index once the primary key is dropped
+ALTER TABLE totp_secrets
+    ADD INDEX IDX_totp_secrets_user_id (user_id);
+
+ALTER TABLE totp_secrets
+    DROP PRIMARY KEY,
+    ADD COLUMN id INT UNSIGNED AUTO_INCREMENT FIRST,
+    ADD CONSTRAINT PK_totp_secrets PRIMARY KEY (id),
+    ADD COLUMN name VARCHAR(32) NOT NULL DEFAULT 'Authenticator' AFTER user_id,
+    -- Existing secrets were inserted already confirmed
+    ADD COLUMN confirmed BOOLEAN NOT NULL DEFAULT TRUE;
+
+ALTER TABLE totp_secrets
+    ALTER COLUMN confirmed SET DEFAULT FALSE;
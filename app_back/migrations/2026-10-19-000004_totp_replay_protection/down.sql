@@ -0,0 +1,2 @@
+ALTER TABLE totp_secrets
+    DROP COLUMN last_used_step;
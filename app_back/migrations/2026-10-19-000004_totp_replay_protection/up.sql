@@ -0,0 +1,2 @@
+ALTER TABLE totp_secrets
+    ADD COLUMN last_used_step BIGINT UNSIGNED DEFAULT NULL;
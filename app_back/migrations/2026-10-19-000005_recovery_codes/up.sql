@@ -0,0 +1,11 @@
+CREATE TABLE recovery_codes
+(
+    CONSTRAINT PK_recovery_codes PRIMARY KEY (id),
+    CONSTRAINT UQ_recovery_codes UNIQUE (user_id, code_hash),
+    id            INT UNSIGNED AUTO_INCREMENT,
+    user_id       INT UNSIGNED NOT NULL,
+    code_hash     BINARY(32)   NOT NULL,
+    creation_date DATETIME     NOT NULL DEFAULT (UTC_TIMESTAMP()),
+    used_date     DATETIME              DEFAULT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
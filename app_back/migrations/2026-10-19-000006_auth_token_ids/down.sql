@@ -0,0 +1,3 @@
+ALTER TABLE auth_tokens
+    DROP INDEX UQ_auth_tokens_id,
+    DROP COLUMN id;
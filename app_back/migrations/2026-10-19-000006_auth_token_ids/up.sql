@@ -0,0 +1,3 @@
+ALTER TABLE auth_tokens
+    ADD COLUMN id INT UNSIGNED NOT NULL AUTO_INCREMENT FIRST,
+    ADD CONSTRAINT UQ_auth_tokens_id UNIQUE (id);
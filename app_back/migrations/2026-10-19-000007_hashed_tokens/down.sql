@@ -0,0 +1,17 @@
+DELETE FROM auth_tokens;
+DELETE FROM confirmations;
+
+ALTER TABLE confirmations
+    CHANGE COLUMN token_hash token BINARY(16) NOT NULL,
+    CHANGE COLUMN code_token_hash code_token BINARY(16) NOT NULL;
+
+ALTER TABLE auth_tokens
+    ADD COLUMN token BINARY(32) NOT NULL AFTER user_id,
+    ADD CONSTRAINT UQ_auth_tokens_id UNIQUE (id);
+
+ALTER TABLE auth_tokens
+    DROP PRIMARY KEY,
+    ADD CONSTRAINT PK_auth_tokens PRIMARY KEY (user_id, token),
+    DROP INDEX UQ_auth_tokens_prefix,
+    DROP COLUMN token_prefix,
+    DROP COLUMN token_hash;
@@ -0,0 +1,19 @@
+-- Existing sessions and confirmations are deliberately invalidated (forced sign out) instead of being rehashed:
+-- their tokens were stored in plaintext and may already have leaked with the database
+DELETE FROM auth_tokens;
+DELETE FROM confirmations;
+
+ALTER TABLE auth_tokens
+    ADD COLUMN token_prefix BINARY(8)  NOT NULL AFTER user_id,
+    ADD COLUMN token_hash   BINARY(32) NOT NULL AFTER token_prefix,
+    ADD CONSTRAINT UQ_auth_tokens_prefix UNIQUE (user_id, token_prefix);
+
+ALTER TABLE auth_tokens
+    DROP PRIMARY KEY,
+    DROP COLUMN token,
+    DROP INDEX UQ_auth_tokens_id,
+    ADD CONSTRAINT PK_auth_tokens PRIMARY KEY (id);
+
+ALTER TABLE confirmations
+    CHANGE COLUMN token token_hash BINARY(32) NOT NULL,
+    CHANGE COLUMN code_token code_token_hash BINARY(32) NOT NULL;
@@ -0,0 +1,3 @@
+DELETE FROM confirmations WHERE action = 'password_reset';
+ALTER TABLE confirmations
+    MODIFY COLUMN action ENUM ('signup', 'signin', 'delete_account') NOT NULL;
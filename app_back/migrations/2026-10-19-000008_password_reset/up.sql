@@ -0,0 +1,2 @@
+ALTER TABLE confirmations
+    MODIFY COLUMN action ENUM ('signup', 'signin', 'delete_account', 'password_reset') NOT NULL;
@@ -0,0 +1,6 @@
+DELETE FROM confirmations WHERE action = 'change_email';
+ALTER TABLE confirmations
+    MODIFY COLUMN action ENUM ('signup', 'signin', 'delete_account', 'password_reset') NOT NULL;
+
+ALTER TABLE users
+    DROP COLUMN pending_email;
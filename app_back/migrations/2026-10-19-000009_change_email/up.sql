@@ -0,0 +1,5 @@
+ALTER TABLE users
+    ADD COLUMN pending_email VARCHAR(320) DEFAULT NULL;
+
+ALTER TABLE confirmations
+    MODIFY COLUMN action ENUM ('signup', 'signin', 'delete_account', 'password_reset', 'change_email') NOT NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE auth_tokens
+    ADD COLUMN revoke_token_hash BINARY(32) DEFAULT NULL;
@@ -0,0 +1,15 @@
+CREATE TABLE webauthn_credentials
+(
+    CONSTRAINT PK_webauthn_credentials PRIMARY KEY (id),
+    CONSTRAINT UQ_webauthn_credentials UNIQUE (credential_id),
+    id            INT UNSIGNED AUTO_INCREMENT,
+    user_id       INT UNSIGNED    NOT NULL,
+    name          VARCHAR(32)     NOT NULL,
+    credential_id VARBINARY(1023) NOT NULL,
+    passkey       TEXT            NOT NULL,
+    creation_date DATETIME        NOT NULL DEFAULT (UTC_TIMESTAMP()),
+    last_use_date DATETIME                 DEFAULT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE INDEX IDX_webauthn_credentials_user_id ON webauthn_credentials (user_id);
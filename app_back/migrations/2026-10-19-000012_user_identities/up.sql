@@ -0,0 +1,12 @@
+CREATE TABLE user_identities
+(
+    CONSTRAINT PK_user_identities PRIMARY KEY (id),
+    CONSTRAINT UQ_user_identities UNIQUE (provider, subject),
+    id            INT UNSIGNED AUTO_INCREMENT,
+    user_id       INT UNSIGNED NOT NULL,
+    provider      VARCHAR(32)  NOT NULL,
+    subject       VARCHAR(255) NOT NULL,
+    email         VARCHAR(320) NOT NULL,
+    creation_date DATETIME     NOT NULL DEFAULT (UTC_TIMESTAMP()),
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
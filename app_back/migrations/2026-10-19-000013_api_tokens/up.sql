@@ -0,0 +1,15 @@
+CREATE TABLE api_tokens
+(
+    CONSTRAINT PK_api_tokens PRIMARY KEY (id),
+    CONSTRAINT UQ_api_tokens_prefix UNIQUE (token_prefix),
+    id              INT UNSIGNED AUTO_INCREMENT,
+    user_id         INT UNSIGNED     NOT NULL,
+    name            VARCHAR(32)      NOT NULL,
+    token_prefix    BINARY(8)        NOT NULL,
+    token_hash      BINARY(32)       NOT NULL,
+    scopes          TINYINT UNSIGNED NOT NULL,
+    creation_date   DATETIME         NOT NULL DEFAULT (UTC_TIMESTAMP()),
+    expiration_date DATETIME                  DEFAULT NULL,
+    last_use_date   DATETIME                  DEFAULT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
@@ -0,0 +1,17 @@
+CREATE TABLE audit_events
+(
+    CONSTRAINT PK_audit_events PRIMARY KEY (id),
+    INDEX IX_audit_events_target (target_user_id, creation_date),
+    INDEX IX_audit_events_actor (actor_user_id, creation_date),
+    id             BIGINT UNSIGNED AUTO_INCREMENT,
+    event          ENUM ('signin', 'signin_failed', 'totp_added', 'totp_removed', 'passkey_added', 'passkey_removed',
+                         'password_changed', 'password_reset', 'email_changed', 'api_token_created', 'api_token_revoked',
+                         'share_accepted', 'account_deletion_scheduled', 'account_deletion_cancelled',
+                         'user_banned', 'user_unbanned', 'user_signed_out', 'storage_limit_changed') NOT NULL,
+    actor_user_id  INT UNSIGNED              DEFAULT NULL,
+    target_user_id INT UNSIGNED              DEFAULT NULL,
+    details        VARCHAR(255)              DEFAULT NULL,
+    device_string  VARCHAR(128)              DEFAULT NULL,
+    ip_address     VARBINARY(16)             DEFAULT NULL,
+    creation_date  DATETIME         NOT NULL DEFAULT (UTC_TIMESTAMP())
+);
@@ -0,0 +1,19 @@
+CREATE TABLE email_outbox
+(
+    CONSTRAINT PK_email_outbox PRIMARY KEY (id),
+    INDEX IX_email_outbox_status (status, next_attempt_date),
+    id                BIGINT UNSIGNED AUTO_INCREMENT,
+    user_id           INT UNSIGNED                      NOT NULL,
+    to_name           VARCHAR(32)                       NOT NULL,
+    to_email          VARCHAR(320)                      NOT NULL,
+    subject           VARCHAR(255)                      NOT NULL,
+    body_text         MEDIUMTEXT                        NOT NULL,
+    body_html         MEDIUMTEXT                        NOT NULL,
+    status            ENUM ('pending', 'sent', 'failed') NOT NULL DEFAULT 'pending',
+    attempts          INT UNSIGNED                      NOT NULL DEFAULT 0,
+    last_error        VARCHAR(1024)                              DEFAULT NULL,
+    creation_date     DATETIME                          NOT NULL DEFAULT (UTC_TIMESTAMP()),
+    next_attempt_date DATETIME                          NOT NULL DEFAULT (UTC_TIMESTAMP()),
+    sent_date         DATETIME                                   DEFAULT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
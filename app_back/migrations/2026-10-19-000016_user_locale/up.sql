@@ -0,0 +1,2 @@
+ALTER TABLE users
+    ADD COLUMN locale ENUM ('en', 'fr') NOT NULL DEFAULT 'en';
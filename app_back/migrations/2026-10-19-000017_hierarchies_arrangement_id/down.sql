@@ -0,0 +1,2 @@
+ALTER TABLE hierarchies_arrangements
+    RENAME COLUMN arrangement_id TO arrangements_id;
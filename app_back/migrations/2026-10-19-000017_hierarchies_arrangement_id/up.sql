@@ -0,0 +1,2 @@
+ALTER TABLE hierarchies_arrangements
+    RENAME COLUMN arrangements_id TO arrangement_id;
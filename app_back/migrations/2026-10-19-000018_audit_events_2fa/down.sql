@@ -0,0 +1,8 @@
+DELETE FROM audit_events
+WHERE event IN ('tfa_login_enabled', 'tfa_login_disabled', 'recovery_codes_regenerated');
+
+ALTER TABLE audit_events
+    MODIFY COLUMN event ENUM ('signin', 'signin_failed', 'totp_added', 'totp_removed', 'passkey_added', 'passkey_removed',
+                              'password_changed', 'password_reset', 'email_changed', 'api_token_created', 'api_token_revoked',
+                              'share_accepted', 'account_deletion_scheduled', 'account_deletion_cancelled',
+                              'user_banned', 'user_unbanned', 'user_signed_out', 'storage_limit_changed') NOT NULL;
@@ -0,0 +1,10 @@
+UPDATE email_outbox
+SET status = 'pending'
+WHERE status = 'sending';
+UPDATE email_outbox
+SET status = 'failed'
+WHERE status = 'expired';
+
+ALTER TABLE email_outbox
+    DROP COLUMN expiration_date,
+    MODIFY COLUMN status ENUM ('pending', 'sent', 'failed') NOT NULL DEFAULT 'pending';
@@ -0,0 +1,11 @@
+-- Emails are claimed ('sending') before being sent, so that several instances do not send the same email,
+-- and expired ('expired', bodies blanked) once the links and codes they contain are not valid anymore
+ALTER TABLE email_outbox
+    MODIFY COLUMN status ENUM ('pending', 'sending', 'sent', 'failed', 'expired') NOT NULL DEFAULT 'pending',
+    ADD COLUMN expiration_date DATETIME DEFAULT NULL AFTER next_attempt_date;
+
+-- Sent emails do not keep their content
+UPDATE email_outbox
+SET body_text = '',
+    body_html = ''
+WHERE status = 'sent';
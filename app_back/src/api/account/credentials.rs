@@ -0,0 +1,122 @@
+use crate::database::audit::AuditEvent;
+use crate::database::auth_token::{AuthToken, Confirmation, TOTPSecret};
+use crate::database::database::{DBConn, DBPool};
+use crate::database::schema::{AuditEventType, ConfirmationAction};
+use crate::database::user::User;
+use crate::mailing::mailer::send_rendered_email;
+use crate::utils::auth::DeviceInfo;
+use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use crate::utils::rate_limit::{RateLimitKey, RateLimiter};
+use crate::utils::utils::{get_frontend_host, left_pad};
+use crate::utils::validation::{validate_input, validate_password};
+use pwhash::bcrypt;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::{openapi, JsonSchema};
+use validator::Validate;
+
+#[derive(JsonSchema, Deserialize, Debug, Validate)]
+pub struct PasswordChangeData {
+    current_password: String,
+    #[validate(custom(function = validate_password))]
+    new_password: String,
+    /// Required if the user has a TOTP authenticator
+    totp_code: Option<String>,
+}
+
+#[derive(JsonSchema, Deserialize, Debug, Validate)]
+pub struct EmailChangeData {
+    #[validate(email(code = "email_invalid", message = "Invalid email"))]
+    email: String,
+    password: String,
+    /// Optional redirect URL for the email confirmation
+    redirect_url: Option<String>,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct EmailChangeResponse {
+    pub user_id: u32,
+    pub code_token: String,
+}
+
+/// Change the password of the authenticated user. All the other sessions of the user are revoked.
+/// - Throw `InvalidPassword` if the current password is incorrect.
+/// - Throw `TFARequired` if the user has a TOTP authenticator and no code is provided.
+/// - Throw `InvalidTOTPCode` if the TOTP code is invalid.
+/// - Throw `TooManyRequests` if too many attempts failed for this account.
+#[openapi(tag = "Account")]
+#[post("/account/password", data = "<data>")]
+pub fn account_password_change(data: Json<PasswordChangeData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, user: User, auth_token: AuthToken, device_info: DeviceInfo) -> Result<(), ErrorResponder> {
+    validate_input(&data)?;
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    let keys = [Some(RateLimitKey::account("password", user.id))];
+
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
+        if !bcrypt::verify(&data.current_password, &user.password_hash) {
+            return ErrorType::InvalidPassword.res_err();
+        }
+        if TOTPSecret::has_user_totp(conn, &user.id)? {
+            let totp_code = data.totp_code.as_ref().ok_or(ErrorType::TFARequired.res())?;
+            if !TOTPSecret::check_user_totp(conn, &user.id, &user.email, totp_code)? {
+                return ErrorType::InvalidTOTPCode.res_err();
+            }
+        }
+
+        User::update_password(conn, &user.id, &data.new_password)?;
+        AuthToken::clear_auth_tokens_except(conn, &user.id, &auth_token.id)?;
+        AuditEvent::insert_for_user(conn, AuditEventType::PasswordChanged, &user.id, &device_info)
+    }))
+}
+
+/// Request the change of the email of the authenticated user.
+/// A confirmation email is sent to the new address, and the current address is notified.
+/// The email is changed once confirmed through `/auth/confirm/code` or `/auth/confirm/token` with the `ChangeEmail` action.
+/// - Throw `InvalidPassword` if the password is incorrect.
+/// - Throw `EmailAlreadyExists` if another account uses the new email.
+/// - Throw `TooManyRequests` if too many attempts failed for this account.
+#[openapi(tag = "Account")]
+#[post("/account/email", data = "<data>")]
+pub fn account_email_change(data: Json<EmailChangeData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, user: User, device_info: DeviceInfo) -> Result<Json<EmailChangeResponse>, ErrorResponder> {
+    validate_input(&data)?;
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    let keys = [Some(RateLimitKey::account("password", user.id))];
+
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
+        if !bcrypt::verify(&data.password, &user.password_hash) {
+            return ErrorType::InvalidPassword.res_err();
+        }
+        if User::find_by_email_opt(conn, &data.email)?.is_some() {
+            return ErrorType::EmailAlreadyExists.res_err();
+        }
+
+        user.set_pending_email(conn, &data.email)?;
+        // Only the latest email change confirmation is valid
+        Confirmation::mark_all_as_used(conn, &user.id, ConfirmationAction::ChangeEmail)?;
+        let (token, code_token, code) = Confirmation::insert_confirmation(conn, user.id, ConfirmationAction::ChangeEmail, &device_info, &data.redirect_url, 0)?;
+        let code_str = left_pad(&code.to_string(), '0', 4);
+        let ip = device_info.ip_address.clone().unwrap_or("Unknown".to_string());
+
+        // Sending confirmation email to the new address
+        let confirm_url = format!("{}/account/email?id={}&token={}", get_frontend_host(), user.id, hex::encode(&token));
+        let mut context = tera::Context::new();
+        context.insert("name", &user.name);
+        context.insert("url", &confirm_url);
+        context.insert("code", &code_str);
+        context.insert("ip", &ip);
+        context.insert("agent", &device_info.device_string);
+        send_rendered_email(conn, &user.id, (user.name.clone(), data.email.clone()), "confirm_change_email".to_string(), context)?;
+
+        // Notifying the current address
+        let mut context = tera::Context::new();
+        context.insert("name", &user.name);
+        context.insert("new_email", &data.email);
+        context.insert("ip", &ip);
+        context.insert("agent", &device_info.device_string);
+        send_rendered_email(conn, &user.id, (user.name.clone(), user.email.clone()), "email_change_requested".to_string(), context)?;
+
+        Ok(Json(EmailChangeResponse {
+            user_id: user.id,
+            code_token: hex::encode(code_token),
+        }))
+    }))
+}
@@ -0,0 +1,74 @@
+use crate::database::audit::AuditEvent;
+use crate::database::auth_token::Confirmation;
+use crate::database::database::{DBConn, DBPool};
+use crate::database::schema::{AuditEventType, ConfirmationAction};
+use crate::database::user::User;
+use crate::mailing::mailer::send_rendered_email;
+use crate::utils::auth::DeviceInfo;
+use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use crate::utils::utils::{get_frontend_host, left_pad};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::{openapi, JsonSchema};
+
+/// Number of days between the confirmation of an account deletion and the effective deletion.
+/// During this period, the deletion can be cancelled.
+pub const ACCOUNT_DELETION_GRACE_DAYS: i64 = 7;
+
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct AccountDeleteData {
+    /// Optional redirect URL for the email confirmation
+    redirect_url: Option<String>,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct AccountDeleteResponse {
+    pub user_id: u32,
+    pub code_token: String,
+}
+
+/// Request the deletion of the authenticated user's account; sends a confirmation email.
+/// Once confirmed through `/auth/confirm/code` or `/auth/confirm/token` with the `DeleteAccount` action,
+/// the account is scheduled for deletion after a grace period.
+#[openapi(tag = "Account")]
+#[post("/account/delete", data = "<data>")]
+pub fn account_delete(data: Json<AccountDeleteData>, db: &rocket::State<DBPool>, user: User, device_info: DeviceInfo) -> Result<Json<AccountDeleteResponse>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    err_transaction(conn, |conn| {
+        let (token, code_token, code) = Confirmation::insert_confirmation(conn, user.id, ConfirmationAction::DeleteAccount, &device_info, &data.redirect_url, 0)?;
+        let code_str = left_pad(&code.to_string(), '0', 4);
+
+        // Sending email
+        let delete_url = format!("{}/account/delete?id={}&token={}", get_frontend_host(), user.id, hex::encode(&token));
+        let mut context = tera::Context::new();
+        context.insert("name", &user.name);
+        context.insert("url", &delete_url);
+        context.insert("code", &code_str);
+        context.insert("grace_days", &ACCOUNT_DELETION_GRACE_DAYS);
+        context.insert("ip", &device_info.ip_address.unwrap_or("Unknown".to_string()));
+        context.insert("agent", &device_info.device_string);
+        send_rendered_email(conn, &user.id, (user.name.clone(), user.email.clone()), "confirm_delete_account".to_string(), context)?;
+
+        Ok(Json(AccountDeleteResponse {
+            user_id: user.id,
+            code_token: hex::encode(code_token),
+        }))
+    })
+}
+
+/// Cancel the scheduled deletion of the authenticated user's account.
+/// - Throw `AccountDeletionNotScheduled` if no deletion is scheduled.
+#[openapi(tag = "Account")]
+#[post("/account/delete/cancel")]
+pub fn account_delete_cancel(db: &rocket::State<DBPool>, user: User, device_info: DeviceInfo) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    if user.deletion_date.is_none() {
+        return ErrorType::AccountDeletionNotScheduled.res_err();
+    }
+    err_transaction(conn, |conn| {
+        user.cancel_deletion(conn)?;
+        AuditEvent::insert_for_user(conn, AuditEventType::AccountDeletionCancelled, &user.id, &device_info)
+    })
+}
@@ -0,0 +1,21 @@
+use crate::database::database::{DBConn, DBPool};
+use crate::database::schema::Locale;
+use crate::database::user::User;
+use crate::utils::errors_catcher::ErrorResponder;
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rocket_okapi::{openapi, JsonSchema};
+
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct LocaleData {
+    locale: Locale,
+}
+
+/// Change the language of the emails sent to the authenticated user.
+#[openapi(tag = "Account")]
+#[post("/account/locale", data = "<data>")]
+pub fn account_locale(data: Json<LocaleData>, db: &rocket::State<DBPool>, user: User) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    User::set_locale_from_id(conn, &user.id, data.locale)
+}
@@ -0,0 +1,44 @@
+use crate::database::audit::AuditEvent;
+use crate::database::database::{DBConn, DBPool};
+use crate::database::schema::AuditEventType;
+use crate::database::user::User;
+use crate::utils::errors_catcher::ErrorResponder;
+use chrono::NaiveDateTime;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket_okapi::{openapi, JsonSchema};
+
+/// Number of events returned per page by `/account/security`.
+const EVENTS_PAGE_SIZE: i64 = 50;
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct SecurityEventItem {
+    pub event: AuditEventType,
+    /// Additional information, e.g. the sign in method or the name of the added authenticator
+    pub details: Option<String>,
+    /// True if the action was performed by an admin or from another account
+    pub by_other_user: bool,
+    pub device_string: Option<String>,
+    pub ip_address: Option<String>,
+    pub creation_date: NaiveDateTime,
+}
+
+/// List the security activity of the authenticated user (sign ins, failed sign ins, credentials changes...),
+/// the most recent first.
+/// Pages contain 50 events, starting at page 0.
+#[openapi(tag = "Account")]
+#[get("/account/security?<page>")]
+pub fn account_security(page: Option<u32>, db: &rocket::State<DBPool>, user: User) -> Result<Json<Vec<SecurityEventItem>>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    let offset = page.unwrap_or(0) as i64 * EVENTS_PAGE_SIZE;
+    let events = AuditEvent::get_user_events(conn, &user.id, offset, EVENTS_PAGE_SIZE)?;
+    Ok(Json(events.into_iter().map(|event| SecurityEventItem {
+        ip_address: event.get_ip_address().map(|ip| ip.to_string()),
+        event: event.event,
+        details: event.details,
+        by_other_user: event.actor_user_id != Some(user.id),
+        device_string: event.device_string,
+        creation_date: event.creation_date,
+    }).collect()))
+}
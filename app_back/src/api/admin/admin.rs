@@ -0,0 +1,241 @@
+use crate::api::auth::signup::send_signup_confirmation;
+use crate::database::audit::AuditEvent;
+use crate::database::auth_token::{AuthToken, Confirmation};
+use crate::database::database::{DBConn, DBPool};
+use crate::database::email_outbox::OutboxEmail;
+use crate::database::schema::{AuditEventType, ConfirmationAction, UserStatus};
+use crate::database::user::User;
+use crate::utils::auth::{AdminUser, DeviceInfo};
+use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use chrono::NaiveDateTime;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::{openapi, JsonSchema};
+
+/// Number of users returned per page by `/admin/users`.
+const USERS_PAGE_SIZE: i64 = 50;
+/// Number of events returned per page by `/admin/audit_events`.
+const EVENTS_PAGE_SIZE: i64 = 100;
+/// Number of emails returned per page by `/admin/emails/failed`.
+const EMAILS_PAGE_SIZE: i64 = 100;
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct AdminUserItem {
+    pub user_id: u32,
+    pub name: String,
+    pub email: String,
+    pub status: UserStatus,
+    pub creation_date: NaiveDateTime,
+    pub tfa_login: bool,
+    pub storage_count_ko: u64,
+    pub storage_limit_mo: u32,
+    pub deletion_date: Option<NaiveDateTime>,
+}
+
+impl From<User> for AdminUserItem {
+    fn from(user: User) -> Self {
+        AdminUserItem {
+            user_id: user.id,
+            name: user.name,
+            email: user.email,
+            status: user.status,
+            creation_date: user.creation_date,
+            tfa_login: user.tfa_login,
+            storage_count_ko: user.storage_count_ko,
+            storage_limit_mo: user.storage_limit_mo,
+            deletion_date: user.deletion_date,
+        }
+    }
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct AdminAuditEventItem {
+    pub event_id: u64,
+    pub event: AuditEventType,
+    pub actor_user_id: Option<u32>,
+    pub target_user_id: Option<u32>,
+    pub details: Option<String>,
+    pub device_string: Option<String>,
+    pub ip_address: Option<String>,
+    pub creation_date: NaiveDateTime,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct AdminFailedEmailItem {
+    pub email_id: u64,
+    pub user_id: u32,
+    pub to_email: String,
+    pub subject: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub creation_date: NaiveDateTime,
+}
+
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct AdminStorageLimitData {
+    storage_limit_mo: u32,
+}
+
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct AdminResendConfirmationData {
+    /// Optional redirect URL for the email confirmation
+    redirect_url: Option<String>,
+}
+
+/// List the users, ordered by id, optionally filtered by a search on their name or email.
+/// Pages contain 50 users, starting at page 0.
+#[openapi(tag = "Admin")]
+#[get("/admin/users?<search>&<page>")]
+pub fn admin_users(search: Option<String>, page: Option<u32>, db: &rocket::State<DBPool>, _admin: AdminUser) -> Result<Json<Vec<AdminUserItem>>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    let search = search.filter(|search| !search.is_empty());
+    let offset = page.unwrap_or(0) as i64 * USERS_PAGE_SIZE;
+    let users = User::search_users(conn, &search, offset, USERS_PAGE_SIZE)?;
+    Ok(Json(users.into_iter().map(AdminUserItem::from).collect()))
+}
+
+/// Get a user with its storage usage.
+/// - Throw `UserNotFound` if the user does not exist.
+#[openapi(tag = "Admin")]
+#[get("/admin/users/<user_id>")]
+pub fn admin_user(user_id: u32, db: &rocket::State<DBPool>, _admin: AdminUser) -> Result<Json<AdminUserItem>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    Ok(Json(User::from_id(conn, &user_id)?.into()))
+}
+
+/// Ban a user and sign out all its sessions.
+/// - Throw `UserNotFound` if the user does not exist.
+/// - Throw `CannotBanSelf` if the admin tries to ban themselves.
+#[openapi(tag = "Admin")]
+#[post("/admin/users/<user_id>/ban")]
+pub fn admin_user_ban(user_id: u32, db: &rocket::State<DBPool>, admin: AdminUser, device_info: DeviceInfo) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    if user_id == admin.user.id {
+        return ErrorType::CannotBanSelf.res_err();
+    }
+    err_transaction(conn, |conn| {
+        let user = User::from_id(conn, &user_id)?;
+        user.switch_status(conn, &UserStatus::Banned)?;
+        AuthToken::clear_auth_tokens(conn, &user.id)?;
+        AuditEvent::insert(conn, AuditEventType::UserBanned, Some(admin.user.id), Some(user.id), None, &device_info)
+    })
+}
+
+/// Unban a user, who gets back the normal status.
+/// - Throw `UserNotFound` if the user does not exist.
+#[openapi(tag = "Admin")]
+#[post("/admin/users/<user_id>/unban")]
+pub fn admin_user_unban(user_id: u32, db: &rocket::State<DBPool>, admin: AdminUser, device_info: DeviceInfo) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    err_transaction(conn, |conn| {
+        let user = User::from_id(conn, &user_id)?;
+        if user.status != UserStatus::Banned {
+            return Ok(());
+        }
+        User::switch_status_from_id(conn, &user.id, &UserStatus::Normal)?;
+        AuditEvent::insert(conn, AuditEventType::UserUnbanned, Some(admin.user.id), Some(user.id), None, &device_info)
+    })
+}
+
+/// Change the storage limit of a user, in Mo.
+/// - Throw `UserNotFound` if the user does not exist.
+#[openapi(tag = "Admin")]
+#[post("/admin/users/<user_id>/storage_limit", data = "<data>")]
+pub fn admin_user_storage_limit(user_id: u32, data: Json<AdminStorageLimitData>, db: &rocket::State<DBPool>, admin: AdminUser, device_info: DeviceInfo) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    err_transaction(conn, |conn| {
+        let user = User::from_id(conn, &user_id)?;
+        User::set_storage_limit_from_id(conn, &user.id, data.storage_limit_mo)?;
+        let details = format!("{} Mo -> {} Mo", user.storage_limit_mo, data.storage_limit_mo);
+        AuditEvent::insert(conn, AuditEventType::StorageLimitChanged, Some(admin.user.id), Some(user.id), Some(details), &device_info)
+    })
+}
+
+/// Sign out all the sessions of a user.
+/// - Throw `UserNotFound` if the user does not exist.
+#[openapi(tag = "Admin")]
+#[post("/admin/users/<user_id>/signout")]
+pub fn admin_user_signout(user_id: u32, db: &rocket::State<DBPool>, admin: AdminUser, device_info: DeviceInfo) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    err_transaction(conn, |conn| {
+        let user = User::from_id(conn, &user_id)?;
+        AuthToken::clear_auth_tokens(conn, &user.id)?;
+        AuditEvent::insert(conn, AuditEventType::UserSignedOut, Some(admin.user.id), Some(user.id), None, &device_info)
+    })
+}
+
+/// Resend the signup confirmation email of an unconfirmed user, invalidating the previous confirmations.
+/// - Throw `UserNotFound` if the user does not exist.
+/// - Throw `UserAlreadyConfirmed` if the user is not unconfirmed.
+#[openapi(tag = "Admin")]
+#[post("/admin/users/<user_id>/resend_confirmation", data = "<data>")]
+pub fn admin_user_resend_confirmation(user_id: u32, data: Json<AdminResendConfirmationData>, db: &rocket::State<DBPool>, _admin: AdminUser, device_info: DeviceInfo) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    err_transaction(conn, |conn| {
+        let user = User::from_id(conn, &user_id)?;
+        if user.status != UserStatus::Unconfirmed {
+            return ErrorType::UserAlreadyConfirmed.res_err();
+        }
+        Confirmation::mark_all_as_used(conn, &user.id, ConfirmationAction::Signup)?;
+        send_signup_confirmation(conn, user.id, &user.name, &user.email, &device_info, &data.redirect_url)?;
+        Ok(())
+    })
+}
+
+/// List the audit events, the most recent first, optionally filtered on a user (as actor or target) and an event type.
+/// Pages contain 100 events, starting at page 0.
+#[openapi(tag = "Admin")]
+#[get("/admin/audit_events?<user_id>&<event>&<page>")]
+pub fn admin_audit_events(user_id: Option<u32>, event: Option<AuditEventType>, page: Option<u32>, db: &rocket::State<DBPool>, _admin: AdminUser) -> Result<Json<Vec<AdminAuditEventItem>>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    let offset = page.unwrap_or(0) as i64 * EVENTS_PAGE_SIZE;
+    let events = AuditEvent::search_events(conn, &user_id, &event, offset, EVENTS_PAGE_SIZE)?;
+    Ok(Json(events.into_iter().map(|event| AdminAuditEventItem {
+        ip_address: event.get_ip_address().map(|ip| ip.to_string()),
+        event_id: event.id,
+        event: event.event,
+        actor_user_id: event.actor_user_id,
+        target_user_id: event.target_user_id,
+        details: event.details,
+        device_string: event.device_string,
+        creation_date: event.creation_date,
+    }).collect()))
+}
+
+/// List the emails whose sending failed after all the attempts, the most recent first.
+/// Pages contain 100 emails, starting at page 0.
+#[openapi(tag = "Admin")]
+#[get("/admin/emails/failed?<page>")]
+pub fn admin_emails_failed(page: Option<u32>, db: &rocket::State<DBPool>, _admin: AdminUser) -> Result<Json<Vec<AdminFailedEmailItem>>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    let offset = page.unwrap_or(0) as i64 * EMAILS_PAGE_SIZE;
+    let emails = OutboxEmail::get_failed(conn, offset, EMAILS_PAGE_SIZE)?;
+    Ok(Json(emails.into_iter().map(|email| AdminFailedEmailItem {
+        email_id: email.id,
+        user_id: email.user_id,
+        to_email: email.to_email,
+        subject: email.subject,
+        attempts: email.attempts,
+        last_error: email.last_error,
+        creation_date: email.creation_date,
+    }).collect()))
+}
+
+/// Put back a failed email in the outbox, to be sent again with a new set of attempts.
+/// - Throw `EmailNotFound` if there is no failed email with this id.
+#[openapi(tag = "Admin")]
+#[post("/admin/emails/<email_id>/retry")]
+pub fn admin_email_retry(email_id: u64, db: &rocket::State<DBPool>, _admin: AdminUser) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    OutboxEmail::retry_failed(conn, &email_id)
+}
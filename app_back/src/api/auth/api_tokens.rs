@@ -0,0 +1,95 @@
+use crate::database::audit::AuditEvent;
+use crate::database::auth_token::{ApiScope, ApiToken};
+use crate::database::database::{DBConn, DBPool};
+use crate::database::schema::{AuditEventType, UserStatus};
+use crate::database::user::User;
+use crate::utils::auth::DeviceInfo;
+use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use crate::utils::validation::validate_input;
+use chrono::NaiveDateTime;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::{openapi, JsonSchema};
+use validator::Validate;
+
+#[derive(JsonSchema, Deserialize, Debug, Validate)]
+pub struct ApiTokenCreateData {
+    /// Name of the token, displayed in the tokens list
+    #[validate(length(min = 1, max = 32, message = "Name must be between 1 and 32 characters"))]
+    name: String,
+    #[validate(length(min = 1, message = "At least one scope is required"))]
+    scopes: Vec<ApiScope>,
+    /// Optional expiration date (UTC), the token never expires if not set
+    expiration_date: Option<NaiveDateTime>,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct ApiTokenCreateResponse {
+    pub api_token_id: u32,
+    /// Token to send in the X-API-Token header, to be shown only once
+    pub api_token: String,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct ApiTokenListItem {
+    pub api_token_id: u32,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub creation_date: NaiveDateTime,
+    pub expiration_date: Option<NaiveDateTime>,
+    pub last_use_date: Option<NaiveDateTime>,
+}
+
+/// Create a personal API token for the authenticated user.
+/// API tokens cannot be managed with an API token, a session is required.
+/// - Throw `UserNotAdmin` if the `Admin` scope is requested by a non admin user.
+#[openapi(tag = "API tokens")]
+#[post("/auth/api_tokens", data = "<data>")]
+pub fn auth_api_tokens_create(data: Json<ApiTokenCreateData>, db: &rocket::State<DBPool>, user: User, device_info: DeviceInfo) -> Result<Json<ApiTokenCreateResponse>, ErrorResponder> {
+    validate_input(&data)?;
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    if data.scopes.contains(&ApiScope::Admin) && user.status != UserStatus::Admin {
+        return ErrorType::UserNotAdmin.res_err();
+    }
+
+    err_transaction(conn, |conn| {
+        let (api_token_id, api_token) = ApiToken::insert_for_user(conn, &user.id, &data.name, ApiScope::to_bits(&data.scopes), &data.expiration_date, 0)?;
+        AuditEvent::insert(conn, AuditEventType::ApiTokenCreated, Some(user.id), Some(user.id), Some(data.name.clone()), &device_info)?;
+        Ok(Json(ApiTokenCreateResponse {
+            api_token_id,
+            api_token: hex::encode(api_token),
+        }))
+    })
+}
+
+/// List the personal API tokens of the authenticated user, the most recent first.
+#[openapi(tag = "API tokens")]
+#[get("/auth/api_tokens")]
+pub fn auth_api_tokens_list(db: &rocket::State<DBPool>, user: User) -> Result<Json<Vec<ApiTokenListItem>>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    let api_tokens = ApiToken::get_user_api_tokens(conn, &user.id)?;
+    Ok(Json(api_tokens.into_iter().map(|token| ApiTokenListItem {
+        api_token_id: token.id,
+        name: token.name,
+        scopes: ApiScope::from_bits(token.scopes),
+        creation_date: token.creation_date,
+        expiration_date: token.expiration_date,
+        last_use_date: token.last_use_date,
+    }).collect()))
+}
+
+/// Revoke a personal API token.
+/// - Throw `ApiTokenNotFound` if the token does not exist.
+#[openapi(tag = "API tokens")]
+#[delete("/auth/api_tokens/<api_token_id>")]
+pub fn auth_api_tokens_delete(api_token_id: u32, db: &rocket::State<DBPool>, user: User, device_info: DeviceInfo) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    err_transaction(conn, |conn| {
+        let api_token = ApiToken::from_id(conn, &user.id, &api_token_id)?;
+        api_token.delete(conn)?;
+        AuditEvent::insert(conn, AuditEventType::ApiTokenRevoked, Some(user.id), Some(user.id), Some(api_token.name), &device_info)
+    })
+}
@@ -1,13 +1,18 @@
+use crate::api::account::delete::ACCOUNT_DELETION_GRACE_DAYS;
 use crate::api::auth::signin::SigninResponse;
-use crate::database::auth_token::{AuthToken, Confirmation};
+use crate::database::audit::AuditEvent;
+use crate::database::auth_token::{AuthToken, Confirmation, CONFIRMATION_LIFETIME_MINUTES};
 use crate::database::database::{DBConn, DBPool};
+use crate::database::schema::AuditEventType;
 use crate::database::schema::ConfirmationAction;
 use crate::database::schema::UserStatus;
 use crate::database::user::User;
 use crate::utils::auth::{DeviceInfo, UserAuthInfo};
 use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use crate::utils::rate_limit::{RateLimitKey, RateLimiter};
 use crate::utils::utils::get_frontend_host;
 use crate::utils::validation::validate_input;
+use chrono::NaiveDateTime;
 use diesel::Connection;
 use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
@@ -42,46 +47,80 @@ pub struct ConfirmSignInUpResponse {
     pub redirect_url: String,
 }
 
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct ConfirmDeleteAccountResponse {
+    /// Date (UTC) at which the account will be deleted, unless the deletion is cancelled before.
+    pub deletion_date: NaiveDateTime,
+    pub redirect_url: String,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct ConfirmChangeEmailResponse {
+    /// New email address of the user
+    pub email: String,
+    pub redirect_url: String,
+}
+
 #[derive(JsonSchema, Serialize, Debug)]
 #[serde(untagged)]
 pub enum ConfirmResponse {
     SignInUp(ConfirmSignInUpResponse),
+    DeleteAccount(ConfirmDeleteAccountResponse),
+    ChangeEmail(ConfirmChangeEmailResponse),
 }
 
 /// Confirm any 2FA request with a code_token and a code (from email code).
+/// - Throw `BadRequest` for password reset confirmations, which are confirmed by `/auth/password/reset`.
+/// - Throw `TooManyRequests` if too many attempts failed for this IP address or account.
 #[openapi(tag = "Authentication")]
 #[post("/auth/confirm/code", data = "<data>")]
-pub fn auth_confirm_code(data: Json<ConfirmCodeData>, db: &rocket::State<DBPool>, user_auth_info: UserAuthInfo, device_info: DeviceInfo) -> Result<Json<ConfirmResponse>, ErrorResponder> {
+pub fn auth_confirm_code(data: Json<ConfirmCodeData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, user_auth_info: UserAuthInfo, device_info: DeviceInfo) -> Result<Json<ConfirmResponse>, ErrorResponder> {
     validate_input(&data)?;
     let conn: &mut DBConn = &mut db.get().unwrap();
     let user_id = user_auth_info.user_id.ok_or(ErrorType::UserNotFound.res())?;
     let user = User::from_id(conn, &user_id)?;
+    reject_password_reset(&data.action)?;
 
     let code_token = hex::decode(&data.code_token).map_err(|_| ErrorType::UnprocessableEntity.res())?;
+    let keys = [RateLimitKey::ip("confirm", &device_info), Some(RateLimitKey::account("confirm", user_id))];
 
-    err_transaction(conn, |conn| {
-        let redirect_url = Confirmation::check_code_and_mark_as_used(conn, &user_id, &data.action, &code_token, &data.code, 15)?
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
+        let redirect_url = Confirmation::check_code_and_mark_as_used(conn, &user_id, &data.action, &code_token, &data.code, CONFIRMATION_LIFETIME_MINUTES)?
             .unwrap_or(get_frontend_host());
         confirm_execute(conn, &data.action, user, redirect_url, &device_info)
-    })
+    }))
 }
 
 /// Confirm any 2FA request with a token (from email link).
+/// - Throw `BadRequest` for password reset confirmations, which are confirmed by `/auth/password/reset`.
+/// - Throw `TooManyRequests` if too many attempts failed for this IP address or account.
 #[openapi(tag = "Authentication")]
 #[post("/auth/confirm/token", data = "<data>")]
-pub fn auth_confirm_token(data: Json<ConfirmTokenData>, db: &rocket::State<DBPool>, user_auth_info: UserAuthInfo, device_info: DeviceInfo) -> Result<Json<ConfirmResponse>, ErrorResponder> {
+pub fn auth_confirm_token(data: Json<ConfirmTokenData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, user_auth_info: UserAuthInfo, device_info: DeviceInfo) -> Result<Json<ConfirmResponse>, ErrorResponder> {
     validate_input(&data)?;
     let conn: &mut DBConn = &mut db.get().unwrap();
     let user_id = user_auth_info.user_id.ok_or(ErrorType::UserNotFound.res())?;
     let user = User::from_id(conn, &user_id)?;
+    reject_password_reset(&data.action)?;
 
     let token = hex::decode(&data.token).map_err(|_| ErrorType::UnprocessableEntity.res())?;
+    let keys = [RateLimitKey::ip("confirm", &device_info), Some(RateLimitKey::account("confirm", user_id))];
 
-    err_transaction(conn, |conn| {
-        let redirect_url = Confirmation::check_token_and_mark_as_used(conn, &user_id, &data.action, &token, 15)?
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
+        let redirect_url = Confirmation::check_token_and_mark_as_used(conn, &user_id, &data.action, &token, CONFIRMATION_LIFETIME_MINUTES)?
             .unwrap_or(get_frontend_host());
         confirm_execute(conn, &data.action, user, redirect_url, &device_info)
-    })
+    }))
+}
+
+/// Password reset confirmations are checked by `/auth/password/reset`, along with the new password.
+/// They are rejected before being checked, so that they cannot be used up here.
+/// - Throw `BadRequest` if the action is a password reset.
+fn reject_password_reset(action: &ConfirmationAction) -> Result<(), ErrorResponder> {
+    if *action == ConfirmationAction::PasswordReset {
+        return ErrorType::BadRequest.res_err();
+    }
+    Ok(())
 }
 
 /// Execute the confirmation action and return the response.
@@ -91,6 +130,7 @@ fn confirm_execute(conn: &mut DBConn, action: &ConfirmationAction, user: User, r
         ConfirmationAction::Signup => {
             user.switch_status(conn, &UserStatus::Normal)?;
             let auth_token = AuthToken::insert_token_for_user(conn, &user.id, device_info, 0)?;
+            AuditEvent::insert(conn, AuditEventType::Signin, Some(user.id), Some(user.id), Some("signup".to_string()), device_info)?;
             Ok(Json(ConfirmResponse::SignInUp(ConfirmSignInUpResponse {
                 status: user.status,
                 name: user.name,
@@ -102,6 +142,7 @@ fn confirm_execute(conn: &mut DBConn, action: &ConfirmationAction, user: User, r
         }
         ConfirmationAction::Signin => {
             let auth_token = AuthToken::insert_token_for_user(conn, &user.id, &device_info, 0)?;
+            AuditEvent::insert(conn, AuditEventType::Signin, Some(user.id), Some(user.id), Some("email".to_string()), device_info)?;
 
             Ok(Json(ConfirmResponse::SignInUp(ConfirmSignInUpResponse {
                 status: user.status,
@@ -112,8 +153,27 @@ fn confirm_execute(conn: &mut DBConn, action: &ConfirmationAction, user: User, r
                 redirect_url,
             })))
         }
-        _ => {
-            ErrorType::BadRequest.res_err()
+        // Rejected beforehand (see `reject_password_reset`), rolled back so that the confirmation is not used up
+        ConfirmationAction::PasswordReset => {
+            ErrorType::BadRequest.res_err_rollback()
+        }
+        ConfirmationAction::ChangeEmail => {
+            let email = user.apply_pending_email(conn)?;
+            AuditEvent::insert(conn, AuditEventType::EmailChanged, Some(user.id), Some(user.id), Some(format!("{} -> {}", user.email, email)), device_info)?;
+
+            Ok(Json(ConfirmResponse::ChangeEmail(ConfirmChangeEmailResponse {
+                email,
+                redirect_url,
+            })))
+        }
+        ConfirmationAction::DeleteAccount => {
+            let deletion_date = user.schedule_deletion(conn, ACCOUNT_DELETION_GRACE_DAYS)?;
+            AuditEvent::insert_for_user(conn, AuditEventType::AccountDeletionScheduled, &user.id, device_info)?;
+
+            Ok(Json(ConfirmResponse::DeleteAccount(ConfirmDeleteAccountResponse {
+                deletion_date,
+                redirect_url,
+            })))
         }
     }
 }
@@ -0,0 +1,184 @@
+use crate::database::audit::AuditEvent;
+use crate::database::auth_token::AuthToken;
+use crate::database::database::{DBConn, DBPool};
+use crate::database::schema::{AuditEventType, UserStatus};
+use crate::database::user::{User, UserIdentity};
+use crate::utils::auth::{AcceptLanguage, DeviceInfo};
+use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use crate::utils::oidc::{OidcLogin, OidcLogins, OidcProvider, OidcProviders};
+use crate::utils::utils::{get_frontend_host, random_token};
+use crate::utils::validation::{validate_input, validate_redirect_url};
+use openidconnect::core::{CoreAuthenticationFlow, CoreClient, CoreIdTokenClaims, CoreProviderMetadata};
+use openidconnect::reqwest::async_http_client;
+use openidconnect::{AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::{openapi, JsonSchema};
+use validator::Validate;
+
+#[derive(JsonSchema, Deserialize, Debug, Validate)]
+pub struct OidcStartData {
+    /// Optional URL to redirect to once signed in, must be on `FRONTEND_HOST`
+    #[validate(custom(function = validate_redirect_url))]
+    redirect_url: Option<String>,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct OidcStartResponse {
+    /// URL of the provider authorization page, to which the user must be redirected
+    pub authorize_url: String,
+}
+
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct OidcCallbackData {
+    /// `code` query parameter of the provider redirection
+    code: String,
+    /// `state` query parameter of the provider redirection
+    state: String,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct OidcCallbackResponse {
+    pub status: UserStatus,
+    pub user_id: u32,
+    pub name: String,
+    pub email: String,
+    pub auth_token: String,
+    pub redirect_url: String,
+}
+
+/// List the names of the configured OIDC providers.
+#[openapi(tag = "Authentication")]
+#[get("/auth/oidc/providers")]
+pub fn auth_oidc_providers(providers: &rocket::State<OidcProviders>) -> Json<Vec<String>> {
+    Json(providers.names())
+}
+
+/// Start a sign in through an OIDC provider, returning the provider URL to which the user must be redirected.
+/// The provider then redirects the user to `FRONTEND_HOST/oidc/<provider>/callback`, which must call `/auth/oidc/<provider>/callback`.
+/// - Throw `OIDCProviderNotFound` if the provider is not configured.
+/// - Throw `InvalidInput` if the redirect URL is not on `FRONTEND_HOST`.
+#[openapi(tag = "Authentication")]
+#[post("/auth/oidc/<provider>/start", data = "<data>")]
+pub async fn auth_oidc_start(provider: &str, data: Json<OidcStartData>, providers: &rocket::State<OidcProviders>, logins: &rocket::State<OidcLogins>) -> Result<Json<OidcStartResponse>, ErrorResponder> {
+    validate_input(&data)?;
+    let provider = providers.get(provider).ok_or(ErrorType::OIDCProviderNotFound.res())?;
+    let client = oidc_client(provider).await?;
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let (authorize_url, csrf_token, nonce) = client
+        .authorize_url(CoreAuthenticationFlow::AuthorizationCode, CsrfToken::new_random, Nonce::new_random)
+        .add_scope(Scope::new("email".to_string()))
+        .add_scope(Scope::new("profile".to_string()))
+        .set_pkce_challenge(pkce_challenge)
+        .url();
+
+    logins.insert(csrf_token.secret().clone(), OidcLogin {
+        provider: provider.name.clone(),
+        nonce,
+        pkce_verifier,
+        redirect_url: data.redirect_url.clone(),
+    });
+    Ok(Json(OidcStartResponse { authorize_url: authorize_url.to_string() }))
+}
+
+/// Finish a sign in through an OIDC provider, returning a new auth token.
+/// The external identity is linked to the account with the same email if the provider verified it,
+/// otherwise a new account is created.
+/// - Throw `OIDCProviderNotFound` if the provider is not configured.
+/// - Throw `OIDCLoginFailed` if the state is unknown or expired, or the provider rejected the code.
+/// - Throw `OIDCEmailNotVerified` if the identity is new and its email is not verified by the provider.
+/// - Throw `UserBanned` if the user is banned.
+#[openapi(tag = "Authentication")]
+#[post("/auth/oidc/<provider>/callback", data = "<data>")]
+pub async fn auth_oidc_callback(provider: &str, data: Json<OidcCallbackData>, db: &rocket::State<DBPool>, providers: &rocket::State<OidcProviders>, logins: &rocket::State<OidcLogins>, device_info: DeviceInfo, accept_language: AcceptLanguage) -> Result<Json<OidcCallbackResponse>, ErrorResponder> {
+    let provider = providers.get(provider).ok_or(ErrorType::OIDCProviderNotFound.res())?;
+    let record_failure = |err: &ErrorResponder| {
+        if let Ok(mut conn) = db.get() {
+            AuditEvent::insert_signin_failure_for_user(&mut conn, None, err, &device_info);
+        }
+    };
+    let login = logins.take(&data.state)
+        .filter(|login| login.provider == provider.name)
+        .ok_or(ErrorType::OIDCLoginFailed.res())
+        .inspect_err(record_failure)?;
+    let client = oidc_client(provider).await?;
+    let claims = exchange_code(&client, login.pkce_verifier, &login.nonce, &data.code).await
+        .inspect_err(record_failure)?;
+
+    let subject = claims.subject().to_string();
+    let email = claims.email().map(|email| email.to_string()).ok_or(ErrorType::OIDCEmailNotVerified.res())?;
+    let email_verified = claims.email_verified().unwrap_or(false);
+    let name = claims.name()
+        .and_then(|name| name.get(None))
+        .map(|name| name.to_string())
+        .unwrap_or(email.split('@').next().unwrap_or_default().to_string());
+    let redirect_url = login.redirect_url.unwrap_or(get_frontend_host());
+
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    err_transaction(conn, |conn| {
+        let user = if let Some(identity) = UserIdentity::find_opt(conn, &provider.name, &subject)? {
+            User::from_id(conn, &identity.user_id)?
+        } else {
+            if !email_verified {
+                return ErrorType::OIDCEmailNotVerified.res_err();
+            }
+            let existing_user = User::find_by_email_opt(conn, &email)?;
+            let user_id = match existing_user {
+                Some(user) if user.status != UserStatus::Unconfirmed => user.id,
+                // New or unconfirmed account: the provider verified the email, the account does not need a password
+                _ => {
+                    let name: String = name.chars().take(32).collect();
+                    let user_id = User::create_user(conn, &name, &email, &hex::encode(random_token(32)))?;
+                    User::switch_status_from_id(conn, &user_id, &UserStatus::Normal)?;
+                    User::set_locale_from_id(conn, &user_id, accept_language.0)?;
+                    user_id
+                }
+            };
+            UserIdentity::insert(conn, &user_id, &provider.name, &subject, &email)?;
+            User::from_id(conn, &user_id)?
+        };
+        if user.status == UserStatus::Banned {
+            return ErrorType::UserBanned.res_err();
+        }
+
+        let auth_token = AuthToken::insert_token_for_user(conn, &user.id, &device_info, 0)?;
+        AuditEvent::insert(conn, AuditEventType::Signin, Some(user.id), Some(user.id), Some(format!("oidc:{}", provider.name)), &device_info)?;
+        Ok(Json(OidcCallbackResponse {
+            status: user.status,
+            user_id: user.id,
+            name: user.name,
+            email: user.email,
+            auth_token: hex::encode(auth_token),
+            redirect_url: redirect_url.clone(),
+        }))
+    })
+}
+
+/// Exchanges the authorization code for an ID token, and verifies it against the nonce of the login.
+/// - Throw `OIDCLoginFailed` if the provider rejected the code or the ID token is invalid.
+async fn exchange_code(client: &CoreClient, pkce_verifier: PkceCodeVerifier, nonce: &Nonce, code: &str) -> Result<CoreIdTokenClaims, ErrorResponder> {
+    let token_response = client.exchange_code(AuthorizationCode::new(code.to_string()))
+        .set_pkce_verifier(pkce_verifier)
+        .request_async(async_http_client)
+        .await
+        .map_err(|_| ErrorType::OIDCLoginFailed.res())?;
+    token_response.id_token()
+        .ok_or(ErrorType::OIDCLoginFailed.res())?
+        .claims(&client.id_token_verifier(), nonce)
+        .cloned()
+        .map_err(|_| ErrorType::OIDCLoginFailed.res())
+}
+
+/// Builds the OIDC client of the provider from its discovered metadata.
+async fn oidc_client(provider: &OidcProvider) -> Result<CoreClient, ErrorResponder> {
+    let issuer = IssuerUrl::new(provider.issuer.clone())
+        .map_err(|e| ErrorType::InternalError(format!("Invalid OIDC issuer URL: {}", e)).res())?;
+    let metadata = CoreProviderMetadata::discover_async(issuer, async_http_client).await
+        .map_err(|e| ErrorType::InternalError(format!("Unable to discover OIDC provider metadata: {}", e)).res())?;
+    let redirect_url = RedirectUrl::new(provider.redirect_url())
+        .map_err(|e| ErrorType::InternalError(format!("Invalid OIDC redirect URL: {}", e)).res())?;
+
+    Ok(CoreClient::from_provider_metadata(metadata, ClientId::new(provider.client_id.clone()), Some(ClientSecret::new(provider.client_secret.clone())))
+        .set_redirect_uri(redirect_url))
+}
@@ -0,0 +1,256 @@
+use crate::api::auth::signin::{check_user_password_and_status, SigninResponse};
+use crate::database::audit::AuditEvent;
+use crate::database::auth_token::{AuthToken, WebauthnCredential};
+use crate::database::database::{DBConn, DBPool};
+use crate::database::schema::{AuditEventType, UserStatus};
+use crate::database::user::User;
+use crate::utils::auth::DeviceInfo;
+use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use crate::utils::rate_limit::{RateLimitKey, RateLimiter};
+use crate::utils::validation::validate_input;
+use crate::utils::webauthn::{user_handle, user_id_from_handle, WebauthnChallenge, WebauthnChallenges};
+use chrono::NaiveDateTime;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::{openapi, JsonSchema};
+use validator::Validate;
+use webauthn_rs::prelude::{AuthenticationResult, CreationChallengeResponse, DiscoverableKey, PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse};
+use webauthn_rs::Webauthn;
+
+#[derive(JsonSchema, Deserialize, Debug, Validate)]
+pub struct PasskeyRegisterStartData {
+    /// Name of the passkey, displayed in the passkeys list
+    #[validate(length(min = 1, max = 32, message = "Name must be between 1 and 32 characters"))]
+    name: String,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct PasskeyRegisterStartResponse {
+    pub challenge_id: String,
+    /// Options to pass to `navigator.credentials.create()`
+    #[schemars(with = "rocket::serde::json::Value")]
+    pub options: CreationChallengeResponse,
+}
+
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct PasskeyRegisterFinishData {
+    challenge_id: String,
+    /// Result of `navigator.credentials.create()`
+    #[schemars(with = "rocket::serde::json::Value")]
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct PasskeyRegisterFinishResponse {
+    pub passkey_id: u32,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct PasskeyListItem {
+    pub passkey_id: u32,
+    pub name: String,
+    pub creation_date: NaiveDateTime,
+    pub last_use_date: Option<NaiveDateTime>,
+}
+
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct PasskeySigninStartData {
+    email: String,
+    password: String,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct PasskeyAuthenticationStartResponse {
+    pub challenge_id: String,
+    /// Options to pass to `navigator.credentials.get()`
+    #[schemars(with = "rocket::serde::json::Value")]
+    pub options: RequestChallengeResponse,
+}
+
+/// Passkey assertion, result of a ceremony started with `/auth/passkey/signin/start` or `/auth/passkey/passwordless/start`.
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct PasskeyAssertionData {
+    challenge_id: String,
+    /// Result of `navigator.credentials.get()`
+    #[schemars(with = "rocket::serde::json::Value")]
+    credential: PublicKeyCredential,
+}
+
+/// Start the registration of a new passkey for the authenticated user.
+#[openapi(tag = "Passkeys")]
+#[post("/auth/passkey/register/start", data = "<data>")]
+pub fn auth_passkey_register_start(data: Json<PasskeyRegisterStartData>, db: &rocket::State<DBPool>, webauthn: &rocket::State<Webauthn>, challenges: &rocket::State<WebauthnChallenges>, user: User) -> Result<Json<PasskeyRegisterStartResponse>, ErrorResponder> {
+    validate_input(&data)?;
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    let exclude_credentials = WebauthnCredential::get_user_credentials(conn, &user.id)?
+        .iter()
+        .map(|credential| credential.credential_id())
+        .collect();
+    let (options, state) = webauthn.start_passkey_registration(user_handle(&user.id), &user.email, &user.name, Some(exclude_credentials))
+        .map_err(|e| ErrorType::InternalError(format!("Unable to start passkey registration: {}", e)).res())?;
+
+    let challenge_id = challenges.insert(WebauthnChallenge::Registration { user_id: user.id, name: data.name.clone(), state })?;
+    Ok(Json(PasskeyRegisterStartResponse { challenge_id, options }))
+}
+
+/// Finish the registration of a passkey with the credential created by the browser.
+/// - Throw `InvalidPasskey` if the challenge is expired or the credential is invalid.
+#[openapi(tag = "Passkeys")]
+#[post("/auth/passkey/register/finish", data = "<data>")]
+pub fn auth_passkey_register_finish(data: Json<PasskeyRegisterFinishData>, db: &rocket::State<DBPool>, webauthn: &rocket::State<Webauthn>, challenges: &rocket::State<WebauthnChallenges>, user: User, device_info: DeviceInfo) -> Result<Json<PasskeyRegisterFinishResponse>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    let (name, state) = match challenges.take(&data.challenge_id) {
+        Some(WebauthnChallenge::Registration { user_id, name, state }) if user_id == user.id => (name, state),
+        _ => return ErrorType::InvalidPasskey.res_err(),
+    };
+    let passkey = webauthn.finish_passkey_registration(&data.credential, &state)
+        .map_err(|_| ErrorType::InvalidPasskey.res())?;
+
+    err_transaction(conn, |conn| {
+        let passkey_id = WebauthnCredential::insert_for_user(conn, &user.id, &name, &passkey)?;
+        AuditEvent::insert(conn, AuditEventType::PasskeyAdded, Some(user.id), Some(user.id), Some(name.clone()), &device_info)?;
+        Ok(Json(PasskeyRegisterFinishResponse { passkey_id }))
+    })
+}
+
+/// List the passkeys of the authenticated user.
+#[openapi(tag = "Passkeys")]
+#[get("/auth/passkey")]
+pub fn auth_passkey_list(db: &rocket::State<DBPool>, user: User) -> Result<Json<Vec<PasskeyListItem>>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    let credentials = WebauthnCredential::get_user_credentials(conn, &user.id)?;
+    Ok(Json(credentials.into_iter().map(|credential| PasskeyListItem {
+        passkey_id: credential.id,
+        name: credential.name,
+        creation_date: credential.creation_date,
+        last_use_date: credential.last_use_date,
+    }).collect()))
+}
+
+/// Remove a passkey.
+/// - Throw `PasskeyNotFound` if the passkey does not exist.
+#[openapi(tag = "Passkeys")]
+#[delete("/auth/passkey/<passkey_id>")]
+pub fn auth_passkey_delete(passkey_id: u32, db: &rocket::State<DBPool>, user: User, device_info: DeviceInfo) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    err_transaction(conn, |conn| {
+        let credential = WebauthnCredential::from_id(conn, &user.id, &passkey_id)?;
+        credential.delete(conn)?;
+        AuditEvent::insert(conn, AuditEventType::PasskeyRemoved, Some(user.id), Some(user.id), Some(credential.name), &device_info)
+    })
+}
+
+/// Start a passkey authentication used as the second factor of `/auth/signin` (`passkey` field).
+/// - Throw `InvalidEmailOrPassword`, `UserBanned` or `UserUnconfirmed` like `/auth/signin`.
+/// - Throw `PasskeyNotFound` if the user has no passkey.
+/// - Throw `TooManyRequests` if too many attempts failed for this IP address or account.
+#[openapi(tag = "Passkeys")]
+#[post("/auth/passkey/signin/start", data = "<data>")]
+pub fn auth_passkey_signin_start(data: Json<PasskeySigninStartData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, webauthn: &rocket::State<Webauthn>, challenges: &rocket::State<WebauthnChallenges>, device_info: DeviceInfo) -> Result<Json<PasskeyAuthenticationStartResponse>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    let keys = [RateLimitKey::ip("signin", &device_info), Some(RateLimitKey::email("signin", &data.email))];
+
+    let user = rate_limiter.limit(&keys, || check_user_password_and_status(conn, &data.email, &data.password))
+        .inspect_err(|err| AuditEvent::insert_signin_failure(conn, &data.email, err, &device_info))?;
+    let passkeys = WebauthnCredential::get_user_credentials(conn, &user.id)?
+        .iter()
+        .map(|credential| credential.to_passkey())
+        .collect::<Result<Vec<_>, _>>()?;
+    if passkeys.is_empty() {
+        return ErrorType::PasskeyNotFound.res_err();
+    }
+    let (options, state) = webauthn.start_passkey_authentication(&passkeys)
+        .map_err(|e| ErrorType::InternalError(format!("Unable to start passkey authentication: {}", e)).res())?;
+
+    let challenge_id = challenges.insert(WebauthnChallenge::Authentication { user_id: user.id, state })?;
+    Ok(Json(PasskeyAuthenticationStartResponse { challenge_id, options }))
+}
+
+/// Start a passwordless sign in with a discoverable passkey.
+/// - Throw `TooManyRequests` if too many sign ins were started from this IP address, or too many are pending.
+#[openapi(tag = "Passkeys")]
+#[post("/auth/passkey/passwordless/start")]
+pub fn auth_passkey_passwordless_start(rate_limiter: &rocket::State<RateLimiter>, webauthn: &rocket::State<Webauthn>, challenges: &rocket::State<WebauthnChallenges>, device_info: DeviceInfo) -> Result<Json<PasskeyAuthenticationStartResponse>, ErrorResponder> {
+    let keys = [RateLimitKey::ip("passwordless_start", &device_info)];
+
+    rate_limiter.limit_every_attempt(&keys, || {
+        let (options, state) = webauthn.start_discoverable_authentication()
+            .map_err(|e| ErrorType::InternalError(format!("Unable to start passkey authentication: {}", e)).res())?;
+
+        let challenge_id = challenges.insert(WebauthnChallenge::Discoverable { state })?;
+        Ok(Json(PasskeyAuthenticationStartResponse { challenge_id, options }))
+    })
+}
+
+/// Finish a passwordless sign in; the passkey verifies the user, no other factor is required.
+/// - Throw `InvalidPasskey` if the challenge is expired or the assertion is invalid.
+/// - Throw `UserBanned` if the user is banned.
+/// - Throw `UserUnconfirmed` if the user is unconfirmed (account not email verified).
+/// - Throw `TooManyRequests` if too many attempts failed for this IP address.
+#[openapi(tag = "Passkeys")]
+#[post("/auth/passkey/passwordless/finish", data = "<data>")]
+pub fn auth_passkey_passwordless_finish(data: Json<PasskeyAssertionData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, webauthn: &rocket::State<Webauthn>, challenges: &rocket::State<WebauthnChallenges>, device_info: DeviceInfo) -> Result<Json<SigninResponse>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    let keys = [RateLimitKey::ip("signin", &device_info)];
+
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
+        let state = match challenges.take(&data.challenge_id) {
+            Some(WebauthnChallenge::Discoverable { state }) => state,
+            _ => return ErrorType::InvalidPasskey.res_err(),
+        };
+        let (handle, _) = webauthn.identify_discoverable_authentication(&data.credential)
+            .map_err(|_| ErrorType::InvalidPasskey.res())?;
+        let user = User::from_id_opt(conn, &user_id_from_handle(&handle))?
+            .ok_or(ErrorType::InvalidPasskey.res())?;
+        match user.status {
+            UserStatus::Banned => return ErrorType::UserBanned.res_err(),
+            UserStatus::Unconfirmed => return ErrorType::UserUnconfirmed.res_err(),
+            _ => {}
+        }
+
+        let keys = WebauthnCredential::get_user_credentials(conn, &user.id)?
+            .iter()
+            .map(|credential| credential.to_passkey().map(|passkey| DiscoverableKey::from(&passkey)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let result = webauthn.finish_discoverable_authentication(&data.credential, state, &keys)
+            .map_err(|_| ErrorType::InvalidPasskey.res())?;
+        update_used_credential(conn, &user.id, &result)?;
+
+        let auth_token = AuthToken::insert_token_for_user(conn, &user.id, &device_info, 0)?;
+        AuditEvent::insert(conn, AuditEventType::Signin, Some(user.id), Some(user.id), Some("passkey".to_string()), &device_info)?;
+        Ok(Json(SigninResponse {
+            status: user.status,
+            user_id: user.id,
+            name: user.name,
+            email: user.email,
+            auth_token: hex::encode(auth_token),
+        }))
+    })).inspect_err(|err| AuditEvent::insert_signin_failure_for_user(conn, None, err, &device_info))
+}
+
+/// Checks a passkey assertion used as a second factor by the user.
+/// - Throw `InvalidPasskey` if the challenge is expired, was started for another user, or the assertion is invalid.
+pub(crate) fn check_passkey_assertion(conn: &mut DBConn, webauthn: &Webauthn, challenges: &WebauthnChallenges, user_id: &u32, data: &PasskeyAssertionData) -> Result<(), ErrorResponder> {
+    let state = match challenges.take(&data.challenge_id) {
+        Some(WebauthnChallenge::Authentication { user_id: challenge_user_id, state }) if challenge_user_id == *user_id => state,
+        _ => return ErrorType::InvalidPasskey.res_err(),
+    };
+    let result = webauthn.finish_passkey_authentication(&data.credential, &state)
+        .map_err(|_| ErrorType::InvalidPasskey.res())?;
+    update_used_credential(conn, user_id, &result)
+}
+
+/// Stores the updated signature counter of the passkey used for an authentication.
+fn update_used_credential(conn: &mut DBConn, user_id: &u32, result: &AuthenticationResult) -> Result<(), ErrorResponder> {
+    let credential = WebauthnCredential::get_user_credentials(conn, user_id)?
+        .into_iter()
+        .find(|credential| credential.credential_id.as_slice() == result.cred_id().as_ref())
+        .ok_or(ErrorType::InvalidPasskey.res())?;
+    let mut passkey = credential.to_passkey()?;
+    passkey.update_credential(result);
+    credential.update_after_use(conn, &passkey)
+}
@@ -0,0 +1,137 @@
+use crate::database::audit::AuditEvent;
+use crate::database::auth_token::{AuthToken, Confirmation, CONFIRMATION_LIFETIME_MINUTES};
+use crate::database::database::{DBConn, DBPool};
+use crate::database::schema::{AuditEventType, ConfirmationAction, UserStatus};
+use crate::database::user::User;
+use crate::mailing::mailer::send_rendered_email;
+use crate::utils::auth::DeviceInfo;
+use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use crate::utils::rate_limit::{RateLimitKey, RateLimiter};
+use crate::utils::utils::{get_frontend_host, left_pad, random_token};
+use crate::utils::validation::{validate_input, validate_password};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::{openapi, JsonSchema};
+use validator::Validate;
+
+#[derive(JsonSchema, Deserialize, Debug, Validate)]
+pub struct PasswordForgotData {
+    #[validate(email(code = "email_invalid", message = "Invalid email"))]
+    email: String,
+    /// Optional redirect URL for the email confirmation
+    redirect_url: Option<String>,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct PasswordForgotResponse {
+    /// Token to be used with the emailed code. Random if no account matches the email,
+    /// so that the response does not reveal whether an account exists.
+    pub code_token: String,
+}
+
+#[derive(JsonSchema, Deserialize, Debug, Validate)]
+pub struct PasswordResetData {
+    /// Id of the user from the emailed link, required with `token`
+    user_id: Option<u32>,
+    /// Email of the user, required with `code_token` and `code`
+    email: Option<String>,
+    /// Emailed token, required if `code_token` and `code` are not provided
+    token: Option<String>,
+    /// Token returned by `/auth/password/forgot`, to be used with `code`
+    code_token: Option<String>,
+    /// 4-digit code emailed to the user
+    #[validate(range(min = 0, max = 9999, message = "Code must be a 4 digit number"))]
+    code: Option<u16>,
+    #[validate(custom(function = validate_password))]
+    password: String,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct PasswordResetResponse {
+    pub redirect_url: String,
+}
+
+/// Request a password reset; sends an email with a token and a 4-digit code.
+/// The response is the same whether an account matches the email or not (no email is sent to unknown
+/// or banned accounts), so that it cannot be used to find out which emails have an account.
+/// Every request counts as an attempt, as each one sends an email.
+/// - Throw `TooManyRequests` if too many resets were requested for this IP address or email.
+#[openapi(tag = "Authentication")]
+#[post("/auth/password/forgot", data = "<data>")]
+pub fn auth_password_forgot(data: Json<PasswordForgotData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, device_info: DeviceInfo) -> Result<Json<PasswordForgotResponse>, ErrorResponder> {
+    validate_input(&data)?;
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    let keys = [RateLimitKey::ip("password_forgot", &device_info), Some(RateLimitKey::email("password_forgot", &data.email))];
+
+    rate_limiter.limit_every_attempt(&keys, || err_transaction(conn, |conn| {
+        match User::find_by_email_opt(conn, &data.email)? {
+            Some(user) if user.status != UserStatus::Banned => {
+                Ok(Json(send_password_reset(conn, &user, &device_info, &data.redirect_url)?))
+            }
+            _ => Ok(Json(PasswordForgotResponse {
+                code_token: hex::encode(random_token(16)),
+            })),
+        }
+    }))
+}
+
+/// Creates a password reset confirmation and sends the password reset email to the user.
+/// Previous password reset confirmations are invalidated.
+pub(crate) fn send_password_reset(conn: &mut DBConn, user: &User, device_info: &DeviceInfo, redirect_url: &Option<String>) -> Result<PasswordForgotResponse, ErrorResponder> {
+    // Only the latest password reset confirmation is valid
+    Confirmation::mark_all_as_used(conn, &user.id, ConfirmationAction::PasswordReset)?;
+    let (token, code_token, code) = Confirmation::insert_confirmation(conn, user.id, ConfirmationAction::PasswordReset, device_info, redirect_url, 0)?;
+    let code_str = left_pad(&code.to_string(), '0', 4);
+
+    // Sending email
+    let reset_url = format!("{}/password/reset?id={}&token={}", get_frontend_host(), user.id, hex::encode(&token));
+    let mut context = tera::Context::new();
+    context.insert("name", &user.name);
+    context.insert("url", &reset_url);
+    context.insert("code", &code_str);
+    context.insert("ip", &device_info.ip_address.clone().unwrap_or("Unknown".to_string()));
+    context.insert("agent", &device_info.device_string);
+    send_rendered_email(conn, &user.id, (user.name.clone(), user.email.clone()), "password_reset".to_string(), context)?;
+
+    Ok(PasswordForgotResponse {
+        code_token: hex::encode(code_token),
+    })
+}
+
+/// Reset the password of a user with its id and the emailed token, or with its email, the code token and the emailed code.
+/// All the existing sessions of the user are revoked.
+/// - Throw `ConfirmationNotFound` if the token or code is invalid, or no account matches the email.
+/// - Throw `TooManyRequests` if too many attempts failed for this IP address or account.
+#[openapi(tag = "Authentication")]
+#[post("/auth/password/reset", data = "<data>")]
+pub fn auth_password_reset(data: Json<PasswordResetData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, device_info: DeviceInfo) -> Result<Json<PasswordResetResponse>, ErrorResponder> {
+    validate_input(&data)?;
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    let user_id = match (&data.user_id, &data.email) {
+        (Some(user_id), _) => Some(*user_id),
+        (None, Some(email)) => User::find_by_email_opt(conn, email)?.map(|user| user.id),
+        (None, None) => return ErrorType::UnprocessableEntity.res_err(),
+    };
+    let keys = [RateLimitKey::ip("confirm", &device_info), user_id.map(|user_id| RateLimitKey::account("confirm", user_id))];
+
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
+        // An unknown email fails like an invalid code
+        let user_id = user_id.ok_or(ErrorType::ConfirmationNotFound.res())?;
+        let action = ConfirmationAction::PasswordReset;
+        let redirect_url = if let Some(token) = &data.token {
+            let token = hex::decode(token).map_err(|_| ErrorType::UnprocessableEntity.res())?;
+            Confirmation::check_token_and_mark_as_used(conn, &user_id, &action, &token, CONFIRMATION_LIFETIME_MINUTES)?
+        } else if let (Some(code_token), Some(code)) = (&data.code_token, &data.code) {
+            let code_token = hex::decode(code_token).map_err(|_| ErrorType::UnprocessableEntity.res())?;
+            Confirmation::check_code_and_mark_as_used(conn, &user_id, &action, &code_token, code, CONFIRMATION_LIFETIME_MINUTES)?
+        } else {
+            return ErrorType::UnprocessableEntity.res_err();
+        }.unwrap_or(get_frontend_host());
+
+        User::update_password(conn, &user_id, &data.password)?;
+        AuthToken::clear_auth_tokens(conn, &user_id)?;
+        AuditEvent::insert(conn, AuditEventType::PasswordReset, None, Some(user_id), None, &device_info)?;
+
+        Ok(Json(PasswordResetResponse { redirect_url }))
+    }))
+}
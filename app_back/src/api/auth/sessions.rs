@@ -0,0 +1,103 @@
+use crate::api::auth::password::{send_password_reset, PasswordForgotResponse};
+use crate::database::auth_token::AuthToken;
+use crate::database::database::{DBConn, DBPool};
+use crate::database::user::User;
+use crate::utils::auth::DeviceInfo;
+use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use crate::utils::rate_limit::{RateLimitKey, RateLimiter};
+use crate::utils::utils::random_token;
+use chrono::NaiveDateTime;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::{openapi, JsonSchema};
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct SessionItem {
+    pub session_id: u32,
+    pub device_string: Option<String>,
+    pub ip_address: Option<String>,
+    pub creation_date: NaiveDateTime,
+    pub last_use_date: NaiveDateTime,
+    /// True if this is the session used to make the request
+    pub current: bool,
+}
+
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct SessionRevokeData {
+    user_id: u32,
+    session_id: u32,
+    /// Token of the "this wasn't me" link of the new sign-in email
+    token: String,
+    /// Optional redirect URL for the password reset email
+    redirect_url: Option<String>,
+}
+
+/// List the sessions (auth tokens) of the authenticated user, the most recently used first.
+#[openapi(tag = "Sessions")]
+#[get("/auth/sessions")]
+pub fn auth_sessions(db: &rocket::State<DBPool>, auth_token: AuthToken) -> Result<Json<Vec<SessionItem>>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    let auth_tokens = AuthToken::get_user_auth_tokens(conn, &auth_token.user_id)?;
+    Ok(Json(auth_tokens.into_iter().map(|token| SessionItem {
+        session_id: token.id,
+        ip_address: token.get_ip_address().map(|ip| ip.to_string()),
+        device_string: token.device_string,
+        creation_date: token.creation_date,
+        last_use_date: token.last_use_date,
+        current: token.id == auth_token.id,
+    }).collect()))
+}
+
+/// Revoke a session of the authenticated user.
+/// - Throw `SessionNotFound` if the user has no session with this id.
+#[openapi(tag = "Sessions")]
+#[delete("/auth/sessions/<session_id>")]
+pub fn auth_sessions_delete(session_id: u32, db: &rocket::State<DBPool>, auth_token: AuthToken) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    AuthToken::delete_from_id(conn, &auth_token.user_id, &session_id)
+}
+
+/// Revoke all the sessions of the authenticated user, except the current one.
+#[openapi(tag = "Sessions")]
+#[post("/auth/sessions/signout_others")]
+pub fn auth_sessions_signout_others(db: &rocket::State<DBPool>, auth_token: AuthToken) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    AuthToken::clear_auth_tokens_except(conn, &auth_token.user_id, &auth_token.id)
+}
+
+/// Sign out: revoke the current session.
+#[openapi(tag = "Sessions")]
+#[post("/auth/signout")]
+pub fn auth_signout(db: &rocket::State<DBPool>, auth_token: AuthToken) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    AuthToken::delete_from_id(conn, &auth_token.user_id, &auth_token.id)
+}
+
+/// Handle the "this wasn't me" link of a new sign-in email.
+/// All the sessions of the user are revoked, the password is invalidated and a password reset email is sent.
+/// - Throw `SessionNotFound` if the session has already been revoked.
+/// - Throw `ConfirmationNotFound` if the token is invalid.
+/// - Throw `ConfirmationExpired` if the link is older than 7 days.
+/// - Throw `TooManyRequests` if too many attempts failed for this IP address or account.
+#[openapi(tag = "Sessions")]
+#[post("/auth/sessions/revoke", data = "<data>")]
+pub fn auth_sessions_revoke(data: Json<SessionRevokeData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, device_info: DeviceInfo) -> Result<Json<PasswordForgotResponse>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    let revoke_token = hex::decode(&data.token).map_err(|_| ErrorType::UnprocessableEntity.res())?;
+    let keys = [RateLimitKey::ip("confirm", &device_info), Some(RateLimitKey::account("confirm", data.user_id))];
+
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
+        let session = AuthToken::from_id(conn, &data.user_id, &data.session_id)?;
+        session.check_revoke_token(&revoke_token)?;
+        let user = User::from_id(conn, &data.user_id)?;
+
+        AuthToken::clear_auth_tokens(conn, &user.id)?;
+        // The password may be known by someone else, it can only be changed through a password reset
+        User::update_password(conn, &user.id, &hex::encode(random_token(32)))?;
+        Ok(Json(send_password_reset(conn, &user, &device_info, &data.redirect_url)?))
+    }))
+}
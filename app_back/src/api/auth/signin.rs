@@ -1,23 +1,32 @@
-use crate::database::auth_token::{AuthToken, Confirmation, TOTPSecret};
+use crate::api::auth::passkey::{check_passkey_assertion, PasskeyAssertionData};
+use crate::database::audit::AuditEvent;
+use crate::database::auth_token::{AuthToken, Confirmation, RecoveryCode, TOTPSecret, WebauthnCredential};
 use crate::database::database::{DBConn, DBPool};
-use crate::database::schema::{ConfirmationAction, UserStatus};
+use crate::database::schema::{AuditEventType, ConfirmationAction, UserStatus};
 use crate::database::user::User;
 use crate::mailing::mailer::send_rendered_email;
 use crate::utils::auth::DeviceInfo;
 use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use crate::utils::rate_limit::{RateLimitKey, RateLimiter};
 use crate::utils::utils::{get_frontend_host, left_pad};
+use crate::utils::webauthn::WebauthnChallenges;
 use diesel::Connection;
 use pwhash::bcrypt;
 use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
 use rocket_okapi::{openapi, JsonSchema};
 use std::env;
+use webauthn_rs::Webauthn;
 
 #[derive(JsonSchema, Deserialize, Debug)]
 pub struct SigninData {
     email: String,
     password: String,
     totp_code: Option<String>,
+    /// Single-use recovery code, alternative to `totp_code` when the user lost their authenticator
+    recovery_code: Option<String>,
+    /// Passkey assertion started with `/auth/passkey/signin/start`, alternative to `totp_code`
+    passkey: Option<PasskeyAssertionData>,
     /// Optional redirect URL for the TFA confirmation (email confirmation)
     redirect_url: Option<String>
 }
@@ -38,30 +47,63 @@ pub struct SigninEmailResponse {
 }
 
 /// Endpoint to sign in a user.
-/// If the user requires 2FA, it will either throw `TFARequired`, `TFARequiredOverEmail` or `InvalidTOTPCode`.
+/// If the user requires 2FA, it will either throw `TFARequired`, `TFARequiredOverEmail`, `InvalidTOTPCode`, `InvalidRecoveryCode` or `InvalidPasskey`.
+/// - Throw `TooManyRequests` if too many attempts failed for this IP address or account.
 #[openapi(tag = "Authentication")]
 #[post("/auth/signin", data = "<data>")]
-pub fn auth_signin(data: Json<SigninData>, db: &rocket::State<DBPool>, device_info: DeviceInfo) -> Result<Json<SigninResponse>, ErrorResponder> {
+pub fn auth_signin(data: Json<SigninData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, webauthn: &rocket::State<Webauthn>, challenges: &rocket::State<WebauthnChallenges>, device_info: DeviceInfo) -> Result<Json<SigninResponse>, ErrorResponder> {
     let conn: &mut DBConn = &mut db.get().unwrap();
+    let keys = [RateLimitKey::ip("signin", &device_info), Some(RateLimitKey::email("signin", &data.email))];
 
-    err_transaction(conn, |conn| {
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
         let user = check_user_password_and_status(conn, &data.email, &data.password)?;
 
         if user.tfa_login {
             if let Some(totp_code) = &data.totp_code {
-                if !TOTPSecret::check_user_totp(conn, &user.id, totp_code)? {
+                if !TOTPSecret::check_user_totp(conn, &user.id, &user.email, totp_code)? {
                     return ErrorType::InvalidTOTPCode.res_err();
                 }
+            } else if let Some(recovery_code) = &data.recovery_code {
+                if !RecoveryCode::check_code_and_mark_as_used(conn, &user.id, recovery_code)? {
+                    return ErrorType::InvalidRecoveryCode.res_err();
+                }
+                let remaining_codes = RecoveryCode::count_unused(conn, &user.id)?;
+
+                // Notifying the user
+                let mut context = tera::Context::new();
+                context.insert("name", &user.name);
+                context.insert("remaining_codes", &remaining_codes);
+                context.insert("ip", &device_info.ip_address.clone().unwrap_or("Unknown".to_string()));
+                context.insert("agent", &device_info.device_string);
+                send_rendered_email(conn, &user.id, (user.name.clone(), user.email.clone()), "recovery_code_used".to_string(), context)?;
+            } else if let Some(passkey) = &data.passkey {
+                check_passkey_assertion(conn, webauthn, challenges, &user.id, passkey)?;
             } else {
-                // 2FA Required, checking if TOTP is available
-                if TOTPSecret::has_user_totp(conn, &user.id)? {
+                // 2FA Required, checking if TOTP or a passkey is available
+                if TOTPSecret::has_user_totp(conn, &user.id)? || WebauthnCredential::has_user_credentials(conn, &user.id)? {
                     return ErrorType::TFARequired.res_err();
                 }
                 return ErrorType::TFARequiredOverEmail.res_err();
             }
         }
 
+        let new_device = !user.tfa_login && !AuthToken::is_known_device(conn, &user.id, &device_info)?;
         let auth_token = AuthToken::insert_token_for_user(conn, &user.id, &device_info, 0)?;
+        AuditEvent::insert(conn, AuditEventType::Signin, Some(user.id), Some(user.id), Some("password".to_string()), &device_info)?;
+
+        if new_device {
+            // Notifying the user of a sign in without 2FA from an unknown device
+            let session = AuthToken::find_from_token_opt(conn, &user.id, &auth_token)?
+                .ok_or(ErrorType::InternalError("Inserted auth token not found".to_string()).res_rollback())?;
+            let revoke_token = session.insert_revoke_token(conn)?;
+            let revoke_url = format!("{}/signin/revoke?id={}&session={}&token={}", get_frontend_host(), user.id, session.id, hex::encode(&revoke_token));
+            let mut context = tera::Context::new();
+            context.insert("name", &user.name);
+            context.insert("url", &revoke_url);
+            context.insert("ip", &device_info.ip_address.clone().unwrap_or("Unknown".to_string()));
+            context.insert("agent", &device_info.device_string);
+            send_rendered_email(conn, &user.id, (user.name.clone(), user.email.clone()), "new_signin".to_string(), context)?;
+        }
 
         Ok(Json(SigninResponse {
             status: user.status,
@@ -70,16 +112,19 @@ pub fn auth_signin(data: Json<SigninData>, db: &rocket::State<DBPool>, device_in
             email: user.email,
             auth_token: hex::encode(auth_token),
         }))
-    })
+    })).inspect_err(|err| AuditEvent::insert_signin_failure(conn, &data.email, err, &device_info))
 }
 
 
 /// Login endpoint for users that require 2FA; sends a confirmation email.
+/// - Throw `TooManyRequests` if too many attempts failed for this IP address or account.
 #[openapi(tag = "Authentication")]
 #[post("/auth/signin/email", data = "<data>")]
-pub fn auth_signin_email(data: Json<SigninData>, db: &rocket::State<DBPool>, device_info: DeviceInfo) -> Result<Json<SigninEmailResponse>, ErrorResponder> {
+pub fn auth_signin_email(data: Json<SigninData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, device_info: DeviceInfo) -> Result<Json<SigninEmailResponse>, ErrorResponder> {
     let conn: &mut DBConn = &mut db.get().unwrap();
-    err_transaction(conn, |conn| {
+    let keys = [RateLimitKey::ip("signin", &device_info), Some(RateLimitKey::email("signin", &data.email))];
+
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
         let user = check_user_password_and_status(conn, &data.email, &data.password)?;
 
         let (token, code_token, code) = Confirmation::insert_confirmation(conn, user.id, ConfirmationAction::Signin, &device_info, &data.redirect_url, 0)?;
@@ -87,27 +132,26 @@ pub fn auth_signin_email(data: Json<SigninData>, db: &rocket::State<DBPool>, dev
 
         // Sending email
         let signin_url = format!("{}/signin?id={}&token={}", get_frontend_host(), user.id, hex::encode(&token));
-        let subject = "Confirm your email address".to_string();
         let mut context = tera::Context::new();
         context.insert("name", &user.name);
         context.insert("url", &signin_url);
         context.insert("code", &code_str);
-        context.insert("ip", &device_info.ip_address.unwrap_or("Unknown".to_string()));
+        context.insert("ip", &device_info.ip_address.clone().unwrap_or("Unknown".to_string()));
         context.insert("agent", &device_info.device_string);
-        send_rendered_email((user.name.clone(), data.email.clone()), subject, "confirm_signin".to_string(), context);
+        send_rendered_email(conn, &user.id, (user.name.clone(), data.email.clone()), "confirm_signin".to_string(), context)?;
 
         Ok(Json(SigninEmailResponse {
             user_id: user.id,
             code_token: hex::encode(code_token),
         }))
-    })
+    })).inspect_err(|err| AuditEvent::insert_signin_failure(conn, &data.email, err, &device_info))
 }
 
 /// Checks the user's email and password, returning the user if the credentials are correct.
 /// - Throw `InvalidEmailOrPassword` if the email or password is incorrect.
 /// - Throw `UserBanned` if the user is banned.
 /// - Throw `UserUnconfirmed` if the user is unconfirmed (account not email verified).
-fn check_user_password_and_status(conn: &mut DBConn, email: &str, password: &str) -> Result<User, ErrorResponder> {
+pub(crate) fn check_user_password_and_status(conn: &mut DBConn, email: &str, password: &str) -> Result<User, ErrorResponder> {
     let user = User::find_by_email_opt(conn, email)
         .and_then(|user| {
             if let Some(user) = user {
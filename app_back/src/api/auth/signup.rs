@@ -6,12 +6,13 @@ use std::env;
 use validator::Validate;
 
 use crate::database::auth_token::Confirmation;
-use crate::database::database::DBPool;
+use crate::database::database::{DBConn, DBPool};
 use crate::database::schema::ConfirmationAction;
 use crate::database::user::User;
 use crate::mailing::mailer::send_rendered_email;
-use crate::utils::auth::DeviceInfo;
+use crate::utils::auth::{AcceptLanguage, DeviceInfo};
 use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use crate::utils::rate_limit::{RateLimitKey, RateLimiter};
 use crate::utils::utils::{get_frontend_host, left_pad};
 use crate::utils::validation::validate_input;
 use crate::utils::validation::validate_password;
@@ -36,35 +37,45 @@ pub struct SignupResponse {
 }
 
 /// Endpoint to register a new user account.
-/// A confirmation entry will be added to the database, and an email will be sent to the user.
+/// A confirmation entry will be added to the database, and an email will be sent to the user,
+/// in the language of the `Accept-Language` header.
+/// - Throw `TooManyRequests` if too many signups with an existing email were attempted from this IP address.
 #[openapi(tag = "Authentication")]
 #[post("/auth/signup", data = "<data>")]
-pub fn auth_signup(data: Json<SignupData>, db: &rocket::State<DBPool>, device_info: DeviceInfo) -> Result<Json<SignupResponse>, ErrorResponder> {
+pub fn auth_signup(data: Json<SignupData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, device_info: DeviceInfo, accept_language: AcceptLanguage) -> Result<Json<SignupResponse>, ErrorResponder> {
     validate_input(&data)?;
     let conn = &mut db.get().unwrap();
+    let keys = [RateLimitKey::ip("signup", &device_info)];
 
-    err_transaction(conn, |conn| {
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
         // Inserting user
         let uid = User::create_user(conn, &data.name, &data.email, &data.password)?;
+        User::set_locale_from_id(conn, &uid, accept_language.0)?;
 
-        // Inserting confirmation
-        let (confirm_token, confirm_code_token, confirm_code) = Confirmation::insert_confirmation(conn, uid, ConfirmationAction::Signup, &device_info, &data.redirect_url, 0)?;
-        let confirm_code_str = left_pad(&confirm_code.to_string(), '0', 4);
-
-        // Sending email
-        let signup_url = format!("{}/signup?id={}&token={}", get_frontend_host(), uid, hex::encode(&confirm_token));
-        let subject = "Confirm your email address".to_string();
-        let mut context = tera::Context::new();
-        context.insert("name", &data.name);
-        context.insert("url", &signup_url);
-        context.insert("code", &confirm_code_str);
-        context.insert("ip", &device_info.ip_address.unwrap_or("Unknown".to_string()));
-        context.insert("agent", &device_info.device_string);
-        send_rendered_email((data.name.clone(), data.email.clone()), subject, "confirm_signup".to_string(), context);
+        let confirm_code_token = send_signup_confirmation(conn, uid, &data.name, &data.email, &device_info, &data.redirect_url)?;
 
         Ok(Json(SignupResponse {
             user_id: uid,
             code_token: hex::encode(confirm_code_token),
         }))
-    })
+    }))
+}
+
+/// Inserts a signup confirmation and sends the confirmation email, returning the code token in clear text.
+pub(crate) fn send_signup_confirmation(conn: &mut DBConn, uid: u32, name: &str, email: &str, device_info: &DeviceInfo, redirect_url: &Option<String>) -> Result<Vec<u8>, ErrorResponder> {
+    // Inserting confirmation
+    let (confirm_token, confirm_code_token, confirm_code) = Confirmation::insert_confirmation(conn, uid, ConfirmationAction::Signup, device_info, redirect_url, 0)?;
+    let confirm_code_str = left_pad(&confirm_code.to_string(), '0', 4);
+
+    // Sending email
+    let signup_url = format!("{}/signup?id={}&token={}", get_frontend_host(), uid, hex::encode(&confirm_token));
+    let mut context = tera::Context::new();
+    context.insert("name", name);
+    context.insert("url", &signup_url);
+    context.insert("code", &confirm_code_str);
+    context.insert("ip", &device_info.ip_address.clone().unwrap_or("Unknown".to_string()));
+    context.insert("agent", &device_info.device_string);
+    send_rendered_email(conn, &uid, (name.to_string(), email.to_string()), "confirm_signup".to_string(), context)?;
+
+    Ok(confirm_code_token)
 }
@@ -1,6 +1,7 @@
-use crate::database::schema::UserStatus;
+use crate::database::schema::{Locale, UserStatus};
 use crate::database::user::User;
 use crate::utils::errors_catcher::ErrorResponder;
+use chrono::NaiveDateTime;
 use rocket::serde::json::Json;
 use rocket::serde::Serialize;
 use rocket_okapi::{openapi, JsonSchema};
@@ -10,6 +11,10 @@ pub struct StatusResponse {
     pub(crate) name: String,
     pub(crate) email: String,
     pub(crate) status: UserStatus,
+    /// Date (UTC) at which the account will be deleted, if a deletion is scheduled
+    pub(crate) deletion_date: Option<NaiveDateTime>,
+    /// Language of the emails sent to the user
+    pub(crate) locale: Locale,
 }
 
 /// Get the account information of the authenticated user.
@@ -22,5 +27,7 @@ pub fn auth_status(user: User) -> Result<Json<StatusResponse>, ErrorResponder> {
         name: user.name,
         email: user.email,
         status: user.status,
+        deletion_date: user.deletion_date,
+        locale: user.locale,
     }))
 }
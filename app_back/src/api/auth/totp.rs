@@ -0,0 +1,218 @@
+use crate::database::audit::AuditEvent;
+use crate::database::auth_token::{RecoveryCode, TOTPSecret};
+use crate::database::database::{DBConn, DBPool};
+use crate::database::schema::AuditEventType;
+use crate::database::user::User;
+use crate::utils::auth::DeviceInfo;
+use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use crate::utils::rate_limit::{RateLimitKey, RateLimiter};
+use crate::utils::utils::random_token;
+use crate::utils::validation::validate_input;
+use chrono::NaiveDateTime;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::{openapi, JsonSchema};
+use validator::Validate;
+
+#[derive(JsonSchema, Deserialize, Debug, Validate)]
+pub struct TOTPEnrollData {
+    /// Name of the authenticator, displayed in the authenticators list
+    #[validate(length(min = 1, max = 32, message = "Name must be between 1 and 32 characters"))]
+    name: String,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct TOTPEnrollResponse {
+    pub totp_id: u32,
+    /// otpauth:// URL to be opened by an authenticator app
+    pub otpauth_url: String,
+    /// Base32 encoded secret, for manual entry in an authenticator app
+    pub secret: String,
+    /// Base64 encoded PNG image of the QR code of the otpauth URL
+    pub qr_png: String,
+}
+
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct TOTPConfirmData {
+    totp_id: u32,
+    code: String,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct TOTPConfirmResponse {
+    /// Recovery codes generated with the first authenticator of the user, to be shown only once.
+    /// None if the user already has recovery codes.
+    pub recovery_codes: Option<Vec<String>>,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct RecoveryCodesResponse {
+    /// Recovery codes in clear text, to be shown only once
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct RecoveryCodesStatusResponse {
+    /// Number of recovery codes that have not been used yet
+    pub remaining_codes: i64,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct TOTPListItem {
+    pub totp_id: u32,
+    pub name: String,
+    pub creation_date: NaiveDateTime,
+}
+
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct TFALoginData {
+    enabled: bool,
+    /// Current password, or `totp_code`, required to disable 2FA login
+    password: Option<String>,
+    totp_code: Option<String>,
+}
+
+/// Proof of identity required for sensitive 2FA changes: the current password or a current TOTP code.
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct ReauthenticationData {
+    password: Option<String>,
+    totp_code: Option<String>,
+}
+
+/// Start the enrollment of a new TOTP authenticator.
+/// The authenticator is only usable once confirmed with a first valid code through `/auth/totp/confirm`.
+/// Previous unconfirmed enrollments are discarded.
+#[openapi(tag = "Two-factor authentication")]
+#[post("/auth/totp/enroll", data = "<data>")]
+pub fn auth_totp_enroll(data: Json<TOTPEnrollData>, db: &rocket::State<DBPool>, user: User) -> Result<Json<TOTPEnrollResponse>, ErrorResponder> {
+    validate_input(&data)?;
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    err_transaction(conn, |conn| {
+        TOTPSecret::clear_unconfirmed(conn, &user.id)?;
+        let secret = random_token(20);
+        let totp_id = TOTPSecret::insert_secret_for_user(conn, &user.id, &data.name, &secret)?;
+
+        let totp = TOTPSecret::from_id(conn, &user.id, &totp_id)?.to_totp(&user.email)?;
+        let qr_png = totp.get_qr_base64()
+            .map_err(|e| ErrorType::InternalError(format!("Unable to generate TOTP QR code: {}", e)).res_rollback())?;
+
+        Ok(Json(TOTPEnrollResponse {
+            totp_id,
+            otpauth_url: totp.get_url(),
+            secret: totp.get_secret_base32(),
+            qr_png,
+        }))
+    })
+}
+
+/// Confirm the enrollment of a TOTP authenticator with a first valid code.
+/// Recovery codes are generated if the user does not have any unused one.
+/// - Throw `TOTPNotFound` if the authenticator does not exist.
+/// - Throw `InvalidTOTPCode` if the code is invalid.
+/// - Throw `TooManyRequests` if too many attempts failed for this account.
+#[openapi(tag = "Two-factor authentication")]
+#[post("/auth/totp/confirm", data = "<data>")]
+pub fn auth_totp_confirm(data: Json<TOTPConfirmData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, user: User, device_info: DeviceInfo) -> Result<Json<TOTPConfirmResponse>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    let keys = [Some(RateLimitKey::account("totp", user.id))];
+
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
+        let secret = TOTPSecret::from_id(conn, &user.id, &data.totp_id)?;
+        if !secret.check_code_and_mark_as_used(conn, &user.email, &data.code)? {
+            return ErrorType::InvalidTOTPCode.res_err();
+        }
+        secret.mark_as_confirmed(conn)?;
+        AuditEvent::insert(conn, AuditEventType::TotpAdded, Some(user.id), Some(user.id), Some(secret.name.clone()), &device_info)?;
+
+        let recovery_codes = if RecoveryCode::count_unused(conn, &user.id)? == 0 {
+            Some(RecoveryCode::regenerate_for_user(conn, &user.id)?)
+        } else {
+            None
+        };
+        Ok(Json(TOTPConfirmResponse { recovery_codes }))
+    }))
+}
+
+/// List the confirmed TOTP authenticators of the authenticated user.
+#[openapi(tag = "Two-factor authentication")]
+#[get("/auth/totp")]
+pub fn auth_totp_list(db: &rocket::State<DBPool>, user: User) -> Result<Json<Vec<TOTPListItem>>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    let secrets = TOTPSecret::get_user_totp_secrets(conn, &user.id)?;
+    Ok(Json(secrets.into_iter().map(|secret| TOTPListItem {
+        totp_id: secret.id,
+        name: secret.name,
+        creation_date: secret.creation_date,
+    }).collect()))
+}
+
+/// Remove a TOTP authenticator, after checking the password or a current TOTP code.
+/// If 2FA login is enabled and no authenticator remains, 2FA is done over email.
+/// - Throw `ReauthenticationRequired`, `InvalidPassword` or `InvalidTOTPCode` if the proof of identity is missing or invalid.
+/// - Throw `TOTPNotFound` if the authenticator does not exist.
+/// - Throw `TooManyRequests` if too many attempts failed for this account.
+#[openapi(tag = "Two-factor authentication")]
+#[delete("/auth/totp/<totp_id>", data = "<data>")]
+pub fn auth_totp_delete(totp_id: u32, data: Json<ReauthenticationData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, user: User, device_info: DeviceInfo) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    let keys = [Some(RateLimitKey::account("password", user.id))];
+
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
+        user.check_reauthentication(conn, data.password.as_deref(), data.totp_code.as_deref())?;
+        let secret = TOTPSecret::from_id(conn, &user.id, &totp_id)?;
+        secret.delete(conn)?;
+        AuditEvent::insert(conn, AuditEventType::TotpRemoved, Some(user.id), Some(user.id), Some(secret.name), &device_info)
+    }))
+}
+
+/// Enable or disable 2FA at login (`users.tfa_login`).
+/// When enabled, the user must provide a TOTP code, or confirm the login over email.
+/// Disabling requires the password or a current TOTP code.
+/// - Throw `ReauthenticationRequired`, `InvalidPassword` or `InvalidTOTPCode` if the proof of identity is missing or invalid.
+/// - Throw `TooManyRequests` if too many attempts failed for this account.
+#[openapi(tag = "Two-factor authentication")]
+#[post("/auth/tfa_login", data = "<data>")]
+pub fn auth_tfa_login(data: Json<TFALoginData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, user: User, device_info: DeviceInfo) -> Result<(), ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    let keys = [Some(RateLimitKey::account("password", user.id))];
+
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
+        if !data.enabled {
+            user.check_reauthentication(conn, data.password.as_deref(), data.totp_code.as_deref())?;
+        }
+        user.set_tfa_login(conn, data.enabled)?;
+        let event = if data.enabled { AuditEventType::TfaLoginEnabled } else { AuditEventType::TfaLoginDisabled };
+        AuditEvent::insert_for_user(conn, event, &user.id, &device_info)
+    }))
+}
+
+/// Get the number of unused recovery codes of the authenticated user.
+#[openapi(tag = "Two-factor authentication")]
+#[get("/auth/recovery_codes")]
+pub fn auth_recovery_codes_status(db: &rocket::State<DBPool>, user: User) -> Result<Json<RecoveryCodesStatusResponse>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+
+    Ok(Json(RecoveryCodesStatusResponse {
+        remaining_codes: RecoveryCode::count_unused(conn, &user.id)?,
+    }))
+}
+
+/// Regenerate the recovery codes of the authenticated user, invalidating the previous ones.
+/// Requires the password or a current TOTP code.
+/// - Throw `ReauthenticationRequired`, `InvalidPassword` or `InvalidTOTPCode` if the proof of identity is missing or invalid.
+/// - Throw `TooManyRequests` if too many attempts failed for this account.
+#[openapi(tag = "Two-factor authentication")]
+#[post("/auth/recovery_codes", data = "<data>")]
+pub fn auth_recovery_codes_regenerate(data: Json<ReauthenticationData>, db: &rocket::State<DBPool>, rate_limiter: &rocket::State<RateLimiter>, user: User, device_info: DeviceInfo) -> Result<Json<RecoveryCodesResponse>, ErrorResponder> {
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    let keys = [Some(RateLimitKey::account("password", user.id))];
+
+    rate_limiter.limit(&keys, || err_transaction(conn, |conn| {
+        user.check_reauthentication(conn, data.password.as_deref(), data.totp_code.as_deref())?;
+        let recovery_codes = RecoveryCode::regenerate_for_user(conn, &user.id)?;
+        AuditEvent::insert_for_user(conn, AuditEventType::RecoveryCodesRegenerated, &user.id, &device_info)?;
+        Ok(Json(RecoveryCodesResponse { recovery_codes }))
+    }))
+}
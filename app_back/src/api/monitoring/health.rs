@@ -0,0 +1,119 @@
+use crate::database::database::{DBConn, DBPool};
+use crate::utils::config::{config, Config, MailTransportKind};
+use crate::MIGRATIONS;
+use diesel::{sql_query, RunQueryDsl};
+use diesel_migrations::MigrationHarness;
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket_okapi::{openapi, JsonSchema};
+use std::fs;
+use std::time::Duration;
+use tracing::warn;
+
+/// Maximum time to wait for a database connection when checking the readiness.
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct HealthResponse {
+    pub status: String,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct DependencyStatus {
+    pub ok: bool,
+    /// Generic reason of the failure, if any (the details are only logged, as the endpoint is public)
+    pub message: Option<String>,
+}
+
+impl From<Result<(), String>> for DependencyStatus {
+    fn from(result: Result<(), String>) -> Self {
+        match result {
+            Ok(_) => DependencyStatus { ok: true, message: None },
+            Err(message) => DependencyStatus { ok: false, message: Some(message) },
+        }
+    }
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct ReadyResponse {
+    pub ready: bool,
+    pub database: DependencyStatus,
+    pub migrations: DependencyStatus,
+    pub storage: DependencyStatus,
+    pub smtp: DependencyStatus,
+}
+
+/// Liveness check, succeeds as long as the process is able to serve requests.
+#[openapi(tag = "Monitoring")]
+#[get("/health")]
+pub fn health() -> Json<HealthResponse> {
+    Json(HealthResponse { status: "ok".to_string() })
+}
+
+/// Readiness check of the dependencies: database reachable, migrations applied, storage writable and SMTP configured.
+/// Responds with a 503 status if any of them is not ready.
+#[openapi(tag = "Monitoring")]
+#[get("/ready")]
+pub fn ready(db: &rocket::State<DBPool>) -> Custom<Json<ReadyResponse>> {
+    let (database, migrations) = match db.get_timeout(DB_CHECK_TIMEOUT) {
+        Ok(mut conn) => (check_database(&mut conn), check_migrations(&mut conn)),
+        Err(e) => {
+            warn!(error = %e, "Readiness check: unable to get a database connection");
+            (Err("Database unreachable".to_string()), Err("Database unreachable".to_string()))
+        }
+    };
+    let response = ReadyResponse {
+        database: database.into(),
+        migrations: migrations.into(),
+        storage: check_storage(config()).into(),
+        smtp: check_smtp(config()).into(),
+        ready: false,
+    };
+    let ready = response.database.ok && response.migrations.ok && response.storage.ok && response.smtp.ok;
+    let status = if ready { Status::Ok } else { Status::ServiceUnavailable };
+    Custom(status, Json(ReadyResponse { ready, ..response }))
+}
+
+fn check_database(conn: &mut DBConn) -> Result<(), String> {
+    sql_query("SELECT 1").execute(conn)
+        .map(|_| ())
+        .map_err(|e| {
+            warn!(error = %e, "Readiness check: database query failed");
+            "Database query failed".to_string()
+        })
+}
+
+fn check_migrations(conn: &mut DBConn) -> Result<(), String> {
+    match conn.has_pending_migration(MIGRATIONS) {
+        Ok(false) => Ok(()),
+        Ok(true) => Err("Pending migrations".to_string()),
+        Err(e) => {
+            warn!(error = %e, "Readiness check: unable to check the migrations");
+            Err("Unable to check the migrations".to_string())
+        }
+    }
+}
+
+/// Checks that a file can be written and removed in the storage directory.
+fn check_storage(config: &Config) -> Result<(), String> {
+    let path = config.storage_path.join(".ready_check");
+    fs::create_dir_all(&config.storage_path)
+        .and_then(|_| fs::write(&path, b"ok"))
+        .and_then(|_| fs::remove_file(&path))
+        .map_err(|e| {
+            warn!(path = %path.display(), error = %e, "Readiness check: storage not writable");
+            "Storage not writable".to_string()
+        })
+}
+
+/// Checks that the SMTP settings are set, if the emails are sent through SMTP.
+fn check_smtp(config: &Config) -> Result<(), String> {
+    if config.mail_transport != MailTransportKind::Smtp || config.smtp().is_some() {
+        Ok(())
+    } else {
+        warn!("Readiness check: SMTP_SERVER, SMTP_FROM_NAME, SMTP_USERNAME and SMTP_PASSWORD must be set");
+        Err("SMTP not configured".to_string())
+    }
+}
@@ -0,0 +1,13 @@
+use crate::database::database::DBPool;
+use crate::utils::auth::MetricsAuth;
+use crate::utils::metrics::render_metrics;
+use rocket::http::ContentType;
+use rocket_okapi::openapi;
+
+/// Prometheus metrics, in the text exposition format.
+/// Requires the `Authorization: Bearer <METRICS_TOKEN>` header, and is disabled (404) when `METRICS_TOKEN` is not configured.
+#[openapi(skip)]
+#[get("/metrics")]
+pub fn metrics(db: &rocket::State<DBPool>, _auth: MetricsAuth) -> (ContentType, String) {
+    (ContentType::new("text", "plain").with_params(("version", "0.0.4")), render_metrics(db))
+}
@@ -0,0 +1,49 @@
+use crate::database::audit::AuditEvent;
+use crate::database::database::{DBConn, DBPool};
+use crate::database::group::SharedGroup;
+use crate::database::auth_token::ApiScope;
+use crate::database::schema::AuditEventType;
+use crate::utils::auth::{ApiUser, DeviceInfo};
+use crate::utils::errors_catcher::{err_transaction, ErrorResponder};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_okapi::{openapi, JsonSchema};
+
+#[derive(JsonSchema, Deserialize, Debug)]
+pub struct ShareAcceptData {
+    group_id: u32,
+    /// If true, the pictures of the group are copied into the user's library.
+    /// Copies are owned by the user, count against their storage quota and survive the revocation of the share.
+    copy: bool,
+}
+
+#[derive(JsonSchema, Serialize, Debug)]
+pub struct ShareAcceptResponse {
+    pub group_id: u32,
+    /// Ids of the pictures copied into the user's library (empty if `copy` was false)
+    pub copied_picture_ids: Vec<u64>,
+}
+
+/// Accept a group shared with the authenticated user.
+/// - Throw `ShareNotFound` if the group is not shared with the user.
+/// - Throw `ShareAlreadyAccepted` if the share has already been accepted.
+/// - Throw `StorageLimitExceeded` if the copies do not fit in the user's storage quota.
+/// - Throw `InsufficientScope` if authenticated with an API token without the `Upload` scope.
+#[openapi(tag = "Sharing")]
+#[post("/share/accept", data = "<data>")]
+pub fn share_accept(data: Json<ShareAcceptData>, db: &rocket::State<DBPool>, api_user: ApiUser, device_info: DeviceInfo) -> Result<Json<ShareAcceptResponse>, ErrorResponder> {
+    api_user.require_scope(ApiScope::Upload)?;
+    let conn: &mut DBConn = &mut db.get().unwrap();
+    let user = api_user.user;
+
+    err_transaction(conn, |conn| {
+        let shared_group = SharedGroup::from_ids(conn, &user.id, &data.group_id)?;
+        let copied_picture_ids = shared_group.accept(conn, data.copy)?;
+        AuditEvent::insert(conn, AuditEventType::ShareAccepted, Some(user.id), Some(user.id), Some(format!("group {}", data.group_id)), &device_info)?;
+
+        Ok(Json(ShareAcceptResponse {
+            group_id: data.group_id,
+            copied_picture_ids,
+        }))
+    })
+}
@@ -0,0 +1,213 @@
+use crate::database::audit::AuditEvent;
+use crate::database::auth_token::AuthToken;
+use crate::database::database::{get_connection, get_connection_pool, DBConn};
+use crate::database::picture::{Blob, Picture};
+use crate::database::schema::{AuditEventType, UserStatus};
+use crate::database::user::User;
+use crate::utils::auth::DeviceInfo;
+use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use crate::utils::utils::random_token;
+use crate::utils::validation::validate_password;
+use crate::MIGRATIONS;
+use chrono::{Duration, Utc};
+use clap::{Parser, Subcommand};
+use diesel_migrations::MigrationHarness;
+
+/// Archypix app backend, launches the API when no command is given.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Administration and maintenance commands
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AdminCommand {
+    /// Create an admin account, or promote an existing confirmed account to admin
+    CreateAdmin {
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        email: String,
+        /// Password of the new account, a random one is generated and printed if not set
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Change the storage limit of a user
+    SetQuota {
+        /// Id or email of the user
+        user: String,
+        /// New storage limit in Mo
+        storage_limit_mo: u32,
+    },
+    /// Ban a user and sign out all its sessions, or unban it
+    Ban {
+        /// Id or email of the user
+        user: String,
+        #[arg(long)]
+        unban: bool,
+    },
+    /// Permanently delete the pictures that are in the trash for more than the given number of days
+    PurgeTrash {
+        #[arg(long, default_value_t = 30)]
+        older_than_days: i64,
+    },
+    /// Recompute the storage used by every user from the pictures they own
+    RecomputeStorage,
+    /// Run the pending database migrations
+    Migrate {
+        /// Only list the pending migrations
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/// Runs an admin command, returning the process exit code.
+pub fn run_admin_command(command: AdminCommand) -> i32 {
+    if let AdminCommand::Migrate { dry_run } = command {
+        return migrate(dry_run);
+    }
+
+    let pool = get_connection_pool();
+    let conn: &mut DBConn = &mut pool.get().unwrap();
+    let result = match command {
+        AdminCommand::CreateAdmin { name, email, password } => create_admin(conn, &name, &email, password),
+        AdminCommand::SetQuota { user, storage_limit_mo } => set_quota(conn, &user, storage_limit_mo),
+        AdminCommand::Ban { user, unban } => ban(conn, &user, unban),
+        AdminCommand::PurgeTrash { older_than_days } => purge_trash(conn, older_than_days),
+        AdminCommand::RecomputeStorage => recompute_storage(conn),
+        AdminCommand::Migrate { .. } => unreachable!(),
+    };
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            1
+        }
+    }
+}
+
+/// Device recorded in the audit events of the admin commands.
+fn cli_device_info() -> DeviceInfo {
+    DeviceInfo {
+        device_string: "Admin CLI".to_string(),
+        ip_address: None,
+    }
+}
+
+/// Finds a user from its id or email.
+fn find_user(conn: &mut DBConn, user: &str) -> Result<User, ErrorResponder> {
+    match user.parse::<u32>() {
+        Ok(user_id) => User::from_id(conn, &user_id),
+        Err(_) => User::find_by_email_opt(conn, user)?.ok_or(ErrorType::UserNotFound.res()),
+    }
+}
+
+fn create_admin(conn: &mut DBConn, name: &str, email: &str, password: Option<String>) -> Result<(), ErrorResponder> {
+    err_transaction(conn, |conn| {
+        if let Some(user) = User::find_by_email_opt(conn, email)? {
+            if user.status != UserStatus::Unconfirmed {
+                user.switch_status(conn, &UserStatus::Admin)?;
+                println!("Promoted user {} ({}) to admin", user.id, user.email);
+                return Ok(());
+            }
+        }
+
+        let generated = password.is_none();
+        let password = password.unwrap_or_else(|| hex::encode(random_token(12)) + "Aa1");
+        if let Err(e) = validate_password(&password) {
+            return ErrorType::InvalidInput(e.message.unwrap_or_default().to_string()).res_err();
+        }
+        let user_id = User::create_user(conn, name, email, &password)?;
+        User::switch_status_from_id(conn, &user_id, &UserStatus::Admin)?;
+        println!("Created admin user {} ({})", user_id, email);
+        if generated {
+            println!("Generated password: {}", password);
+        }
+        Ok(())
+    })
+}
+
+fn set_quota(conn: &mut DBConn, user: &str, storage_limit_mo: u32) -> Result<(), ErrorResponder> {
+    err_transaction(conn, |conn| {
+        let user = find_user(conn, user)?;
+        User::set_storage_limit_from_id(conn, &user.id, storage_limit_mo)?;
+        let details = format!("{} Mo -> {} Mo", user.storage_limit_mo, storage_limit_mo);
+        AuditEvent::insert(conn, AuditEventType::StorageLimitChanged, None, Some(user.id), Some(details), &cli_device_info())?;
+        println!("Storage limit of user {} set to {} Mo (currently using {} Ko)", user.id, storage_limit_mo, user.storage_count_ko);
+        Ok(())
+    })
+}
+
+fn ban(conn: &mut DBConn, user: &str, unban: bool) -> Result<(), ErrorResponder> {
+    err_transaction(conn, |conn| {
+        let user = find_user(conn, user)?;
+        if unban {
+            if user.status == UserStatus::Banned {
+                user.switch_status(conn, &UserStatus::Normal)?;
+                AuditEvent::insert(conn, AuditEventType::UserUnbanned, None, Some(user.id), None, &cli_device_info())?;
+            }
+            println!("Unbanned user {}", user.id);
+        } else {
+            user.switch_status(conn, &UserStatus::Banned)?;
+            AuthToken::clear_auth_tokens(conn, &user.id)?;
+            AuditEvent::insert(conn, AuditEventType::UserBanned, None, Some(user.id), None, &cli_device_info())?;
+            println!("Banned user {}", user.id);
+        }
+        Ok(())
+    })
+}
+
+fn purge_trash(conn: &mut DBConn, older_than_days: i64) -> Result<(), ErrorResponder> {
+    let date = Utc::now().naive_utc() - Duration::days(older_than_days);
+    let unreferenced_blobs = err_transaction(conn, |conn| {
+        let picture_ids = Picture::get_trashed_before(conn, &date)?;
+        let unreferenced_blobs = Picture::delete_permanently(conn, &picture_ids)?;
+        println!("Permanently deleted {} pictures", picture_ids.len());
+        Ok(unreferenced_blobs)
+    })?;
+    Blob::delete_files(&unreferenced_blobs);
+    Ok(())
+}
+
+fn recompute_storage(conn: &mut DBConn) -> Result<(), ErrorResponder> {
+    err_transaction(conn, |conn| {
+        let changed = User::recompute_storage_counts(conn)?;
+        for (user_id, old_count, new_count) in &changed {
+            println!("User {}: {} Ko -> {} Ko", user_id, old_count, new_count);
+        }
+        println!("Fixed the storage count of {} users", changed.len());
+        Ok(())
+    })
+}
+
+fn migrate(dry_run: bool) -> i32 {
+    let mut conn = get_connection();
+    let result = if dry_run {
+        conn.pending_migrations(MIGRATIONS).map(|migrations| {
+            migrations.iter().for_each(|migration| println!("Pending: {}", migration.name()));
+            println!("{} pending migrations", migrations.len());
+        })
+    } else {
+        conn.run_pending_migrations(MIGRATIONS).map(|versions| {
+            versions.iter().for_each(|version| println!("Applied: {}", version));
+            println!("{} migrations applied", versions.len());
+        })
+    };
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
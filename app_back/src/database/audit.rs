@@ -0,0 +1,121 @@
+use crate::database::database::DBConn;
+use crate::database::schema::*;
+use crate::utils::auth::DeviceInfo;
+use crate::utils::errors_catcher::{ErrorResponder, ErrorType, ErrorTypeKind};
+use crate::utils::utils::ip_from_bytes;
+use chrono::NaiveDateTime;
+use diesel::{insert_into, BoolExpressionMethods, ExpressionMethods, Identifiable, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper};
+use std::net::IpAddr;
+
+/// Security relevant event (sign in, credentials change, ban...), kept for the user's security activity
+/// and for the admins.
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq)]
+#[diesel(primary_key(id))]
+#[diesel(table_name = audit_events)]
+pub struct AuditEvent {
+    pub id: u64,
+    pub event: AuditEventType,
+    pub actor_user_id: Option<u32>,
+    pub target_user_id: Option<u32>,
+    pub details: Option<String>,
+    pub device_string: Option<String>,
+    pub ip_address: Option<Vec<u8>>,
+    pub creation_date: NaiveDateTime,
+}
+
+impl AuditEvent {
+    /// Records an event performed by `actor_user_id` on `target_user_id`, from the device of the request.
+    pub fn insert(conn: &mut DBConn, event: AuditEventType, actor_user_id: Option<u32>, target_user_id: Option<u32>, details: Option<String>, device_info: &DeviceInfo) -> Result<(), ErrorResponder> {
+        insert_into(audit_events::table)
+            .values((
+                audit_events::dsl::event.eq(event),
+                audit_events::dsl::actor_user_id.eq(actor_user_id),
+                audit_events::dsl::target_user_id.eq(target_user_id),
+                audit_events::dsl::details.eq(details),
+                audit_events::dsl::device_string.eq(&device_info.device_string),
+                audit_events::dsl::ip_address.eq(inet6_aton(&device_info.ip_address)),
+            ))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to insert audit event".to_string(), e).res_rollback()
+            })
+    }
+    /// Records an event performed by a user on their own account.
+    pub fn insert_for_user(conn: &mut DBConn, event: AuditEventType, user_id: &u32, device_info: &DeviceInfo) -> Result<(), ErrorResponder> {
+        AuditEvent::insert(conn, event, Some(*user_id), Some(*user_id), None, device_info)
+    }
+    /// Records a failed sign in if the error is caused by wrong credentials.
+    /// The target user is the user with this email, if any.
+    /// Errors are ignored as the sign in already failed.
+    pub fn insert_signin_failure(conn: &mut DBConn, email: &str, error: &ErrorResponder, device_info: &DeviceInfo) {
+        if !is_signin_failure(error) {
+            return;
+        }
+        let target_user_id = users::table
+            .filter(users::dsl::email.eq(email))
+            .select(users::dsl::id)
+            .first::<u32>(conn)
+            .ok();
+        AuditEvent::insert_signin_failure_for_user(conn, target_user_id, error, device_info);
+    }
+    /// Records a failed sign in without email (passwordless passkey, OIDC provider) if the error is caused by
+    /// wrong credentials or a rejected login.
+    /// Errors are ignored as the sign in already failed.
+    pub fn insert_signin_failure_for_user(conn: &mut DBConn, target_user_id: Option<u32>, error: &ErrorResponder, device_info: &DeviceInfo) {
+        if !is_signin_failure(error) {
+            return;
+        }
+        let _ = AuditEvent::insert(conn, AuditEventType::SigninFailed, None, target_user_id, Some(error.error_type().to_string()), device_info);
+    }
+
+    /// Gets the events targeting a user, the most recent first.
+    pub fn get_user_events(conn: &mut DBConn, user_id: &u32, offset: i64, limit: i64) -> Result<Vec<AuditEvent>, ErrorResponder> {
+        audit_events::table
+            .filter(audit_events::dsl::target_user_id.eq(user_id))
+            .order(audit_events::dsl::id.desc())
+            .offset(offset)
+            .limit(limit)
+            .select(AuditEvent::as_select())
+            .load::<AuditEvent>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user audit events".to_string(), e).res_rollback()
+            })
+    }
+    /// Gets the events performed by or targeting a user (all users if None), optionally of a single type,
+    /// the most recent first.
+    pub fn search_events(conn: &mut DBConn, user_id: &Option<u32>, event: &Option<AuditEventType>, offset: i64, limit: i64) -> Result<Vec<AuditEvent>, ErrorResponder> {
+        let mut query = audit_events::table.into_boxed();
+        if let Some(user_id) = user_id {
+            query = query.filter(audit_events::dsl::actor_user_id.eq(user_id).or(audit_events::dsl::target_user_id.eq(user_id)));
+        }
+        if let Some(event) = event {
+            query = query.filter(audit_events::dsl::event.eq(*event));
+        }
+        query
+            .order(audit_events::dsl::id.desc())
+            .offset(offset)
+            .limit(limit)
+            .select(AuditEvent::as_select())
+            .load::<AuditEvent>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to search audit events".to_string(), e).res_rollback()
+            })
+    }
+
+    pub fn get_ip_address(&self) -> Option<IpAddr> {
+        self.ip_address.as_ref().and_then(|ip| ip_from_bytes(ip))
+    }
+}
+
+/// Errors of a sign in caused by wrong credentials or a rejected login, recorded as failed sign ins.
+fn is_signin_failure(error: &ErrorResponder) -> bool {
+    matches!(
+        error.error_type(),
+        ErrorTypeKind::InvalidEmailOrPassword
+            | ErrorTypeKind::InvalidTOTPCode
+            | ErrorTypeKind::InvalidRecoveryCode
+            | ErrorTypeKind::InvalidPasskey
+            | ErrorTypeKind::OIDCLoginFailed
+    )
+}
@@ -3,56 +3,172 @@ use crate::database::schema::*;
 use crate::database::utils::is_error_duplicate_key;
 use crate::utils::auth::DeviceInfo;
 use crate::utils::errors_catcher::{ErrorResponder, ErrorType};
-use crate::utils::utils::{random_code, random_token};
+use crate::utils::utils::{constant_time_eq, get_auth_token_idle_days, get_auth_token_max_age_days, get_totp_skew_steps, hash_token, ip_from_bytes, random_code, random_token};
 use chrono::{Duration, NaiveDateTime, TimeDelta, Utc};
 use diesel::{delete, QueryDsl, SelectableHelper};
-use diesel::{insert_into, update, Identifiable, Insertable, Queryable, RunQueryDsl, Selectable};
-use diesel::{ExpressionMethods, OptionalExtension};
+use diesel::{insert_into, select, update, Identifiable, Insertable, Queryable, RunQueryDsl, Selectable};
+use diesel::dsl::exists;
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension};
 use rocket::Request;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use rocket_okapi::JsonSchema;
+use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+use tracing::warn;
 use totp_rs::{Rfc6238, TOTP};
+use webauthn_rs::prelude::{CredentialID, Passkey};
 
-#[derive(Queryable, Selectable, Identifiable, Insertable, Debug, PartialEq)]
-#[diesel(primary_key(user_id, token))]
+#[derive(Queryable, Selectable, Identifiable, Insertable, Debug, Clone, PartialEq)]
+#[diesel(primary_key(id))]
 #[diesel(belongs_to(User))]
 #[diesel(table_name = auth_tokens)]
 pub struct AuthToken {
+    /// Public identifier of the session, the token itself must never be exposed
+    pub id: u32,
     pub user_id: u32,
-    pub token: Vec<u8>,
+    /// First bytes of the token, not secret enough to authenticate but used for lookup
+    pub token_prefix: Vec<u8>,
+    /// Keyed hash of the token (see [`hash_token`])
+    pub token_hash: Vec<u8>,
     pub creation_date: NaiveDateTime,
     pub last_use_date: NaiveDateTime,
     pub device_string: Option<String>,
     pub ip_address: Option<Vec<u8>>,
+    /// Keyed hash of the token of the "this wasn't me" link sent in the new sign-in email (see [`hash_token`])
+    pub revoke_token_hash: Option<Vec<u8>>,
 }
 
+/// Number of bytes of the token stored in clear text for lookup
+const AUTH_TOKEN_PREFIX_LENGTH: usize = 8;
+/// Lifetime of the "this wasn't me" link of the new sign-in email, in days
+pub const REVOKE_TOKEN_LIFETIME_DAYS: i64 = 7;
+/// Lifetime of the emailed confirmation tokens and codes, in minutes
+pub const CONFIRMATION_LIFETIME_MINUTES: i64 = 15;
+
 impl AuthToken {
+    /// Creates a new auth token for the user, returning the token in clear text.
+    /// Only the prefix and the keyed hash of the token are stored.
     pub(crate) fn insert_token_for_user(conn: &mut DBConn, user_id: &u32, device_info: &DeviceInfo, try_count: u8) -> Result<Vec<u8>, ErrorResponder> {
         let auth_token = random_token(32);
 
         insert_into(auth_tokens::table)
             .values((
                 auth_tokens::dsl::user_id.eq(user_id),
-                auth_tokens::dsl::token.eq(&auth_token),
+                auth_tokens::dsl::token_prefix.eq(&auth_token[..AUTH_TOKEN_PREFIX_LENGTH]),
+                auth_tokens::dsl::token_hash.eq(hash_token(&auth_token)),
                 auth_tokens::dsl::device_string.eq(&device_info.device_string),
                 auth_tokens::dsl::ip_address.eq(inet6_aton(&device_info.ip_address))
             ))
             .execute(conn)
             .map(|_| auth_token)
             .or_else(|e| {
-                if is_error_duplicate_key(&e, "auth_tokens.PRIMARY") && try_count < 4 {
-                    println!("Auth token already exists, trying again.");
+                if is_error_duplicate_key(&e, "auth_tokens.UQ_auth_tokens_prefix") && try_count < 4 {
+                    warn!(user_id, try_count, "Auth token prefix already exists, trying again");
                     return AuthToken::insert_token_for_user(conn, user_id, device_info, try_count + 1);
                 }
                 ErrorType::DatabaseError("Failed to insert auth token".to_string(), e).res_err_rollback()
             })
     }
+    /// Finds the auth token of the user matching the clear text token.
+    /// The token is looked up by its prefix, then its hash is compared in constant time.
+    pub fn find_from_token_opt(conn: &mut DBConn, user_id: &u32, auth_token: &[u8]) -> Result<Option<AuthToken>, ErrorResponder> {
+        if auth_token.len() < AUTH_TOKEN_PREFIX_LENGTH {
+            return Ok(None);
+        }
+        let candidate = auth_tokens::table
+            .filter(auth_tokens::dsl::user_id.eq(user_id))
+            .filter(auth_tokens::dsl::token_prefix.eq(&auth_token[..AUTH_TOKEN_PREFIX_LENGTH]))
+            .select(AuthToken::as_select())
+            .first::<AuthToken>(conn)
+            .optional()
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get auth token".to_string(), e).res_rollback()
+            })?;
+        Ok(candidate.filter(|candidate| constant_time_eq(&candidate.token_hash, &hash_token(auth_token))))
+    }
+    /// Gets the auth token of the user with the given id.
+    /// - Throw `SessionNotFound` if the user has no auth token with this id.
+    pub fn from_id(conn: &mut DBConn, user_id: &u32, id: &u32) -> Result<AuthToken, ErrorResponder> {
+        auth_tokens::table
+            .filter(auth_tokens::dsl::user_id.eq(user_id))
+            .filter(auth_tokens::dsl::id.eq(id))
+            .select(AuthToken::as_select())
+            .first::<AuthToken>(conn)
+            .optional()
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get auth token".to_string(), e).res_rollback()
+            })?
+            .ok_or(ErrorType::SessionNotFound.res())
+    }
+    /// Checks whether the user has a session on the same device (device string) and from the same IP address.
+    /// Both must match, as an IP address can be shared by many devices (NAT) and a device string by many users.
+    pub fn is_known_device(conn: &mut DBConn, user_id: &u32, device_info: &DeviceInfo) -> Result<bool, ErrorResponder> {
+        select(exists(
+            auth_tokens::table
+                .filter(auth_tokens::dsl::user_id.eq(user_id))
+                .filter(auth_tokens::dsl::device_string.eq(&device_info.device_string))
+                .filter(auth_tokens::dsl::ip_address.eq(inet6_aton(&device_info.ip_address)))
+        ))
+            .get_result::<bool>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to check known devices".to_string(), e).res_rollback()
+            })
+    }
+    /// Creates the token of the "this wasn't me" link of the new sign-in email, returning it in clear text.
+    /// Must be called when the session is created, the link expiring `REVOKE_TOKEN_LIFETIME_DAYS` after the session creation.
+    pub fn insert_revoke_token(&self, conn: &mut DBConn) -> Result<Vec<u8>, ErrorResponder> {
+        let revoke_token = random_token(16);
+        update(auth_tokens::table)
+            .filter(auth_tokens::dsl::id.eq(self.id))
+            .set(auth_tokens::dsl::revoke_token_hash.eq(hash_token(&revoke_token)))
+            .execute(conn)
+            .map(|_| revoke_token)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to set auth token revoke token".to_string(), e).res_rollback()
+            })
+    }
+    /// Checks the clear text revoke token against its stored hash in constant time, and its age.
+    /// - Throw `ConfirmationNotFound` if the token is invalid.
+    /// - Throw `ConfirmationExpired` if the token is older than `REVOKE_TOKEN_LIFETIME_DAYS`.
+    pub fn check_revoke_token(&self, revoke_token: &[u8]) -> Result<(), ErrorResponder> {
+        let valid = self.revoke_token_hash.as_ref()
+            .is_some_and(|revoke_token_hash| constant_time_eq(revoke_token_hash, &hash_token(revoke_token)));
+        if !valid {
+            return ErrorType::ConfirmationNotFound.res_err();
+        }
+        if self.creation_date < Utc::now().naive_utc() - Duration::days(REVOKE_TOKEN_LIFETIME_DAYS) {
+            return ErrorType::ConfirmationExpired.res_err();
+        }
+        Ok(())
+    }
+    /// Checks whether the token exceeded its absolute lifetime (`AUTH_TOKEN_MAX_AGE_DAYS`) or its idle
+    /// lifetime (`AUTH_TOKEN_IDLE_DAYS`). The idle lifetime slides with every use of the token.
+    pub fn is_expired(&self) -> bool {
+        let current_naive = Utc::now().naive_utc();
+        current_naive - self.creation_date > Duration::days(get_auth_token_max_age_days())
+            || current_naive - self.last_use_date > Duration::days(get_auth_token_idle_days())
+    }
+    /// Deletes all the expired auth tokens (see [`AuthToken::is_expired`]), returning the number of deleted tokens.
+    pub fn delete_expired(conn: &mut DBConn) -> Result<usize, ErrorResponder> {
+        let current_naive = Utc::now().naive_utc();
+        delete(auth_tokens::table)
+            .filter(auth_tokens::dsl::creation_date.lt(current_naive - Duration::days(get_auth_token_max_age_days()))
+                .or(auth_tokens::dsl::last_use_date.lt(current_naive - Duration::days(get_auth_token_idle_days()))))
+            .execute(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to delete expired auth tokens".to_string(), e).res_rollback()
+            })
+    }
     pub fn update_last_use_date(&self, conn: &mut DBConn) -> Result<(), ErrorResponder> {
         // Working in UTC time.
         let current_naive = Utc::now().naive_utc();
         if current_naive - self.last_use_date > TimeDelta::try_minutes(10).unwrap() {
-            println!("Updating last_use_date");
             update(auth_tokens::table)
-                .filter(auth_tokens::dsl::user_id.eq(self.user_id))
-                .filter(auth_tokens::dsl::token.eq(self.token.clone()))
+                .filter(auth_tokens::dsl::id.eq(self.id))
                 .set((
                     auth_tokens::dsl::last_use_date.eq(utc_timestamp()),
                 ))
@@ -62,9 +178,57 @@ impl AuthToken {
         }
         Ok(())
     }
+    pub fn get_ip_address(&self) -> Option<IpAddr> {
+        self.ip_address.as_ref().and_then(|ip| ip_from_bytes(ip))
+    }
     pub fn get_auth_token_from_headers(request: &Request<'_>) -> Option<Vec<u8>> {
         request.headers().get_one("X-Auth-Token").map(|s| hex::decode(s).ok()).flatten()
     }
+    pub fn get_user_auth_tokens(conn: &mut DBConn, user_id: &u32) -> Result<Vec<AuthToken>, ErrorResponder> {
+        auth_tokens::table
+            .filter(auth_tokens::dsl::user_id.eq(user_id))
+            .order(auth_tokens::dsl::last_use_date.desc())
+            .select(AuthToken::as_select())
+            .load::<AuthToken>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user auth tokens".to_string(), e).res_rollback()
+            })
+    }
+    pub fn delete(&self, conn: &mut DBConn) -> Result<(), ErrorResponder> {
+        delete(auth_tokens::table)
+            .filter(auth_tokens::dsl::id.eq(self.id))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to delete auth token".to_string(), e).res_rollback()
+            })
+    }
+    /// Deletes the auth token with the given id.
+    /// - Throw `SessionNotFound` if the user has no auth token with this id.
+    pub fn delete_from_id(conn: &mut DBConn, user_id: &u32, id: &u32) -> Result<(), ErrorResponder> {
+        let deleted = delete(auth_tokens::table)
+            .filter(auth_tokens::dsl::user_id.eq(user_id))
+            .filter(auth_tokens::dsl::id.eq(id))
+            .execute(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to delete auth token".to_string(), e).res_rollback()
+            })?;
+        if deleted == 0 {
+            return ErrorType::SessionNotFound.res_err();
+        }
+        Ok(())
+    }
+    /// Deletes all the auth tokens of the user except the one with the given id.
+    pub fn clear_auth_tokens_except(conn: &mut DBConn, user_id: &u32, id: &u32) -> Result<(), ErrorResponder> {
+        delete(auth_tokens::table)
+            .filter(auth_tokens::dsl::user_id.eq(user_id))
+            .filter(auth_tokens::dsl::id.ne(id))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to delete other auth tokens".to_string(), e).res_rollback()
+            })
+    }
     pub fn clear_auth_tokens(conn: &mut DBConn, user_id: &u32) -> Result<(), ErrorResponder> {
         delete(auth_tokens::table)
             .filter(auth_tokens::dsl::user_id.eq(user_id))
@@ -86,8 +250,10 @@ pub struct Confirmation {
     pub action: ConfirmationAction,
     pub used: bool,
     pub date: NaiveDateTime,
-    pub token: Vec<u8>,
-    pub code_token: Vec<u8>,
+    /// Keyed hash of the emailed token (see [`hash_token`])
+    pub token_hash: Vec<u8>,
+    /// Keyed hash of the token sent to the browser (see [`hash_token`])
+    pub code_token_hash: Vec<u8>,
     pub code: u16,
     pub code_trials: u8,
     pub redirect_url: Option<String>,
@@ -96,6 +262,8 @@ pub struct Confirmation {
 }
 
 impl Confirmation {
+    /// Creates a new confirmation, returning the token, the code token (both in clear text) and the code.
+    /// Only the keyed hashes of the tokens are stored.
     pub(crate) fn insert_confirmation(conn: &mut DBConn, user_id: u32, action: ConfirmationAction, device_info: &DeviceInfo, redirect_url: &Option<String>, try_count: u8) -> Result<(Vec<u8>, Vec<u8>, u16), ErrorResponder> {
         let token = random_token(16);
         let code_token = random_token(16);
@@ -105,8 +273,8 @@ impl Confirmation {
             .values((
                 confirmations::dsl::user_id.eq::<u32>(user_id),
                 confirmations::dsl::action.eq(&action),
-                confirmations::dsl::token.eq(&token),
-                confirmations::dsl::code_token.eq(&code_token),
+                confirmations::dsl::token_hash.eq(hash_token(&token)),
+                confirmations::dsl::code_token_hash.eq(hash_token(&code_token)),
                 confirmations::dsl::code.eq(&code),
                 confirmations::dsl::redirect_url.eq(redirect_url),
                 confirmations::dsl::device_string.eq(&device_info.device_string),
@@ -116,22 +284,41 @@ impl Confirmation {
             .map(|_| (token, code_token, code))
             .or_else(|e| {
                 if (is_error_duplicate_key(&e, "confirmations.PRIMARY") || is_error_duplicate_key(&e, "confirmations.UQ_confirmations")) && try_count < 3 {
-                    println!("Confirmation token already exists, trying again.");
+                    warn!(user_id, try_count, "Confirmation token already exists, trying again");
                     return Confirmation::insert_confirmation(conn, user_id, action, device_info, redirect_url, try_count + 1);
                 }
                 ErrorType::DatabaseError("Failed to insert confirmation".to_string(), e).res_err_rollback()
             })
     }
-    pub fn check_code_and_mark_as_used(conn: &mut DBConn, user_id: &u32, action: &ConfirmationAction, code_token: &Vec<u8>, code: &u16, max_minutes: i64) -> Result<Option<String>, ErrorResponder> {
-        let mut confirmation = confirmations::table
+    /// Gets all the confirmations of the user for this action.
+    fn get_user_confirmations(conn: &mut DBConn, user_id: &u32, action: &ConfirmationAction) -> Result<Vec<Confirmation>, ErrorResponder> {
+        confirmations::table
             .filter(confirmations::dsl::user_id.eq(user_id))
             .filter(confirmations::dsl::action.eq(action))
-            .filter(confirmations::dsl::code_token.eq(code_token))
-            .first::<Confirmation>(conn)
-            .optional()
+            .select(Confirmation::as_select())
+            .load::<Confirmation>(conn)
             .map_err(|e| {
                 ErrorType::DatabaseError("Failed to get confirmation".to_string(), e).res_rollback()
-            })?;
+            })
+    }
+    /// Finds the confirmation of the user for this action matching the clear text code token.
+    /// Hashes are compared in constant time.
+    fn find_from_code_token_opt(conn: &mut DBConn, user_id: &u32, action: &ConfirmationAction, code_token: &Vec<u8>) -> Result<Option<Confirmation>, ErrorResponder> {
+        let code_token_hash = hash_token(code_token);
+        Ok(Confirmation::get_user_confirmations(conn, user_id, action)?
+            .into_iter()
+            .find(|confirmation| constant_time_eq(&confirmation.code_token_hash, &code_token_hash)))
+    }
+    /// Finds the confirmation of the user for this action matching the clear text token.
+    /// Hashes are compared in constant time.
+    fn find_from_token_opt(conn: &mut DBConn, user_id: &u32, action: &ConfirmationAction, token: &Vec<u8>) -> Result<Option<Confirmation>, ErrorResponder> {
+        let token_hash = hash_token(token);
+        Ok(Confirmation::get_user_confirmations(conn, user_id, action)?
+            .into_iter()
+            .find(|confirmation| constant_time_eq(&confirmation.token_hash, &token_hash)))
+    }
+    pub fn check_code_and_mark_as_used(conn: &mut DBConn, user_id: &u32, action: &ConfirmationAction, code_token: &Vec<u8>, code: &u16, max_minutes: i64) -> Result<Option<String>, ErrorResponder> {
+        let confirmation = Confirmation::find_from_code_token_opt(conn, user_id, action, code_token)?;
         if let Some(mut confirmation) = confirmation {
             if confirmation.used {
                 return ErrorType::ConfirmationAlreadyUsed.res_err();
@@ -147,7 +334,7 @@ impl Confirmation {
                 update(confirmations::table)
                     .filter(confirmations::dsl::user_id.eq(user_id))
                     .filter(confirmations::dsl::action.eq(action))
-                    .filter(confirmations::dsl::code_token.eq(code_token))
+                    .filter(confirmations::dsl::token_hash.eq(&confirmation.token_hash))
                     .set((
                         confirmations::dsl::code_trials.eq(confirmation.code_trials),
                     ))
@@ -164,16 +351,8 @@ impl Confirmation {
         ErrorType::ConfirmationNotFound.res_err()
     }
     pub fn check_token_and_mark_as_used(conn: &mut DBConn, user_id: &u32, action: &ConfirmationAction, token: &Vec<u8>, max_minutes: i64) -> Result<Option<String>, ErrorResponder> {
-        let mut confirmation = confirmations::table
-            .filter(confirmations::dsl::user_id.eq(user_id))
-            .filter(confirmations::dsl::action.eq(action))
-            .filter(confirmations::dsl::token.eq(token))
-            .first::<Confirmation>(conn)
-            .optional()
-            .map_err(|e| {
-                ErrorType::DatabaseError("Failed to get confirmation".to_string(), e).res_rollback()
-            })?;
-        if let Some(mut confirmation) = confirmation {
+        let confirmation = Confirmation::find_from_token_opt(conn, user_id, action, token)?;
+        if let Some(confirmation) = confirmation {
             if confirmation.used {
                 return ErrorType::ConfirmationAlreadyUsed.res_err();
             }
@@ -189,7 +368,7 @@ impl Confirmation {
         update(confirmations::table)
             .filter(confirmations::dsl::user_id.eq(&self.user_id))
             .filter(confirmations::dsl::action.eq(&self.action))
-            .filter(confirmations::dsl::token.eq(&self.token))
+            .filter(confirmations::dsl::token_hash.eq(&self.token_hash))
             .set((
                 confirmations::dsl::used.eq(true),
             ))
@@ -215,32 +394,59 @@ impl Confirmation {
 }
 
 #[derive(Queryable, Selectable, Identifiable, Insertable, Debug, PartialEq)]
-#[diesel(primary_key(user_id))]
+#[diesel(primary_key(id))]
 #[diesel(belongs_to(User))]
 #[diesel(table_name = totp_secrets)]
 pub struct TOTPSecret {
+    pub id: u32,
     pub user_id: u32,
+    pub name: String,
     pub creation_date: NaiveDateTime,
     pub secret: Vec<u8>,
+    /// False until the user has proven the enrollment with a first valid code
+    pub confirmed: bool,
+    /// Time step of the last accepted code, codes of this step or of a previous one are rejected
+    pub last_used_step: Option<u64>,
 }
 
 impl TOTPSecret {
-    pub fn insert_secret_for_user(conn: &mut DBConn, user_id: &u32, secret: &Vec<u8>) -> Result<(), ErrorResponder> {
+    /// Inserts a new unconfirmed secret for the user, returning its id.
+    pub fn insert_secret_for_user(conn: &mut DBConn, user_id: &u32, name: &str, secret: &Vec<u8>) -> Result<u32, ErrorResponder> {
         insert_into(totp_secrets::table)
             .values((
                 totp_secrets::dsl::user_id.eq(user_id),
+                totp_secrets::dsl::name.eq(name),
                 totp_secrets::dsl::secret.eq(secret),
             ))
             .execute(conn)
-            .map(|_| ())
             .map_err(|e| {
                 ErrorType::DatabaseError("Failed to insert TOTP secret".to_string(), e).res_rollback()
+            })?;
+        select(last_insert_id()).get_result::<u64>(conn)
+            .map(|id| id as u32)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get last insert id".to_string(), e).res_rollback()
             })
     }
+    /// Gets a secret of the user from its id, confirmed or not.
+    /// - Throw `TOTPNotFound` if the secret does not exist or belongs to another user.
+    pub fn from_id(conn: &mut DBConn, user_id: &u32, id: &u32) -> Result<TOTPSecret, ErrorResponder> {
+        totp_secrets::table
+            .filter(totp_secrets::dsl::id.eq(id))
+            .filter(totp_secrets::dsl::user_id.eq(user_id))
+            .select(TOTPSecret::as_select())
+            .first::<TOTPSecret>(conn)
+            .optional()
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get TOTP secret".to_string(), e).res_rollback()
+            })?
+            .ok_or_else(|| ErrorType::TOTPNotFound.res())
+    }
     pub fn has_user_totp(conn: &mut DBConn, user_id: &u32) -> Result<bool, ErrorResponder> {
         totp_secrets::table
             .filter(totp_secrets::dsl::user_id.eq(user_id))
-            .select(totp_secrets::dsl::user_id)
+            .filter(totp_secrets::dsl::confirmed.eq(true))
+            .select(totp_secrets::dsl::id)
             .first::<u32>(conn)
             .optional()
             .map(|opt| opt.is_some())
@@ -248,30 +454,494 @@ impl TOTPSecret {
                 ErrorType::DatabaseError("Failed to check if user has TOTP".to_string(), e).res_rollback()
             })
     }
+    /// Gets all the confirmed secrets of the user.
     pub fn get_user_totp_secrets(conn: &mut DBConn, user_id: &u32) -> Result<Vec<TOTPSecret>, ErrorResponder> {
         totp_secrets::table
             .filter(totp_secrets::dsl::user_id.eq(user_id))
+            .filter(totp_secrets::dsl::confirmed.eq(true))
             .select(TOTPSecret::as_select())
             .load::<TOTPSecret>(conn)
             .map_err(|e| {
                 ErrorType::DatabaseError("Failed to get user TOTP secrets".to_string(), e).res_rollback()
             })
     }
-    pub fn check_user_totp(conn: &mut DBConn, user_id: &u32, code: &str) -> Result<bool, ErrorResponder> {
+    /// Checks the code against all the confirmed secrets of the user, and marks its time step as used.
+    pub fn check_user_totp(conn: &mut DBConn, user_id: &u32, email: &str, code: &str) -> Result<bool, ErrorResponder> {
         let secrets = TOTPSecret::get_user_totp_secrets(conn, user_id)?;
         for secret in secrets {
-            if secret.to_totp()?.check_current(code).map_err(|_| {
-                ErrorType::InternalError("SystemTimeError occurred when checking TOTP.".to_string()).res()
-            })? {
+            if secret.check_code_and_mark_as_used(conn, email, code)? {
                 return Ok(true);
             }
         }
         Ok(false)
     }
+    /// Checks the code at the current time, with a tolerance of `TOTP_SKEW_STEPS` steps, and stores
+    /// its time step so that the code (or an older one) cannot be reused.
+    pub fn check_code_and_mark_as_used(&self, conn: &mut DBConn, email: &str, code: &str) -> Result<bool, ErrorResponder> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map_err(|_| ErrorType::InternalError("SystemTimeError occurred when checking TOTP.".to_string()).res())?
+            .as_secs();
+        let step = self.check_code_at(email, code, now, get_totp_skew_steps())?;
+        if let Some(step) = step {
+            // The step condition is checked in the query to reject concurrent uses of the same code
+            let updated = update(totp_secrets::table)
+                .filter(totp_secrets::dsl::id.eq(self.id))
+                .filter(totp_secrets::dsl::last_used_step.is_null().or(totp_secrets::dsl::last_used_step.lt(step)))
+                .set(totp_secrets::dsl::last_used_step.eq(step))
+                .execute(conn)
+                .map_err(|e| {
+                    ErrorType::DatabaseError("Failed to update TOTP last used step".to_string(), e).res_rollback()
+                })?;
+            return Ok(updated == 1);
+        }
+        Ok(false)
+    }
+    /// Checks the code at the unix time `now`, accepting the codes of `skew` steps before and after.
+    /// Returns the time step of the matching code, if it is more recent than `last_used_step`.
+    pub fn check_code_at(&self, email: &str, code: &str, now: u64, skew: u8) -> Result<Option<u64>, ErrorResponder> {
+        let totp = self.to_totp(email)?;
+        let current_step = now / totp.step;
+        let first_step = current_step.saturating_sub(skew as u64);
+        let last_step = current_step + skew as u64;
 
-    fn to_totp(&self) -> Result<TOTP, ErrorResponder> {
-        let rf6238 = Rfc6238::new(6, self.secret.clone(), Some("Archypix".to_string()), "clementgre@archypix.com".to_string())
+        let mut matching_step = None;
+        for step in first_step..=last_step {
+            // Every step is checked to keep a constant time
+            if bool::from(totp.generate(step * totp.step).as_bytes().ct_eq(code.as_bytes())) {
+                matching_step = Some(step);
+            }
+        }
+        Ok(matching_step.filter(|step| self.last_used_step.map_or(true, |last| *step > last)))
+    }
+    pub fn mark_as_confirmed(&self, conn: &mut DBConn) -> Result<(), ErrorResponder> {
+        update(totp_secrets::table)
+            .filter(totp_secrets::dsl::id.eq(self.id))
+            .set(totp_secrets::dsl::confirmed.eq(true))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to confirm TOTP secret".to_string(), e).res_rollback()
+            })
+    }
+    pub fn delete(&self, conn: &mut DBConn) -> Result<(), ErrorResponder> {
+        delete(totp_secrets::table)
+            .filter(totp_secrets::dsl::id.eq(self.id))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to delete TOTP secret".to_string(), e).res_rollback()
+            })
+    }
+    /// Deletes the enrollments the user never confirmed.
+    pub fn clear_unconfirmed(conn: &mut DBConn, user_id: &u32) -> Result<(), ErrorResponder> {
+        delete(totp_secrets::table)
+            .filter(totp_secrets::dsl::user_id.eq(user_id))
+            .filter(totp_secrets::dsl::confirmed.eq(false))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to delete unconfirmed TOTP secrets".to_string(), e).res_rollback()
+            })
+    }
+
+    /// Builds the TOTP, with the user's email as the account name shown in authenticator apps.
+    pub fn to_totp(&self, email: &str) -> Result<TOTP, ErrorResponder> {
+        let rf6238 = Rfc6238::new(6, self.secret.clone(), Some("Archypix".to_string()), email.to_string())
             .map_err(|_| ErrorType::InternalError("Unable to create Rfc6238 (for TOTP)".to_string()).res())?;
         TOTP::from_rfc6238(rf6238).map_err(|_| ErrorType::InternalError("Unable to create TOTP".to_string()).res())
     }
 }
+
+/// Number of recovery codes generated for a user
+pub const RECOVERY_CODES_COUNT: usize = 10;
+
+/// Single-use code allowing a user to sign in when they lost their TOTP authenticator.
+/// Only the SHA-256 hash of the code is stored, codes being random enough (80 bits) not to require a slow hash.
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq)]
+#[diesel(primary_key(id))]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = recovery_codes)]
+pub struct RecoveryCode {
+    pub id: u32,
+    pub user_id: u32,
+    pub code_hash: Vec<u8>,
+    pub creation_date: NaiveDateTime,
+    pub used_date: Option<NaiveDateTime>,
+}
+
+impl RecoveryCode {
+    /// Replaces all the recovery codes of the user by new ones, returning the new codes in clear text.
+    pub fn regenerate_for_user(conn: &mut DBConn, user_id: &u32) -> Result<Vec<String>, ErrorResponder> {
+        delete(recovery_codes::table)
+            .filter(recovery_codes::dsl::user_id.eq(user_id))
+            .execute(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to delete existing recovery codes".to_string(), e).res_rollback()
+            })?;
+
+        let codes = (0..RECOVERY_CODES_COUNT).map(|_| {
+            let code = hex::encode(random_token(10));
+            format!("{}-{}-{}-{}", &code[0..5], &code[5..10], &code[10..15], &code[15..20])
+        }).collect::<Vec<String>>();
+
+        insert_into(recovery_codes::table)
+            .values(codes.iter().map(|code| (
+                recovery_codes::dsl::user_id.eq(user_id),
+                recovery_codes::dsl::code_hash.eq(RecoveryCode::hash_code(code)),
+            )).collect::<Vec<_>>())
+            .execute(conn)
+            .map(|_| codes)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to insert recovery codes".to_string(), e).res_rollback()
+            })
+    }
+    /// Counts the recovery codes of the user that have not been used yet.
+    pub fn count_unused(conn: &mut DBConn, user_id: &u32) -> Result<i64, ErrorResponder> {
+        recovery_codes::table
+            .filter(recovery_codes::dsl::user_id.eq(user_id))
+            .filter(recovery_codes::dsl::used_date.is_null())
+            .count()
+            .get_result::<i64>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to count recovery codes".to_string(), e).res_rollback()
+            })
+    }
+    /// Marks the recovery code as used, returning false if the code is invalid or already used.
+    pub fn check_code_and_mark_as_used(conn: &mut DBConn, user_id: &u32, code: &str) -> Result<bool, ErrorResponder> {
+        update(recovery_codes::table)
+            .filter(recovery_codes::dsl::user_id.eq(user_id))
+            .filter(recovery_codes::dsl::code_hash.eq(RecoveryCode::hash_code(code)))
+            .filter(recovery_codes::dsl::used_date.is_null())
+            .set(recovery_codes::dsl::used_date.eq(utc_timestamp()))
+            .execute(conn)
+            .map(|updated| updated == 1)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to mark recovery code as used".to_string(), e).res_rollback()
+            })
+    }
+    /// Hashes the code, ignoring case and separators.
+    fn hash_code(code: &str) -> Vec<u8> {
+        let normalized = code.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect::<String>();
+        Sha256::digest(normalized.as_bytes()).to_vec()
+    }
+}
+
+/// WebAuthn credential (passkey) of a user, usable as a second factor or for passwordless sign in.
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq)]
+#[diesel(primary_key(id))]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = webauthn_credentials)]
+pub struct WebauthnCredential {
+    pub id: u32,
+    pub user_id: u32,
+    pub name: String,
+    pub credential_id: Vec<u8>,
+    /// JSON serialized [`Passkey`], holding the public key and the signature counter
+    pub passkey: String,
+    pub creation_date: NaiveDateTime,
+    pub last_use_date: Option<NaiveDateTime>,
+}
+
+impl WebauthnCredential {
+    pub fn insert_for_user(conn: &mut DBConn, user_id: &u32, name: &str, passkey: &Passkey) -> Result<u32, ErrorResponder> {
+        insert_into(webauthn_credentials::table)
+            .values((
+                webauthn_credentials::dsl::user_id.eq(user_id),
+                webauthn_credentials::dsl::name.eq(name),
+                webauthn_credentials::dsl::credential_id.eq(passkey.cred_id().as_ref()),
+                webauthn_credentials::dsl::passkey.eq(WebauthnCredential::serialize_passkey(passkey)?),
+            ))
+            .execute(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to insert passkey".to_string(), e).res_rollback()
+            })
+            .and_then(|_| {
+                select(last_insert_id()).get_result::<u64>(conn)
+                    .map(|id| id as u32)
+                    .map_err(|e| {
+                        ErrorType::DatabaseError("Failed to get last insert id".to_string(), e).res_rollback()
+                    })
+            })
+    }
+    /// Gets the passkey of the user with the given id.
+    /// - Throw `PasskeyNotFound` if the user has no passkey with this id.
+    pub fn from_id(conn: &mut DBConn, user_id: &u32, id: &u32) -> Result<WebauthnCredential, ErrorResponder> {
+        webauthn_credentials::table
+            .filter(webauthn_credentials::dsl::user_id.eq(user_id))
+            .filter(webauthn_credentials::dsl::id.eq(id))
+            .select(WebauthnCredential::as_select())
+            .first::<WebauthnCredential>(conn)
+            .optional()
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get passkey".to_string(), e).res_rollback()
+            })?
+            .ok_or(ErrorType::PasskeyNotFound.res())
+    }
+    pub fn get_user_credentials(conn: &mut DBConn, user_id: &u32) -> Result<Vec<WebauthnCredential>, ErrorResponder> {
+        webauthn_credentials::table
+            .filter(webauthn_credentials::dsl::user_id.eq(user_id))
+            .order(webauthn_credentials::dsl::creation_date.asc())
+            .select(WebauthnCredential::as_select())
+            .load::<WebauthnCredential>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user passkeys".to_string(), e).res_rollback()
+            })
+    }
+    pub fn has_user_credentials(conn: &mut DBConn, user_id: &u32) -> Result<bool, ErrorResponder> {
+        select(exists(
+            webauthn_credentials::table.filter(webauthn_credentials::dsl::user_id.eq(user_id))
+        ))
+            .get_result::<bool>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to check user passkeys".to_string(), e).res_rollback()
+            })
+    }
+    /// Stores the passkey updated after an authentication (signature counter, backup state) and the last use date.
+    pub fn update_after_use(&self, conn: &mut DBConn, passkey: &Passkey) -> Result<(), ErrorResponder> {
+        update(webauthn_credentials::table)
+            .filter(webauthn_credentials::dsl::id.eq(self.id))
+            .set((
+                webauthn_credentials::dsl::passkey.eq(WebauthnCredential::serialize_passkey(passkey)?),
+                webauthn_credentials::dsl::last_use_date.eq(utc_timestamp()),
+            ))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to update passkey".to_string(), e).res_rollback()
+            })
+    }
+    pub fn delete(&self, conn: &mut DBConn) -> Result<(), ErrorResponder> {
+        delete(webauthn_credentials::table)
+            .filter(webauthn_credentials::dsl::id.eq(self.id))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to delete passkey".to_string(), e).res_rollback()
+            })
+    }
+
+    pub fn to_passkey(&self) -> Result<Passkey, ErrorResponder> {
+        serde_json::from_str(&self.passkey)
+            .map_err(|e| ErrorType::InternalError(format!("Unable to deserialize passkey: {}", e)).res())
+    }
+    pub fn credential_id(&self) -> CredentialID {
+        CredentialID::from(self.credential_id.clone())
+    }
+    fn serialize_passkey(passkey: &Passkey) -> Result<String, ErrorResponder> {
+        serde_json::to_string(passkey)
+            .map_err(|e| ErrorType::InternalError(format!("Unable to serialize passkey: {}", e)).res_rollback())
+    }
+}
+
+/// Permission granted to a personal API token.
+/// The discriminant is the position of the scope bit in the stored bit field: positions 0 and 2,
+/// used by the removed `ReadPictures` and `ManageTags` scopes, must not be reused.
+#[derive(JsonSchema, Serialize, Deserialize, EnumIter, Debug, Clone, Copy, PartialEq)]
+pub enum ApiScope {
+    Upload = 1,
+    /// Only available to admin users
+    Admin = 3,
+}
+
+impl ApiScope {
+    /// Bit field with all the scopes, granted to sessions.
+    pub const ALL: u8 = 0b1010;
+
+    pub fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+    pub fn to_bits(scopes: &[ApiScope]) -> u8 {
+        scopes.iter().fold(0, |bits, scope| bits | scope.bit())
+    }
+    pub fn from_bits(bits: u8) -> Vec<ApiScope> {
+        ApiScope::iter().filter(|scope| bits & scope.bit() != 0).collect()
+    }
+}
+
+/// Named, revocable token allowing scripts and integrations to use the API on behalf of a user.
+/// Sent in the X-API-Token header, the token is stored like auth tokens (prefix and keyed hash).
+#[derive(Queryable, Selectable, Identifiable, Debug, Clone, PartialEq)]
+#[diesel(primary_key(id))]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = api_tokens)]
+pub struct ApiToken {
+    pub id: u32,
+    pub user_id: u32,
+    pub name: String,
+    pub token_prefix: Vec<u8>,
+    pub token_hash: Vec<u8>,
+    /// Bit field of [`ApiScope`]
+    pub scopes: u8,
+    pub creation_date: NaiveDateTime,
+    pub expiration_date: Option<NaiveDateTime>,
+    pub last_use_date: Option<NaiveDateTime>,
+}
+
+impl ApiToken {
+    /// Creates a new API token for the user, returning its id and the token in clear text.
+    pub(crate) fn insert_for_user(conn: &mut DBConn, user_id: &u32, name: &str, scopes: u8, expiration_date: &Option<NaiveDateTime>, try_count: u8) -> Result<(u32, Vec<u8>), ErrorResponder> {
+        let api_token = random_token(32);
+
+        insert_into(api_tokens::table)
+            .values((
+                api_tokens::dsl::user_id.eq(user_id),
+                api_tokens::dsl::name.eq(name),
+                api_tokens::dsl::token_prefix.eq(&api_token[..AUTH_TOKEN_PREFIX_LENGTH]),
+                api_tokens::dsl::token_hash.eq(hash_token(&api_token)),
+                api_tokens::dsl::scopes.eq(scopes),
+                api_tokens::dsl::expiration_date.eq(expiration_date),
+            ))
+            .execute(conn)
+            .and_then(|_| select(last_insert_id()).get_result::<u64>(conn))
+            .map(|id| (id as u32, api_token))
+            .or_else(|e| {
+                if is_error_duplicate_key(&e, "api_tokens.UQ_api_tokens_prefix") && try_count < 4 {
+                    warn!(user_id, try_count, "API token prefix already exists, trying again");
+                    return ApiToken::insert_for_user(conn, user_id, name, scopes, expiration_date, try_count + 1);
+                }
+                ErrorType::DatabaseError("Failed to insert API token".to_string(), e).res_err_rollback()
+            })
+    }
+    /// Finds the API token matching the clear text token.
+    /// The token is looked up by its prefix, then its hash is compared in constant time.
+    pub fn find_from_token_opt(conn: &mut DBConn, api_token: &[u8]) -> Result<Option<ApiToken>, ErrorResponder> {
+        if api_token.len() < AUTH_TOKEN_PREFIX_LENGTH {
+            return Ok(None);
+        }
+        let candidate = api_tokens::table
+            .filter(api_tokens::dsl::token_prefix.eq(&api_token[..AUTH_TOKEN_PREFIX_LENGTH]))
+            .select(ApiToken::as_select())
+            .first::<ApiToken>(conn)
+            .optional()
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get API token".to_string(), e).res_rollback()
+            })?;
+        Ok(candidate.filter(|candidate| constant_time_eq(&candidate.token_hash, &hash_token(api_token))))
+    }
+    /// Gets the API token of the user with the given id.
+    /// - Throw `ApiTokenNotFound` if the user has no API token with this id.
+    pub fn from_id(conn: &mut DBConn, user_id: &u32, id: &u32) -> Result<ApiToken, ErrorResponder> {
+        api_tokens::table
+            .filter(api_tokens::dsl::user_id.eq(user_id))
+            .filter(api_tokens::dsl::id.eq(id))
+            .select(ApiToken::as_select())
+            .first::<ApiToken>(conn)
+            .optional()
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get API token".to_string(), e).res_rollback()
+            })?
+            .ok_or(ErrorType::ApiTokenNotFound.res())
+    }
+    pub fn get_user_api_tokens(conn: &mut DBConn, user_id: &u32) -> Result<Vec<ApiToken>, ErrorResponder> {
+        api_tokens::table
+            .filter(api_tokens::dsl::user_id.eq(user_id))
+            .order(api_tokens::dsl::creation_date.desc())
+            .select(ApiToken::as_select())
+            .load::<ApiToken>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user API tokens".to_string(), e).res_rollback()
+            })
+    }
+    pub fn get_api_token_from_headers(request: &Request<'_>) -> Option<Vec<u8>> {
+        request.headers().get_one("X-API-Token").map(|s| hex::decode(s).ok()).flatten()
+    }
+    pub fn is_expired(&self) -> bool {
+        self.expiration_date.is_some_and(|date| date < Utc::now().naive_utc())
+    }
+    pub fn update_last_use_date(&self, conn: &mut DBConn) -> Result<(), ErrorResponder> {
+        let current_naive = Utc::now().naive_utc();
+        if self.last_use_date.map_or(true, |date| current_naive - date > TimeDelta::try_minutes(10).unwrap()) {
+            update(api_tokens::table)
+                .filter(api_tokens::dsl::id.eq(self.id))
+                .set(api_tokens::dsl::last_use_date.eq(utc_timestamp()))
+                .execute(conn).map_err(|e| {
+                ErrorType::DatabaseError("Failed to update API token use date".to_string(), e).res()
+            })?;
+        }
+        Ok(())
+    }
+    pub fn delete(&self, conn: &mut DBConn) -> Result<(), ErrorResponder> {
+        delete(api_tokens::table)
+            .filter(api_tokens::dsl::id.eq(self.id))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to delete API token".to_string(), e).res_rollback()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMAIL: &str = "user@example.com";
+    /// Fixed clock, in the middle of a 30 seconds step
+    const NOW: u64 = 1_700_000_015;
+
+    fn totp_secret(last_used_step: Option<u64>) -> TOTPSecret {
+        TOTPSecret {
+            id: 1,
+            user_id: 1,
+            name: "Phone".to_string(),
+            creation_date: NaiveDateTime::default(),
+            secret: b"12345678901234567890".to_vec(),
+            confirmed: true,
+            last_used_step,
+        }
+    }
+
+    fn code_at_step(secret: &TOTPSecret, step: u64) -> String {
+        let totp = secret.to_totp(EMAIL).unwrap();
+        totp.generate(step * totp.step)
+    }
+
+    #[test]
+    fn accepts_the_current_code() {
+        let secret = totp_secret(None);
+        let step = NOW / 30;
+        let code = code_at_step(&secret, step);
+        assert_eq!(secret.check_code_at(EMAIL, &code, NOW, 0).unwrap(), Some(step));
+    }
+
+    #[test]
+    fn rejects_a_replayed_code() {
+        let mut secret = totp_secret(None);
+        let step = NOW / 30;
+        let code = code_at_step(&secret, step);
+        assert_eq!(secret.check_code_at(EMAIL, &code, NOW, 1).unwrap(), Some(step));
+
+        secret.last_used_step = Some(step);
+        assert_eq!(secret.check_code_at(EMAIL, &code, NOW, 1).unwrap(), None);
+        assert_eq!(secret.check_code_at(EMAIL, &code, NOW + 30, 1).unwrap(), None);
+        let previous_code = code_at_step(&secret, step - 1);
+        assert_eq!(secret.check_code_at(EMAIL, &previous_code, NOW, 1).unwrap(), None);
+    }
+
+    #[test]
+    fn accepts_codes_within_the_skew_window() {
+        let secret = totp_secret(None);
+        let current_step = NOW / 30;
+        for skew in 0..=3u8 {
+            for step in current_step - skew as u64..=current_step + skew as u64 {
+                let code = code_at_step(&secret, step);
+                assert_eq!(secret.check_code_at(EMAIL, &code, NOW, skew).unwrap(), Some(step), "skew {}, step {}", skew, step);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_codes_outside_the_skew_window() {
+        let secret = totp_secret(None);
+        let current_step = NOW / 30;
+        for skew in 0..=3u8 {
+            for step in [current_step - skew as u64 - 1, current_step + skew as u64 + 1] {
+                let code = code_at_step(&secret, step);
+                assert_eq!(secret.check_code_at(EMAIL, &code, NOW, skew).unwrap(), None, "skew {}, step {}", skew, step);
+            }
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use diesel::mysql::MysqlConnection;
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
-use std::env;
+use crate::utils::config::config;
 
 pub type DBPool = Pool<ConnectionManager<MysqlConnection>>;
 pub type DBConn = PooledConnection<ConnectionManager<MysqlConnection>>;
@@ -23,5 +23,5 @@ pub fn get_connection_pool() -> Pool<ConnectionManager<MysqlConnection>> {
 }
 
 pub fn get_database_url() -> String {
-    env::var("DATABASE_URL").expect("DATABASE_URL must be set")
+    config().database_url.clone()
 }
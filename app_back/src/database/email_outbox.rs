@@ -0,0 +1,196 @@
+use crate::database::database::DBConn;
+use crate::database::schema::*;
+use crate::utils::errors_catcher::{err_transaction, ErrorResponder, ErrorType};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{delete, insert_into, update, BoolExpressionMethods, ExpressionMethods, Identifiable, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper};
+
+/// Number of sending attempts after which an email is marked as failed (dead letter).
+pub const EMAIL_MAX_ATTEMPTS: u32 = 10;
+/// Delay before the first retry, doubled at each attempt.
+const EMAIL_RETRY_BASE_SECONDS: i64 = 30;
+/// Maximum delay between two attempts.
+const EMAIL_RETRY_MAX_SECONDS: i64 = 60 * 60;
+/// Time given to an outbox worker to send the emails it claimed, after which they can be claimed again.
+const EMAIL_CLAIM_SECONDS: i64 = 5 * 60;
+
+/// Email waiting to be sent, sent, or failed, persisted so that no email is lost on SMTP errors or restarts.
+/// Emails are inserted in the transaction of the request, and sent by the outbox worker (see [`crate::mailing::mailer`]).
+/// As the bodies contain links and codes, they are removed once the email is sent or expired.
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq)]
+#[diesel(primary_key(id))]
+#[diesel(table_name = email_outbox)]
+pub struct OutboxEmail {
+    pub id: u64,
+    pub user_id: u32,
+    pub to_name: String,
+    pub to_email: String,
+    pub subject: String,
+    pub body_text: String,
+    pub body_html: String,
+    pub status: EmailStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub creation_date: NaiveDateTime,
+    pub next_attempt_date: NaiveDateTime,
+    /// Date after which the links and codes of the email are not valid anymore, the email is then not sent
+    pub expiration_date: Option<NaiveDateTime>,
+    pub sent_date: Option<NaiveDateTime>,
+}
+
+impl OutboxEmail {
+    /// Queues an email to be sent to a user, before `expiration_date` if any.
+    pub fn insert(conn: &mut DBConn, user_id: &u32, to: &(String, String), subject: &str, body_text: &str, body_html: &str, expiration_date: Option<NaiveDateTime>) -> Result<(), ErrorResponder> {
+        insert_into(email_outbox::table)
+            .values((
+                email_outbox::dsl::user_id.eq(user_id),
+                email_outbox::dsl::to_name.eq(&to.0),
+                email_outbox::dsl::to_email.eq(&to.1),
+                email_outbox::dsl::subject.eq(subject),
+                email_outbox::dsl::body_text.eq(body_text),
+                email_outbox::dsl::body_html.eq(body_html),
+                email_outbox::dsl::expiration_date.eq(expiration_date),
+            ))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to queue email".to_string(), e).res_rollback()
+            })
+    }
+
+    /// Claims the pending emails whose next attempt is due (and the claimed emails whose claim timed out), the oldest first.
+    /// The emails are locked while being claimed, skipping the ones locked by other workers,
+    /// so that each email is only sent by one worker. They must be sent within [`EMAIL_CLAIM_SECONDS`].
+    pub fn claim_due(conn: &mut DBConn, limit: i64) -> Result<Vec<OutboxEmail>, ErrorResponder> {
+        let now = Utc::now().naive_utc();
+        err_transaction(conn, |conn| {
+            let emails = email_outbox::table
+                .filter(email_outbox::dsl::status.eq_any([EmailStatus::Pending, EmailStatus::Sending]))
+                .filter(email_outbox::dsl::next_attempt_date.le(now))
+                .filter(email_outbox::dsl::expiration_date.is_null().or(email_outbox::dsl::expiration_date.gt(now)))
+                .order(email_outbox::dsl::id.asc())
+                .limit(limit)
+                .select(OutboxEmail::as_select())
+                .for_update()
+                .skip_locked()
+                .load::<OutboxEmail>(conn)
+                .map_err(|e| {
+                    ErrorType::DatabaseError("Failed to get due emails".to_string(), e).res_rollback()
+                })?;
+            update(email_outbox::table)
+                .filter(email_outbox::dsl::id.eq_any(emails.iter().map(|email| email.id).collect::<Vec<u64>>()))
+                .set((
+                    email_outbox::dsl::status.eq(EmailStatus::Sending),
+                    email_outbox::dsl::next_attempt_date.eq(now + Duration::seconds(EMAIL_CLAIM_SECONDS)),
+                ))
+                .execute(conn)
+                .map_err(|e| {
+                    ErrorType::DatabaseError("Failed to claim due emails".to_string(), e).res_rollback()
+                })?;
+            Ok(emails)
+        })
+    }
+
+    /// Gets the emails whose sending failed for good, the most recent first.
+    pub fn get_failed(conn: &mut DBConn, offset: i64, limit: i64) -> Result<Vec<OutboxEmail>, ErrorResponder> {
+        email_outbox::table
+            .filter(email_outbox::dsl::status.eq(EmailStatus::Failed))
+            .order(email_outbox::dsl::id.desc())
+            .offset(offset)
+            .limit(limit)
+            .select(OutboxEmail::as_select())
+            .load::<OutboxEmail>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get failed emails".to_string(), e).res_rollback()
+            })
+    }
+
+    /// Marks the email as sent, removing its content.
+    pub fn mark_as_sent(&self, conn: &mut DBConn) -> Result<(), ErrorResponder> {
+        update(email_outbox::table)
+            .filter(email_outbox::dsl::id.eq(self.id))
+            .set((
+                email_outbox::dsl::status.eq(EmailStatus::Sent),
+                email_outbox::dsl::sent_date.eq(utc_timestamp()),
+                email_outbox::dsl::body_text.eq(""),
+                email_outbox::dsl::body_html.eq(""),
+            ))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to mark email as sent".to_string(), e).res_rollback()
+            })
+    }
+
+    /// Records a failed sending attempt, scheduling the next one with an exponential backoff,
+    /// or marking the email as failed after [`EMAIL_MAX_ATTEMPTS`] attempts.
+    pub fn mark_attempt_failed(&self, conn: &mut DBConn, error: &str) -> Result<(), ErrorResponder> {
+        let attempts = self.attempts + 1;
+        let status = if attempts >= EMAIL_MAX_ATTEMPTS { EmailStatus::Failed } else { EmailStatus::Pending };
+        let delay = EMAIL_RETRY_BASE_SECONDS.saturating_mul(1 << (attempts - 1).min(20)).min(EMAIL_RETRY_MAX_SECONDS);
+        let error: String = error.chars().take(1024).collect();
+
+        update(email_outbox::table)
+            .filter(email_outbox::dsl::id.eq(self.id))
+            .set((
+                email_outbox::dsl::status.eq(status),
+                email_outbox::dsl::attempts.eq(attempts),
+                email_outbox::dsl::last_error.eq(error),
+                email_outbox::dsl::next_attempt_date.eq(Utc::now().naive_utc() + Duration::seconds(delay)),
+            ))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to record email sending failure".to_string(), e).res_rollback()
+            })
+    }
+
+    /// Puts back a failed email in the queue, with a new set of attempts.
+    /// - Throw `EmailNotFound` if there is no failed and unexpired email with this id.
+    pub fn retry_failed(conn: &mut DBConn, email_id: &u64) -> Result<(), ErrorResponder> {
+        let count = update(email_outbox::table)
+            .filter(email_outbox::dsl::id.eq(email_id))
+            .filter(email_outbox::dsl::status.eq(EmailStatus::Failed))
+            .filter(email_outbox::dsl::expiration_date.is_null().or(email_outbox::dsl::expiration_date.gt(Utc::now().naive_utc())))
+            .set((
+                email_outbox::dsl::status.eq(EmailStatus::Pending),
+                email_outbox::dsl::attempts.eq(0),
+                email_outbox::dsl::next_attempt_date.eq(utc_timestamp()),
+            ))
+            .execute(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to retry email".to_string(), e).res_rollback()
+            })?;
+        if count == 0 {
+            return ErrorType::EmailNotFound.res_err();
+        }
+        Ok(())
+    }
+
+    /// Marks the unsent emails (pending, claimed or failed) past their expiration date as expired, removing their content.
+    /// Returns the number of expired emails.
+    pub fn expire(conn: &mut DBConn) -> Result<usize, ErrorResponder> {
+        update(email_outbox::table)
+            .filter(email_outbox::dsl::status.eq_any([EmailStatus::Pending, EmailStatus::Sending, EmailStatus::Failed]))
+            .filter(email_outbox::dsl::expiration_date.le(Utc::now().naive_utc()))
+            .set((
+                email_outbox::dsl::status.eq(EmailStatus::Expired),
+                email_outbox::dsl::body_text.eq(""),
+                email_outbox::dsl::body_html.eq(""),
+            ))
+            .execute(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to expire emails".to_string(), e).res_rollback()
+            })
+    }
+
+    /// Deletes the emails sent or expired before the given date, returning the number of deleted emails.
+    pub fn delete_sent_before(conn: &mut DBConn, date: &NaiveDateTime) -> Result<usize, ErrorResponder> {
+        delete(email_outbox::table
+            .filter(email_outbox::dsl::status.eq(EmailStatus::Sent).and(email_outbox::dsl::sent_date.lt(date))
+                .or(email_outbox::dsl::status.eq(EmailStatus::Expired).and(email_outbox::dsl::expiration_date.lt(date)))))
+            .execute(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to delete sent emails".to_string(), e).res_rollback()
+            })
+    }
+}
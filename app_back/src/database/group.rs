@@ -1,6 +1,8 @@
+use crate::database::database::DBConn;
 use crate::database::schema::*;
 use crate::database::{picture::Picture, user::User};
-use diesel::{Associations, Identifiable, Queryable, Selectable};
+use crate::utils::errors_catcher::{ErrorResponder, ErrorType};
+use diesel::{update, Associations, ExpressionMethods, Identifiable, OptionalExtension, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper};
 
 #[derive(Queryable, Selectable, Identifiable, Associations, Debug, PartialEq)]
 #[diesel(primary_key(id))]
@@ -53,8 +55,9 @@ pub struct LinkShareGroups {
 pub struct SharedGroup {
     pub user_id: u32,
     pub group_id: u32,
-    pub permissions: Vec<u8>,
+    pub permissions: u8,
     pub match_conversion_group_id: Option<u32>,
+    /// If true, the pictures of the group are copied into the recipient's library when the share is accepted.
     pub copied: bool,
     pub confirmed: bool,
 }
@@ -63,6 +66,64 @@ impl Arrangement {}
 
 impl Group {}
 
-impl GroupPicture {}
+impl GroupPicture {
+    /// Gets all non-deleted pictures of a group
+    pub fn get_group_pictures(conn: &mut DBConn, group_id: &u32) -> Result<Vec<Picture>, ErrorResponder> {
+        groups_pictures::table
+            .inner_join(pictures::table)
+            .filter(groups_pictures::dsl::group_id.eq(group_id))
+            .filter(pictures::dsl::deleted_date.is_null())
+            .select(Picture::as_select())
+            .load::<Picture>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get group pictures".to_string(), e).res_rollback()
+            })
+    }
+}
+
+impl SharedGroup {
+    pub fn from_ids(conn: &mut DBConn, user_id: &u32, group_id: &u32) -> Result<SharedGroup, ErrorResponder> {
+        shared_groups::table
+            .filter(shared_groups::dsl::user_id.eq(user_id))
+            .filter(shared_groups::dsl::group_id.eq(group_id))
+            .select(SharedGroup::as_select())
+            .first::<SharedGroup>(conn)
+            .optional()
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get shared group".to_string(), e).res_rollback()
+            })?
+            .ok_or_else(|| ErrorType::ShareNotFound.res())
+    }
 
-impl SharedGroup {}
+    /// Accepts the share. If `copy` is true, every picture of the group is materialized as an owned copy
+    /// in the recipient's library, so that the pictures survive the revocation of the share.
+    /// Returns the ids of the created copies.
+    /// - Throw `ShareAlreadyAccepted` if the share is already confirmed.
+    /// - Throw `StorageLimitExceeded` if the copies do not fit in the recipient's quota.
+    pub fn accept(&self, conn: &mut DBConn, copy: bool) -> Result<Vec<u64>, ErrorResponder> {
+        if self.confirmed {
+            return ErrorType::ShareAlreadyAccepted.res_err();
+        }
+        update(shared_groups::table)
+            .filter(shared_groups::dsl::user_id.eq(self.user_id))
+            .filter(shared_groups::dsl::group_id.eq(self.group_id))
+            .set((
+                shared_groups::dsl::confirmed.eq(true),
+                shared_groups::dsl::copied.eq(copy),
+            ))
+            .execute(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to accept shared group".to_string(), e).res_rollback()
+            })?;
+
+        let mut copies = Vec::new();
+        if copy {
+            for picture in GroupPicture::get_group_pictures(conn, &self.group_id)? {
+                if let Some(copy_id) = picture.copy_to_user(conn, &self.user_id)? {
+                    copies.push(copy_id);
+                }
+            }
+        }
+        Ok(copies)
+    }
+}
@@ -1,10 +1,17 @@
 use bigdecimal::BigDecimal;
 use chrono::NaiveDateTime;
-use diesel::{Associations, Identifiable, Queryable, Selectable};
+use diesel::{delete, insert_into, select, update, Associations, ExpressionMethods, Identifiable, NullableExpressionMethods, OptionalExtension, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper};
 
+use crate::database::database::DBConn;
 use crate::database::schema::PictureOrientation;
 use crate::database::schema::*;
 use crate::database::user::User;
+use crate::utils::config::config;
+use crate::utils::errors_catcher::{ErrorResponder, ErrorType};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tracing::warn;
 
 #[derive(Queryable, Selectable, Identifiable, Associations, Debug, PartialEq)]
 #[diesel(primary_key(id))]
@@ -13,9 +20,10 @@ use crate::database::user::User;
 pub struct Picture {
     pub id: u64,
     pub name: String,
-    pub comment: String,
+    pub comment: Option<String>,
     pub owner_id: u32,
     pub author_id: u32,
+    pub blob_id: Option<u64>,
     pub deleted_date: Option<NaiveDateTime>,
     pub copied: bool,
     pub creation_date: NaiveDateTime,
@@ -24,7 +32,7 @@ pub struct Picture {
     pub latitude: Option<BigDecimal>,
     /// 6 decimals, maximum 1000.000000°
     pub longitude: Option<BigDecimal>,
-    pub altitude: Option<u16>,
+    pub altitude: Option<i16>,
     pub orientation: PictureOrientation,
     pub width: u16,
     pub height: u16,
@@ -39,7 +47,228 @@ pub struct Picture {
     pub f_number: Option<BigDecimal>,
 }
 
-impl Picture {}
+impl Picture {
+    pub fn from_id(conn: &mut DBConn, id: &u64) -> Result<Picture, ErrorResponder> {
+        pictures::table
+            .filter(pictures::dsl::id.eq(id))
+            .select(Picture::as_select())
+            .first::<Picture>(conn)
+            .optional()
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get picture from id".to_string(), e).res_rollback()
+            })?
+            .ok_or_else(|| ErrorType::PictureNotFound.res())
+    }
+
+    /// Gets the ids of the pictures moved to the trash before `date`.
+    pub fn get_trashed_before(conn: &mut DBConn, date: &NaiveDateTime) -> Result<Vec<u64>, ErrorResponder> {
+        pictures::table
+            .filter(pictures::dsl::deleted_date.le(date))
+            .select(pictures::dsl::id)
+            .load::<u64>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get trashed pictures".to_string(), e).res_rollback()
+            })
+    }
+
+    /// Size charged on the storage quota of the owner of a picture: the size of its blob, or for the pictures not backed
+    /// by a blob (created before the blobs), whose stored size is unknown, an estimation from their dimensions
+    /// at 4 bits per pixel (a high quality JPEG).
+    pub fn charged_size_ko(blob_size_ko: Option<u64>, width: u16, height: u16) -> u64 {
+        blob_size_ko.unwrap_or_else(|| (width as u64 * height as u64 / 2).div_ceil(1000))
+    }
+
+    /// Permanently deletes pictures and their relations, releasing their blobs and the storage used by their owners.
+    /// Returns the blobs that are not used anymore, whose files must be deleted once the transaction is committed
+    /// (see [`Blob::delete_files`]).
+    /// Must be called inside a transaction.
+    pub fn delete_permanently(conn: &mut DBConn, picture_ids: &[u64]) -> Result<Vec<Blob>, ErrorResponder> {
+        let pictures: Vec<(u32, Option<u64>, u64)> = pictures::table
+            .left_join(blobs::table)
+            .filter(pictures::dsl::id.eq_any(picture_ids))
+            .select((pictures::dsl::owner_id, blobs::dsl::id.nullable(), blobs::dsl::size_ko.nullable(), pictures::dsl::width, pictures::dsl::height))
+            .load::<(u32, Option<u64>, Option<u64>, u16, u16)>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get pictures blobs".to_string(), e).res_rollback()
+            })?
+            .into_iter()
+            .map(|(owner_id, blob_id, size_ko, width, height)| (owner_id, blob_id, Picture::charged_size_ko(size_ko, width, height)))
+            .collect();
+
+        delete(ratings::table.filter(ratings::dsl::picture_id.eq_any(picture_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete pictures ratings".to_string(), e).res_rollback())?;
+        delete(pictures_tags::table.filter(pictures_tags::dsl::picture_id.eq_any(picture_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete pictures tags".to_string(), e).res_rollback())?;
+        delete(duplicates::table.filter(duplicates::dsl::picture_id.eq_any(picture_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete pictures duplicates".to_string(), e).res_rollback())?;
+        delete(groups_pictures::table.filter(groups_pictures::dsl::picture_id.eq_any(picture_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete groups pictures".to_string(), e).res_rollback())?;
+        delete(pictures::table.filter(pictures::dsl::id.eq_any(picture_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete pictures".to_string(), e).res_rollback())?;
+
+        for (owner_id, _, size_ko) in &pictures {
+            User::remove_storage_count(conn, owner_id, *size_ko)?;
+        }
+        Blob::release(conn, &pictures.iter().filter_map(|(_, blob_id, _)| *blob_id).collect::<Vec<u64>>())
+    }
+
+    /// Creates an owned copy of this picture in the library of `user_id`.
+    /// The copy keeps the original author, shares the same underlying blob, and counts against
+    /// the storage quota of its new owner (see [`Picture::charged_size_ko`]).
+    /// Returns `None` if the user already owns a picture backed by the same blob, or for a picture not backed by a blob,
+    /// a copy with the same author, name and creation date.
+    /// - Throw `StorageLimitExceeded` if the copy does not fit in the user's quota.
+    pub fn copy_to_user(&self, conn: &mut DBConn, user_id: &u32) -> Result<Option<u64>, ErrorResponder> {
+        let mut existing_copy = pictures::table
+            .filter(pictures::dsl::owner_id.eq(user_id))
+            .filter(pictures::dsl::deleted_date.is_null())
+            .into_boxed();
+        existing_copy = match self.blob_id {
+            Some(blob_id) => existing_copy.filter(pictures::dsl::blob_id.eq(blob_id)),
+            None => existing_copy
+                .filter(pictures::dsl::blob_id.is_null())
+                .filter(pictures::dsl::author_id.eq(self.author_id))
+                .filter(pictures::dsl::name.eq(&self.name))
+                .filter(pictures::dsl::creation_date.eq(self.creation_date)),
+        };
+        let already_owned = existing_copy
+            .select(pictures::dsl::id)
+            .first::<u64>(conn)
+            .optional()
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to check for an existing copy of the picture".to_string(), e).res_rollback()
+            })?;
+        if already_owned.is_some() {
+            return Ok(None);
+        }
+        let size_ko = match self.blob_id {
+            Some(blob_id) => {
+                let blob = Blob::from_id(conn, &blob_id)?;
+                blob.increment_ref_count(conn)?;
+                blob.size_ko
+            }
+            None => Picture::charged_size_ko(None, self.width, self.height),
+        };
+        User::add_storage_count(conn, user_id, size_ko)?;
+
+        insert_into(pictures::table)
+            .values((
+                pictures::dsl::name.eq(&self.name),
+                pictures::dsl::comment.eq(&self.comment),
+                pictures::dsl::owner_id.eq(user_id),
+                pictures::dsl::author_id.eq(&self.author_id),
+                pictures::dsl::blob_id.eq(self.blob_id),
+                pictures::dsl::copied.eq(true),
+                pictures::dsl::creation_date.eq(&self.creation_date),
+                pictures::dsl::edition_date.eq(&self.edition_date),
+                pictures::dsl::latitude.eq(&self.latitude),
+                pictures::dsl::longitude.eq(&self.longitude),
+                pictures::dsl::altitude.eq(&self.altitude),
+                pictures::dsl::orientation.eq(&self.orientation),
+                pictures::dsl::width.eq(&self.width),
+                pictures::dsl::height.eq(&self.height),
+                pictures::dsl::camera_brand.eq(&self.camera_brand),
+                pictures::dsl::camera_model.eq(&self.camera_model),
+                pictures::dsl::focal_length.eq(&self.focal_length),
+                pictures::dsl::exposure_time_num.eq(&self.exposure_time_num),
+                pictures::dsl::exposure_time_den.eq(&self.exposure_time_den),
+                pictures::dsl::iso_speed.eq(&self.iso_speed),
+                pictures::dsl::f_number.eq(&self.f_number),
+            ))
+            .execute(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to insert picture copy".to_string(), e).res_rollback()
+            })?;
+
+        select(last_insert_id()).get_result::<u64>(conn)
+            .map(Some)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get last insert id".to_string(), e).res_rollback()
+            })
+    }
+}
+
+/// Stored file content, deduplicated by hash and shared between pictures (e.g. copies of a shared picture).
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq)]
+#[diesel(primary_key(id))]
+#[diesel(table_name = blobs)]
+pub struct Blob {
+    pub id: u64,
+    pub hash: Vec<u8>,
+    pub size_ko: u64,
+    /// Number of pictures using this blob, the blob can be removed from storage when it reaches 0.
+    pub ref_count: u32,
+}
+
+impl Blob {
+    pub fn from_id(conn: &mut DBConn, id: &u64) -> Result<Blob, ErrorResponder> {
+        blobs::table
+            .filter(blobs::dsl::id.eq(id))
+            .select(Blob::as_select())
+            .first::<Blob>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get blob from id".to_string(), e).res_rollback()
+            })
+    }
+    pub fn increment_ref_count(&self, conn: &mut DBConn) -> Result<(), ErrorResponder> {
+        update(blobs::table)
+            .filter(blobs::dsl::id.eq(self.id))
+            .set(blobs::dsl::ref_count.eq(blobs::dsl::ref_count + 1))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to increment blob reference count".to_string(), e).res_rollback()
+            })
+    }
+    fn decrement_ref_count(conn: &mut DBConn, id: &u64) -> Result<(), ErrorResponder> {
+        update(blobs::table)
+            .filter(blobs::dsl::id.eq(id))
+            .filter(blobs::dsl::ref_count.gt(0))
+            .set(blobs::dsl::ref_count.eq(blobs::dsl::ref_count - 1))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to decrement blob reference count".to_string(), e).res_rollback()
+            })
+    }
+    /// Releases a reference of each blob (once per occurrence of its id), and deletes the released blobs
+    /// that are not used by any picture anymore.
+    /// Returns the deleted blobs, whose files must be deleted once the transaction is committed (see [`Blob::delete_files`]).
+    pub fn release(conn: &mut DBConn, blob_ids: &[u64]) -> Result<Vec<Blob>, ErrorResponder> {
+        for blob_id in blob_ids {
+            Blob::decrement_ref_count(conn, blob_id)?;
+        }
+        let unreferenced = blobs::table
+            .filter(blobs::dsl::id.eq_any(blob_ids))
+            .filter(blobs::dsl::ref_count.eq(0))
+            .select(Blob::as_select())
+            .load::<Blob>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get unreferenced blobs".to_string(), e).res_rollback()
+            })?;
+        delete(blobs::table.filter(blobs::dsl::id.eq_any(unreferenced.iter().map(|blob| blob.id).collect::<Vec<u64>>())))
+            .execute(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to delete unreferenced blobs".to_string(), e).res_rollback()
+            })?;
+        Ok(unreferenced)
+    }
+    /// Path of the file of the blob in the storage, named after its hash.
+    pub fn file_path(&self) -> PathBuf {
+        config().storage_path.join("blobs").join(hex::encode(&self.hash))
+    }
+    /// Deletes the files of deleted blobs from the storage, ignoring the files already missing.
+    pub fn delete_files(blobs: &[Blob]) {
+        for blob in blobs {
+            let path = blob.file_path();
+            if let Err(e) = fs::remove_file(&path) {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!(blob_id = blob.id, path = %path.display(), error = %e, "Unable to delete blob file");
+                }
+            }
+        }
+    }
+}
 
 
 #[derive(Queryable, Selectable, Identifiable, Associations, Debug, PartialEq)]
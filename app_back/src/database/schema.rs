@@ -3,22 +3,60 @@ use diesel::{allow_tables_to_appear_in_same_query, joinable, table};
 use diesel_derives::define_sql_function;
 use rocket_okapi::JsonSchema;
 use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
 
 define_sql_function! { fn last_insert_id() -> Unsigned<Bigint> }
 define_sql_function! { fn inet6_ntoa(ip: Nullable<Binary>) -> Nullable<VarChar> }
 define_sql_function! { fn inet6_aton(ip: Nullable<VarChar>) -> Nullable<Varbinary> }
 define_sql_function! { fn utc_timestamp() -> Datetime }
 
-#[derive(JsonSchema, Debug, PartialEq, Serialize, diesel_derive_enum::DbEnum)]
+#[derive(JsonSchema, Debug, Clone, PartialEq, Serialize, diesel_derive_enum::DbEnum)]
 pub enum UserStatus {
     Unconfirmed,
     Normal,
     Banned,
     Admin,
 }
+/// Language of the emails sent to a user, English being the fallback.
+#[derive(JsonSchema, Debug, Clone, Copy, PartialEq, Default, diesel_derive_enum::DbEnum, Deserialize, Serialize, EnumIter)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Fr,
+}
+impl Locale {
+    /// Language code, also used as directory of the email templates
+    pub fn code(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Fr => "fr",
+        }
+    }
+    /// Gets the preferred supported locale from an `Accept-Language` header value (e.g. `fr-FR,fr;q=0.9,en;q=0.8`).
+    pub fn from_accept_language(header: &str) -> Option<Locale> {
+        let mut languages: Vec<(&str, f32)> = header.split(',')
+            .filter_map(|language| {
+                let mut parts = language.trim().split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+                Some((tag, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+        languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+        languages.into_iter().find_map(|(tag, _)| {
+            let primary = tag.split('-').next().unwrap_or_default().to_lowercase();
+            Locale::iter().find(|locale| locale.code() == primary)
+        })
+    }
+}
 table! {
     use diesel::sql_types::*;
-    use super::UserStatusMapping;
+    use super::{LocaleMapping, UserStatusMapping};
     users (id) {
         id -> Unsigned<Integer>,
         name -> Varchar,
@@ -30,17 +68,26 @@ table! {
         tfa_login -> Bool,
         storage_count_ko -> Unsigned<BigInt>,
         storage_limit_mo -> Unsigned<Integer>,
+        deletion_date -> Nullable<Datetime>,
+        pending_email -> Nullable<Varchar>,
+        locale -> LocaleMapping,
     }
 }
 
 table! {
-    auth_tokens (user_id, token) {
+    auth_tokens (id) {
+        id -> Unsigned<Integer>,
         user_id -> Unsigned<Integer>,
-        token -> Binary,
+        // 8 byte, first bytes of the token, used for lookup
+        token_prefix -> Binary,
+        // 32 byte, keyed hash of the token
+        token_hash -> Binary,
         creation_date -> Datetime,
         last_use_date -> Datetime,
         device_string -> Nullable<Varchar>,
         ip_address -> Nullable<Varbinary>,
+        // 32 byte, keyed hash of the token of the new sign-in email revoke link
+        revoke_token_hash -> Nullable<Binary>,
     }
 }
 joinable!(auth_tokens -> users (user_id));
@@ -51,18 +98,21 @@ pub enum ConfirmationAction {
     Signup,
     Signin,
     DeleteAccount,
+    PasswordReset,
+    ChangeEmail,
 }
 table! {
     use diesel::sql_types::*;
     use super::ConfirmationActionMapping;
-    confirmations (user_id, action, token) {
+    confirmations (user_id, action, token_hash) {
         user_id -> Unsigned<Integer>,
-        // 16 byte
         action -> ConfirmationActionMapping,
         used -> Bool,
         date -> Datetime,
-        token -> Binary,
-        code_token -> Binary,
+        // 32 byte, keyed hash of the emailed token
+        token_hash -> Binary,
+        // 32 byte, keyed hash of the token sent to the browser
+        code_token_hash -> Binary,
         code -> Unsigned<Smallint>,
         code_trials -> Unsigned<Tinyint>,
         redirect_url -> Nullable<Varchar>,
@@ -74,16 +124,159 @@ joinable!(confirmations -> users (user_id));
 allow_tables_to_appear_in_same_query!(confirmations, users);
 
 table! {
-    totp_secrets (user_id) {
+    totp_secrets (id) {
+        id -> Unsigned<Integer>,
         user_id -> Unsigned<Integer>,
+        name -> Varchar,
         creation_date -> Datetime,
         // 20 byte
         secret -> Binary,
+        confirmed -> Bool,
+        last_used_step -> Nullable<Unsigned<BigInt>>,
     }
 }
 joinable!(totp_secrets -> users (user_id));
 allow_tables_to_appear_in_same_query!(totp_secrets, users);
 
+table! {
+    recovery_codes (id) {
+        id -> Unsigned<Integer>,
+        user_id -> Unsigned<Integer>,
+        // 32 byte (SHA-256 of the code)
+        code_hash -> Binary,
+        creation_date -> Datetime,
+        used_date -> Nullable<Datetime>,
+    }
+}
+joinable!(recovery_codes -> users (user_id));
+allow_tables_to_appear_in_same_query!(recovery_codes, users);
+
+table! {
+    webauthn_credentials (id) {
+        id -> Unsigned<Integer>,
+        user_id -> Unsigned<Integer>,
+        name -> Varchar,
+        credential_id -> Varbinary,
+        // JSON serialized webauthn-rs Passkey
+        passkey -> Text,
+        creation_date -> Datetime,
+        last_use_date -> Nullable<Datetime>,
+    }
+}
+joinable!(webauthn_credentials -> users (user_id));
+allow_tables_to_appear_in_same_query!(webauthn_credentials, users);
+
+table! {
+    user_identities (id) {
+        id -> Unsigned<Integer>,
+        user_id -> Unsigned<Integer>,
+        // Name of the OIDC provider
+        provider -> Varchar,
+        // OIDC subject identifier, unique for a provider
+        subject -> Varchar,
+        email -> Varchar,
+        creation_date -> Datetime,
+    }
+}
+joinable!(user_identities -> users (user_id));
+allow_tables_to_appear_in_same_query!(user_identities, users);
+
+table! {
+    api_tokens (id) {
+        id -> Unsigned<Integer>,
+        user_id -> Unsigned<Integer>,
+        name -> Varchar,
+        // 8 byte, first bytes of the token, used for lookup
+        token_prefix -> Binary,
+        // 32 byte, keyed hash of the token
+        token_hash -> Binary,
+        // Bit field of ApiScope
+        scopes -> Unsigned<TinyInt>,
+        creation_date -> Datetime,
+        expiration_date -> Nullable<Datetime>,
+        last_use_date -> Nullable<Datetime>,
+    }
+}
+joinable!(api_tokens -> users (user_id));
+allow_tables_to_appear_in_same_query!(api_tokens, users);
+
+#[derive(JsonSchema, Debug, Clone, Copy, PartialEq, diesel_derive_enum::DbEnum, Deserialize, Serialize, rocket::FromFormField)]
+pub enum AuditEventType {
+    Signin,
+    SigninFailed,
+    TotpAdded,
+    TotpRemoved,
+    PasskeyAdded,
+    PasskeyRemoved,
+    PasswordChanged,
+    PasswordReset,
+    EmailChanged,
+    ApiTokenCreated,
+    ApiTokenRevoked,
+    ShareAccepted,
+    AccountDeletionScheduled,
+    AccountDeletionCancelled,
+    UserBanned,
+    UserUnbanned,
+    UserSignedOut,
+    StorageLimitChanged,
+    TfaLoginEnabled,
+    TfaLoginDisabled,
+    RecoveryCodesRegenerated,
+}
+table! {
+    use diesel::sql_types::*;
+    use super::AuditEventTypeMapping;
+    audit_events (id) {
+        id -> Unsigned<BigInt>,
+        event -> AuditEventTypeMapping,
+        // User who performed the action (e.g. the admin banning a user), None if unknown or from the CLI
+        actor_user_id -> Nullable<Unsigned<Integer>>,
+        // User affected by the action, None if unknown (e.g. failed sign in with an unknown email)
+        target_user_id -> Nullable<Unsigned<Integer>>,
+        details -> Nullable<Varchar>,
+        device_string -> Nullable<Varchar>,
+        ip_address -> Nullable<Varbinary>,
+        creation_date -> Datetime,
+    }
+}
+
+#[derive(JsonSchema, Debug, Clone, Copy, PartialEq, diesel_derive_enum::DbEnum, Serialize)]
+pub enum EmailStatus {
+    Pending,
+    // Claimed by an outbox worker until `next_attempt_date`, then considered pending again
+    Sending,
+    Sent,
+    // Dead letter: all the sending attempts failed
+    Failed,
+    // Not sent before `expiration_date`, the content is removed
+    Expired,
+}
+table! {
+    use diesel::sql_types::*;
+    use super::EmailStatusMapping;
+    email_outbox (id) {
+        id -> Unsigned<BigInt>,
+        user_id -> Unsigned<Integer>,
+        to_name -> Varchar,
+        to_email -> Varchar,
+        subject -> Varchar,
+        body_text -> Text,
+        body_html -> Text,
+        status -> EmailStatusMapping,
+        // Number of failed sending attempts
+        attempts -> Unsigned<Integer>,
+        last_error -> Nullable<Varchar>,
+        creation_date -> Datetime,
+        next_attempt_date -> Datetime,
+        // Date after which the links and codes of the email are not valid anymore
+        expiration_date -> Nullable<Datetime>,
+        sent_date -> Nullable<Datetime>,
+    }
+}
+joinable!(email_outbox -> users (user_id));
+allow_tables_to_appear_in_same_query!(email_outbox, users);
+
 table! {
     shares_auto_accept (user_id_acceptor, user_id_sharer) {
         user_id_acceptor -> Unsigned<Integer>,
@@ -119,7 +312,18 @@ table! {
 joinable!(tags -> tag_groups (tag_group_id));
 allow_tables_to_appear_in_same_query!(tags, tag_groups);
 
+table! {
+    blobs (id) {
+        id -> Unsigned<BigInt>,
+        // 32 byte (SHA-256 of the file content)
+        hash -> Binary,
+        size_ko -> Unsigned<BigInt>,
+        ref_count -> Unsigned<Integer>,
+    }
+}
+
 #[derive(Debug, PartialEq, diesel_derive_enum::DbEnum)]
+#[DbValueStyle = "PascalCase"]
 pub enum PictureOrientation {
     Unspecified,
     Normal,
@@ -138,9 +342,10 @@ table! {
     pictures (id) {
         id -> Unsigned<BigInt>,
         name -> Varchar,
-        comment -> Text,
+        comment -> Nullable<Text>,
         owner_id -> Unsigned<Integer>,
         author_id -> Unsigned<Integer>,
+        blob_id -> Nullable<Unsigned<BigInt>>,
         deleted_date -> Nullable<Datetime>,
         copied -> Bool,
         creation_date -> Datetime,
@@ -162,7 +367,9 @@ table! {
 }
 joinable!(pictures -> users (owner_id));
 //joinable!(pictures -> users (author_id));
+joinable!(pictures -> blobs (blob_id));
 allow_tables_to_appear_in_same_query!(pictures, users);
+allow_tables_to_appear_in_same_query!(pictures, blobs);
 
 table! {
     pictures_tags (picture_id, tag_id) {
@@ -1,15 +1,17 @@
-use crate::database::auth_token::{AuthToken, Confirmation};
+use crate::database::auth_token::{AuthToken, Confirmation, TOTPSecret};
 use crate::database::database::DBConn;
+use crate::database::picture::{Blob, Picture};
 use crate::database::schema::*;
 use crate::utils::errors_catcher::{ErrorResponder, ErrorType};
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel::QueryDsl;
-use diesel::{insert_into, select, update, Associations, Identifiable, Insertable, OptionalExtension, Queryable, RunQueryDsl, Selectable};
-use diesel::{ExpressionMethods, SelectableHelper};
+use diesel::{delete, insert_into, select, update, Associations, BoolExpressionMethods, Identifiable, Insertable, OptionalExtension, Queryable, RunQueryDsl, Selectable};
+use diesel::{ExpressionMethods, NullableExpressionMethods, SelectableHelper, TextExpressionMethods};
 use pwhash::bcrypt;
 use rocket::Request;
+use std::collections::HashMap;
 
-#[derive(Queryable, Selectable, Identifiable, Insertable, Debug, PartialEq)]
+#[derive(Queryable, Selectable, Identifiable, Insertable, Debug, Clone, PartialEq)]
 #[diesel(primary_key(id))]
 #[diesel(table_name = users)]
 pub struct User {
@@ -22,6 +24,26 @@ pub struct User {
     pub tfa_login: bool,
     pub storage_count_ko: u64,
     pub storage_limit_mo: u32,
+    /// Date at which the account will be deleted, if a deletion has been requested and confirmed
+    pub deletion_date: Option<NaiveDateTime>,
+    /// New email address waiting to be confirmed through a `ChangeEmail` confirmation
+    pub pending_email: Option<String>,
+    /// Language of the emails sent to the user
+    pub locale: Locale,
+}
+
+/// External identity (OIDC provider account) linked to a user.
+#[derive(Queryable, Selectable, Identifiable, Associations, Debug, PartialEq)]
+#[diesel(primary_key(id))]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = user_identities)]
+pub struct UserIdentity {
+    pub id: u32,
+    pub user_id: u32,
+    pub provider: String,
+    pub subject: String,
+    pub email: String,
+    pub creation_date: NaiveDateTime,
 }
 
 #[derive(Queryable, Selectable, Identifiable, Associations, Debug, PartialEq)]
@@ -55,21 +77,12 @@ impl User {
                 data.ok_or_else(|| ErrorType::UserNotFound.res())
             })
     }
+    /// Finds the user and its auth token from the user id and the clear text auth token.
     pub fn find_logged_in_opt(conn: &mut DBConn, user_id: u32, auth_token: Vec<u8>) -> Result<Option<(User, AuthToken)>, ErrorResponder> {
-        users::table.left_join(auth_tokens::table)
-            .filter(users::dsl::id.eq(user_id))
-            .filter(auth_tokens::dsl::token.eq(auth_token))
-            .select((User::as_select(), Option::<AuthToken>::as_select()))
-            .first::<(User, Option<AuthToken>)>(conn)
-            .optional()
-            .map_err(|e| {
-                ErrorType::DatabaseError("Failed to get user and auth token".to_string(), e).res_rollback()
-            })
-            .map(|data| {
-                data.and_then(|(user, auth)| {
-                    auth.map(|auth| (user, auth))
-                })
-            })
+        if let Some(auth) = AuthToken::find_from_token_opt(conn, &user_id, &auth_token)? {
+            return Ok(User::from_id_opt(conn, &user_id)?.map(|user| (user, auth)));
+        }
+        Ok(None)
     }
 
     pub fn find_by_email_opt(conn: &mut DBConn, email: &str) -> Result<Option<User>, ErrorResponder> {
@@ -128,6 +141,48 @@ impl User {
             })
     }
 
+    pub fn update_password(conn: &mut DBConn, user_id: &u32, password: &str) -> Result<(), ErrorResponder> {
+        update(users::table)
+            .filter(users::dsl::id.eq(user_id))
+            .set(users::dsl::password_hash.eq(bcrypt::hash(password).unwrap()))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to update user password".to_string(), e).res_rollback()
+            })
+    }
+
+    pub fn set_pending_email(&self, conn: &mut DBConn, email: &str) -> Result<(), ErrorResponder> {
+        update(users::table)
+            .filter(users::dsl::id.eq(self.id))
+            .set(users::dsl::pending_email.eq(Some(email)))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to set user pending email".to_string(), e).res_rollback()
+            })
+    }
+    /// Replaces the email of the user with its pending email, returning the new email.
+    /// - Throw `EmailChangeNotRequested` if the user has no pending email.
+    /// - Throw `EmailAlreadyExists` if another account uses the pending email.
+    pub fn apply_pending_email(&self, conn: &mut DBConn) -> Result<String, ErrorResponder> {
+        let email = self.pending_email.clone().ok_or(ErrorType::EmailChangeNotRequested.res())?;
+        if User::find_by_email_opt(conn, &email)?.is_some() {
+            return ErrorType::EmailAlreadyExists.res_err();
+        }
+        update(users::table)
+            .filter(users::dsl::id.eq(self.id))
+            .set((
+                users::dsl::email.eq(&email),
+                users::dsl::pending_email.eq(None::<String>),
+            ))
+            .execute(conn)
+            .map(|_| email)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to update user email".to_string(), e).res_rollback()
+            })
+    }
+
     pub fn switch_status(&self, conn: &mut DBConn, status: &UserStatus) -> Result<(), ErrorResponder> {
         Self::switch_status_from_id(conn, &self.id, status)
     }
@@ -142,9 +197,384 @@ impl User {
         Ok(())
     }
 
+    /// Lists the users whose name or email contains `search` (all users if None), ordered by id.
+    pub fn search_users(conn: &mut DBConn, search: &Option<String>, offset: i64, limit: i64) -> Result<Vec<User>, ErrorResponder> {
+        let mut query = users::table.into_boxed();
+        if let Some(search) = search {
+            let pattern = format!("%{}%", search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+            query = query.filter(users::dsl::name.like(pattern.clone()).or(users::dsl::email.like(pattern)));
+        }
+        query
+            .order(users::dsl::id.asc())
+            .offset(offset)
+            .limit(limit)
+            .select(User::as_select())
+            .load::<User>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to search users".to_string(), e).res_rollback()
+            })
+    }
+    pub fn set_storage_limit_from_id(conn: &mut DBConn, user_id: &u32, storage_limit_mo: u32) -> Result<(), ErrorResponder> {
+        update(users::table)
+            .filter(users::dsl::id.eq(user_id))
+            .set(users::dsl::storage_limit_mo.eq(storage_limit_mo))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to update user storage limit".to_string(), e).res_rollback()
+            })
+    }
+
+    pub fn set_locale_from_id(conn: &mut DBConn, user_id: &u32, locale: Locale) -> Result<(), ErrorResponder> {
+        update(users::table)
+            .filter(users::dsl::id.eq(user_id))
+            .set(users::dsl::locale.eq(locale))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to update user locale".to_string(), e).res_rollback()
+            })
+    }
+
+    /// Gets the locale of a user, English if the user does not exist.
+    pub fn get_locale_from_id(conn: &mut DBConn, user_id: &u32) -> Result<Locale, ErrorResponder> {
+        users::table
+            .filter(users::dsl::id.eq(user_id))
+            .select(users::dsl::locale)
+            .first::<Locale>(conn)
+            .optional()
+            .map(|locale| locale.unwrap_or_default())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user locale".to_string(), e).res_rollback()
+            })
+    }
+
+    /// Checks that the user, already authenticated by a session, proves its identity again
+    /// with its password or a code of one of its TOTP authenticators, before a sensitive change.
+    /// - Throw `ReauthenticationRequired` if neither a password nor a TOTP code is provided.
+    /// - Throw `InvalidTOTPCode` if the TOTP code is invalid.
+    /// - Throw `InvalidPassword` if the password is incorrect.
+    pub fn check_reauthentication(&self, conn: &mut DBConn, password: Option<&str>, totp_code: Option<&str>) -> Result<(), ErrorResponder> {
+        if let Some(totp_code) = totp_code {
+            if !TOTPSecret::check_user_totp(conn, &self.id, &self.email, totp_code)? {
+                return ErrorType::InvalidTOTPCode.res_err();
+            }
+            return Ok(());
+        }
+        let password = password.ok_or(ErrorType::ReauthenticationRequired.res())?;
+        if !bcrypt::verify(password, &self.password_hash) {
+            return ErrorType::InvalidPassword.res_err();
+        }
+        Ok(())
+    }
+
+    pub fn set_tfa_login(&self, conn: &mut DBConn, tfa_login: bool) -> Result<(), ErrorResponder> {
+        update(users::table)
+            .filter(users::dsl::id.eq(self.id))
+            .set(users::dsl::tfa_login.eq(tfa_login))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to update user 2FA login setting".to_string(), e).res_rollback()
+            })
+    }
+
+    /// Adds `size_ko` to the storage used by the user.
+    /// The user row is locked until the end of the transaction, so that concurrent additions cannot exceed the limit.
+    /// Must be called inside a transaction.
+    /// - Throw `StorageLimitExceeded` if the new storage count exceeds the user's storage limit.
+    pub fn add_storage_count(conn: &mut DBConn, user_id: &u32, size_ko: u64) -> Result<(), ErrorResponder> {
+        let (storage_count_ko, storage_limit_mo) = users::table
+            .filter(users::dsl::id.eq(user_id))
+            .select((users::dsl::storage_count_ko, users::dsl::storage_limit_mo))
+            .for_update()
+            .first::<(u64, u32)>(conn)
+            .optional()
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user storage count".to_string(), e).res_rollback()
+            })?
+            .ok_or_else(|| ErrorType::UserNotFound.res_rollback())?;
+        if storage_count_ko + size_ko > storage_limit_mo as u64 * 1000 {
+            return ErrorType::StorageLimitExceeded.res_err_rollback();
+        }
+        update(users::table)
+            .filter(users::dsl::id.eq(user_id))
+            .set(users::dsl::storage_count_ko.eq(users::dsl::storage_count_ko + size_ko))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to update user storage count".to_string(), e).res_rollback()
+            })
+    }
+
+    /// Removes `size_ko` from the storage used by the user.
+    /// The count is left unchanged if it is lower than `size_ko` (see [`User::recompute_storage_counts`]).
+    pub fn remove_storage_count(conn: &mut DBConn, user_id: &u32, size_ko: u64) -> Result<(), ErrorResponder> {
+        update(users::table)
+            .filter(users::dsl::id.eq(user_id))
+            .filter(users::dsl::storage_count_ko.ge(size_ko))
+            .set(users::dsl::storage_count_ko.eq(users::dsl::storage_count_ko - size_ko))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to update user storage count".to_string(), e).res_rollback()
+            })
+    }
+    /// Recomputes the storage used by every user from the charged size of the pictures they own
+    /// (trashed pictures included, see [`Picture::charged_size_ko`]).
+    /// Returns the ids of the users whose count was wrong, with their old and new counts.
+    /// Must be called inside a transaction.
+    pub fn recompute_storage_counts(conn: &mut DBConn) -> Result<Vec<(u32, u64, u64)>, ErrorResponder> {
+        let mut used: HashMap<u32, u64> = HashMap::new();
+        pictures::table
+            .left_join(blobs::table)
+            .select((pictures::dsl::owner_id, blobs::dsl::size_ko.nullable(), pictures::dsl::width, pictures::dsl::height))
+            .load::<(u32, Option<u64>, u16, u16)>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get pictures sizes".to_string(), e).res_rollback()
+            })?
+            .into_iter()
+            .for_each(|(owner_id, size_ko, width, height)| {
+                *used.entry(owner_id).or_default() += Picture::charged_size_ko(size_ko, width, height);
+            });
+
+        let counts = users::table
+            .select((users::dsl::id, users::dsl::storage_count_ko))
+            .load::<(u32, u64)>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get users storage counts".to_string(), e).res_rollback()
+            })?;
+        let mut changed = Vec::new();
+        for (user_id, count) in counts {
+            let new_count = used.get(&user_id).copied().unwrap_or(0);
+            if new_count != count {
+                update(users::table)
+                    .filter(users::dsl::id.eq(user_id))
+                    .set(users::dsl::storage_count_ko.eq(new_count))
+                    .execute(conn)
+                    .map_err(|e| {
+                        ErrorType::DatabaseError("Failed to update user storage count".to_string(), e).res_rollback()
+                    })?;
+                changed.push((user_id, count, new_count));
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Schedules the deletion of the account after a grace period of `grace_days` days.
+    /// Returns the deletion date.
+    pub fn schedule_deletion(&self, conn: &mut DBConn, grace_days: i64) -> Result<NaiveDateTime, ErrorResponder> {
+        let deletion_date = Utc::now().naive_utc() + Duration::days(grace_days);
+        update(users::table)
+            .filter(users::dsl::id.eq(self.id))
+            .set(users::dsl::deletion_date.eq(deletion_date))
+            .execute(conn)
+            .map(|_| deletion_date)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to schedule user deletion".to_string(), e).res_rollback()
+            })
+    }
+    /// Cancels a scheduled deletion of the account.
+    pub fn cancel_deletion(&self, conn: &mut DBConn) -> Result<(), ErrorResponder> {
+        update(users::table)
+            .filter(users::dsl::id.eq(self.id))
+            .set(users::dsl::deletion_date.eq(None::<NaiveDateTime>))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to cancel user deletion".to_string(), e).res_rollback()
+            })
+    }
+    /// Gets the ids of the users whose grace period is over and that must be deleted.
+    pub fn get_users_to_delete(conn: &mut DBConn) -> Result<Vec<u32>, ErrorResponder> {
+        users::table
+            .filter(users::dsl::deletion_date.le(Utc::now().naive_utc()))
+            .select(users::dsl::id)
+            .load::<u32>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get users to delete".to_string(), e).res_rollback()
+            })
+    }
+
+    /// Deletes all the data of a user, in foreign key order, and finally the user itself.
+    /// Pictures authored by the user but owned by other users (copies of shared pictures) are kept
+    /// and attributed to their owner.
+    /// Returns the blobs that are not used anymore, whose files must be deleted once the transaction is committed
+    /// (see [`Blob::delete_files`]).
+    /// Must be called inside a transaction.
+    pub fn delete_account_data(conn: &mut DBConn, user_id: &u32) -> Result<Vec<Blob>, ErrorResponder> {
+        let picture_ids = pictures::table
+            .filter(pictures::dsl::owner_id.eq(user_id))
+            .select(pictures::dsl::id)
+            .load::<u64>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user pictures".to_string(), e).res_rollback()
+            })?;
+        let arrangement_ids = arrangements::table
+            .filter(arrangements::dsl::user_id.eq(user_id))
+            .select(arrangements::dsl::id)
+            .load::<u32>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user arrangements".to_string(), e).res_rollback()
+            })?;
+        let group_ids = groups::table
+            .filter(groups::dsl::arrangement_id.eq_any(&arrangement_ids))
+            .select(groups::dsl::id)
+            .load::<u32>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user groups".to_string(), e).res_rollback()
+            })?;
+        let hierarchy_ids = hierarchies::table
+            .filter(hierarchies::dsl::user_id.eq(user_id))
+            .select(hierarchies::dsl::id)
+            .load::<u32>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user hierarchies".to_string(), e).res_rollback()
+            })?;
+        let tag_group_ids = tag_groups::table
+            .filter(tag_groups::dsl::user_id.eq(user_id))
+            .select(tag_groups::dsl::id)
+            .load::<u32>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user tag groups".to_string(), e).res_rollback()
+            })?;
+        let tag_ids = tags::table
+            .filter(tags::dsl::tag_group_id.eq_any(&tag_group_ids))
+            .select(tags::dsl::id)
+            .load::<u32>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user tags".to_string(), e).res_rollback()
+            })?;
+        let duplicate_group_ids = duplicate_groups::table
+            .filter(duplicate_groups::dsl::user_id.eq(user_id))
+            .select(duplicate_groups::dsl::id)
+            .load::<u32>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user duplicate groups".to_string(), e).res_rollback()
+            })?;
+
+        // Pictures relations
+        delete(ratings::table.filter(ratings::dsl::user_id.eq(user_id).or(ratings::dsl::picture_id.eq_any(&picture_ids))))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user ratings".to_string(), e).res_rollback())?;
+        delete(pictures_tags::table.filter(pictures_tags::dsl::picture_id.eq_any(&picture_ids).or(pictures_tags::dsl::tag_id.eq_any(&tag_ids))))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user pictures tags".to_string(), e).res_rollback())?;
+        delete(duplicates::table.filter(duplicates::dsl::group_id.eq_any(&duplicate_group_ids).or(duplicates::dsl::picture_id.eq_any(&picture_ids))))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user duplicates".to_string(), e).res_rollback())?;
+        delete(duplicate_groups::table.filter(duplicate_groups::dsl::id.eq_any(&duplicate_group_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user duplicate groups".to_string(), e).res_rollback())?;
+        delete(groups_pictures::table.filter(groups_pictures::dsl::group_id.eq_any(&group_ids).or(groups_pictures::dsl::picture_id.eq_any(&picture_ids))))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user groups pictures".to_string(), e).res_rollback())?;
+
+        // Hierarchies, shares and arrangements
+        delete(hierarchies_arrangements::table.filter(
+            hierarchies_arrangements::dsl::hierarchy_id.eq_any(&hierarchy_ids)
+                .or(hierarchies_arrangements::dsl::arrangement_id.eq_any(&arrangement_ids))
+                .or(hierarchies_arrangements::dsl::parent_group_id.eq_any(&group_ids))))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user hierarchies arrangements".to_string(), e).res_rollback())?;
+        delete(hierarchies::table.filter(hierarchies::dsl::id.eq_any(&hierarchy_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user hierarchies".to_string(), e).res_rollback())?;
+        delete(link_share_groups::table.filter(link_share_groups::dsl::group_id.eq_any(&group_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user link shares".to_string(), e).res_rollback())?;
+        update(shared_groups::table.filter(shared_groups::dsl::match_conversion_group_id.eq_any(&group_ids)))
+            .set(shared_groups::dsl::match_conversion_group_id.eq(None::<u32>))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to unlink user match conversion groups".to_string(), e).res_rollback())?;
+        delete(shared_groups::table.filter(shared_groups::dsl::user_id.eq(user_id).or(shared_groups::dsl::group_id.eq_any(&group_ids))))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user shares".to_string(), e).res_rollback())?;
+        delete(groups::table.filter(groups::dsl::id.eq_any(&group_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user groups".to_string(), e).res_rollback())?;
+        delete(arrangements::table.filter(arrangements::dsl::id.eq_any(&arrangement_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user arrangements".to_string(), e).res_rollback())?;
+        delete(shares_auto_accept::table.filter(shares_auto_accept::dsl::user_id_acceptor.eq(user_id).or(shares_auto_accept::dsl::user_id_sharer.eq(user_id))))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user shares auto accept".to_string(), e).res_rollback())?;
+
+        // Tags
+        delete(tags::table.filter(tags::dsl::id.eq_any(&tag_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user tags".to_string(), e).res_rollback())?;
+        delete(tag_groups::table.filter(tag_groups::dsl::id.eq_any(&tag_group_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user tag groups".to_string(), e).res_rollback())?;
+
+        // Pictures and blobs
+        update(pictures::table.filter(pictures::dsl::author_id.eq(user_id)).filter(pictures::dsl::owner_id.ne(user_id)))
+            .set(pictures::dsl::author_id.eq(pictures::dsl::owner_id))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to anonymize user authored pictures".to_string(), e).res_rollback())?;
+        let blob_ids = pictures::table
+            .filter(pictures::dsl::id.eq_any(&picture_ids))
+            .filter(pictures::dsl::blob_id.is_not_null())
+            .select(pictures::dsl::blob_id.assume_not_null())
+            .load::<u64>(conn)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user pictures blobs".to_string(), e).res_rollback()
+            })?;
+        delete(pictures::table.filter(pictures::dsl::id.eq_any(&picture_ids)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user pictures".to_string(), e).res_rollback())?;
+        let unreferenced_blobs = Blob::release(conn, &blob_ids)?;
+
+        // Authentication
+        delete(auth_tokens::table.filter(auth_tokens::dsl::user_id.eq(user_id)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user auth tokens".to_string(), e).res_rollback())?;
+        delete(confirmations::table.filter(confirmations::dsl::user_id.eq(user_id)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user confirmations".to_string(), e).res_rollback())?;
+        delete(totp_secrets::table.filter(totp_secrets::dsl::user_id.eq(user_id)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user TOTP secrets".to_string(), e).res_rollback())?;
+        delete(recovery_codes::table.filter(recovery_codes::dsl::user_id.eq(user_id)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user recovery codes".to_string(), e).res_rollback())?;
+        delete(api_tokens::table.filter(api_tokens::dsl::user_id.eq(user_id)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user API tokens".to_string(), e).res_rollback())?;
+        delete(user_identities::table.filter(user_identities::dsl::user_id.eq(user_id)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user identities".to_string(), e).res_rollback())?;
+        delete(webauthn_credentials::table.filter(webauthn_credentials::dsl::user_id.eq(user_id)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user passkeys".to_string(), e).res_rollback())?;
+
+        // Emails: the queued emails are not sent anymore
+        delete(email_outbox::table.filter(email_outbox::dsl::user_id.eq(user_id)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user emails".to_string(), e).res_rollback())?;
+
+        // Audit events: the events on other users performed by this user (as an admin) are kept anonymously
+        delete(audit_events::table.filter(audit_events::dsl::target_user_id.eq(user_id)))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to delete user audit events".to_string(), e).res_rollback())?;
+        update(audit_events::table.filter(audit_events::dsl::actor_user_id.eq(user_id)))
+            .set(audit_events::dsl::actor_user_id.eq(None::<u32>))
+            .execute(conn).map_err(|e| ErrorType::DatabaseError("Failed to anonymize user audit events".to_string(), e).res_rollback())?;
+
+        delete(users::table.filter(users::dsl::id.eq(user_id)))
+            .execute(conn)
+            .map(|_| unreferenced_blobs)
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to delete user".to_string(), e).res_rollback()
+            })
+    }
+
     pub fn get_id_from_headers(request: &Request<'_>) -> Option<u32> {
         request.headers().get_one("X-User-Id").map(|s| s.parse::<u32>().ok()).flatten()
     }
 }
 
 impl ShareAutoAccept {}
+
+impl UserIdentity {
+    pub fn find_opt(conn: &mut DBConn, provider: &str, subject: &str) -> Result<Option<UserIdentity>, ErrorResponder> {
+        user_identities::table
+            .filter(user_identities::dsl::provider.eq(provider))
+            .filter(user_identities::dsl::subject.eq(subject))
+            .select(UserIdentity::as_select())
+            .first::<UserIdentity>(conn)
+            .optional()
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to get user identity".to_string(), e).res_rollback()
+            })
+    }
+    pub fn insert(conn: &mut DBConn, user_id: &u32, provider: &str, subject: &str, email: &str) -> Result<(), ErrorResponder> {
+        insert_into(user_identities::table)
+            .values((
+                user_identities::dsl::user_id.eq(user_id),
+                user_identities::dsl::provider.eq(provider),
+                user_identities::dsl::subject.eq(subject),
+                user_identities::dsl::email.eq(email),
+            ))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| {
+                ErrorType::DatabaseError("Failed to insert user identity".to_string(), e).res_rollback()
+            })
+    }
+}
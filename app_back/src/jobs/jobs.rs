@@ -0,0 +1,114 @@
+use crate::database::auth_token::AuthToken;
+use crate::database::database::DBPool;
+use crate::database::email_outbox::OutboxEmail;
+use crate::database::picture::Blob;
+use crate::database::user::User;
+use crate::utils::errors_catcher::err_transaction;
+use crate::utils::metrics::start_job;
+use chrono::{TimeDelta, Utc};
+use std::time::Duration;
+use tokio::task;
+use tracing::{error, info};
+
+/// Number of days the sent emails are kept in the outbox.
+const SENT_EMAILS_RETENTION_DAYS: i64 = 7;
+
+/// Starts all the periodic background jobs.
+/// Each job runs on the blocking thread pool as it uses synchronous database connections.
+pub fn start_jobs(db: DBPool) {
+    spawn_periodic_job("delete_scheduled_accounts", Duration::from_secs(60 * 60), db.clone(), delete_scheduled_accounts);
+    spawn_periodic_job("delete_expired_auth_tokens", Duration::from_secs(60 * 60), db.clone(), delete_expired_auth_tokens);
+    spawn_periodic_job("expire_emails", Duration::from_secs(5 * 60), db.clone(), expire_emails);
+    spawn_periodic_job("delete_sent_emails", Duration::from_secs(24 * 60 * 60), db, delete_sent_emails);
+}
+
+/// Runs `job` every `period`, the first run being immediate.
+/// Runs are recorded in the `jobs_running` and `job_duration_seconds` metrics.
+fn spawn_periodic_job(name: &'static str, period: Duration, db: DBPool, job: fn(&DBPool)) {
+    task::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let db = db.clone();
+            if let Err(e) = task::spawn_blocking(move || {
+                let _timer = start_job(name);
+                job(&db)
+            }).await {
+                error!(job = name, error = ?e, "Job failed");
+            }
+        }
+    });
+}
+
+/// Deletes the accounts whose deletion grace period is over.
+fn delete_scheduled_accounts(db: &DBPool) {
+    let conn = &mut match db.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!(error = ?e, "Unable to get a database connection");
+            return;
+        }
+    };
+    let user_ids = match User::get_users_to_delete(conn) {
+        Ok(user_ids) => user_ids,
+        Err(e) => {
+            error!(error = ?e, "Unable to get the users to delete");
+            return;
+        }
+    };
+    for user_id in user_ids {
+        match err_transaction(conn, |conn| User::delete_account_data(conn, &user_id)) {
+            Ok(unreferenced_blobs) => {
+                Blob::delete_files(&unreferenced_blobs);
+                info!(user_id, "Deleted account")
+            }
+            Err(e) => error!(user_id, error = ?e, "Failed to delete account"),
+        }
+    }
+}
+
+/// Deletes the auth tokens that exceeded their absolute or idle lifetime.
+fn delete_expired_auth_tokens(db: &DBPool) {
+    let conn = &mut match db.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!(error = ?e, "Unable to get a database connection");
+            return;
+        }
+    };
+    match AuthToken::delete_expired(conn) {
+        Ok(count) => info!(count, "Deleted expired auth tokens"),
+        Err(e) => error!(error = ?e, "Failed to delete expired auth tokens"),
+    }
+}
+
+/// Expires the unsent emails of the outbox whose links and codes are not valid anymore, removing their content.
+fn expire_emails(db: &DBPool) {
+    let conn = &mut match db.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!(error = ?e, "Unable to get a database connection");
+            return;
+        }
+    };
+    match OutboxEmail::expire(conn) {
+        Ok(count) => info!(count, "Expired emails"),
+        Err(e) => error!(error = ?e, "Failed to expire emails"),
+    }
+}
+
+/// Deletes the emails of the outbox sent or expired for more than [`SENT_EMAILS_RETENTION_DAYS`] days.
+fn delete_sent_emails(db: &DBPool) {
+    let conn = &mut match db.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!(error = ?e, "Unable to get a database connection");
+            return;
+        }
+    };
+    let date = Utc::now().naive_utc() - TimeDelta::days(SENT_EMAILS_RETENTION_DAYS);
+    match OutboxEmail::delete_sent_before(conn, &date) {
+        Ok(count) => info!(count, "Deleted sent emails"),
+        Err(e) => error!(error = ?e, "Failed to delete sent emails"),
+    }
+}
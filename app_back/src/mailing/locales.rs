@@ -0,0 +1,120 @@
+use crate::database::schema::Locale;
+use strum::IntoEnumIterator;
+use tera::{Context, Tera};
+
+/// Names of the emails, each one having an HTML template `<name>.html` and a text template `text_<name>.html`
+/// in every locale directory of `templates/`.
+pub const EMAIL_NAMES: [&str; 8] = [
+    "confirm_signup",
+    "confirm_signin",
+    "recovery_code_used",
+    "new_signin",
+    "password_reset",
+    "confirm_change_email",
+    "email_change_requested",
+    "confirm_delete_account",
+];
+
+/// Embeds the templates of the given locales into the binary, as `(name, content)` pairs named `<locale>/<file>`.
+macro_rules! embed_templates {
+    ($($locale:literal),*) => {
+        vec![$(embed_templates!(@files $locale,
+            "base.html", "text_base.html",
+            "confirm_signup.html", "text_confirm_signup.html",
+            "confirm_signin.html", "text_confirm_signin.html",
+            "recovery_code_used.html", "text_recovery_code_used.html",
+            "new_signin.html", "text_new_signin.html",
+            "password_reset.html", "text_password_reset.html",
+            "confirm_change_email.html", "text_confirm_change_email.html",
+            "email_change_requested.html", "text_email_change_requested.html",
+            "confirm_delete_account.html", "text_confirm_delete_account.html"
+        )),*].concat()
+    };
+    (@files $locale:literal, $($file:literal),*) => {
+        vec![$((concat!($locale, "/", $file), include_str!(concat!("templates/", $locale, "/", $file)))),*]
+    };
+}
+
+/// Builds the Tera instance with the embedded templates of all the locales.
+pub fn build_templates() -> tera::Result<Tera> {
+    let mut tera = Tera::default();
+    tera.autoescape_on(vec![".html"]);
+    tera.add_raw_templates(embed_templates!("en", "fr"))?;
+    Ok(tera)
+}
+
+/// Gets the name of the template of an email in a locale, falling back to English if it does not exist.
+pub fn template_name(tera: &Tera, template: &str, locale: Locale) -> String {
+    let name = format!("{}/{}.html", locale.code(), template);
+    if tera.get_template_names().any(|existing| existing == name) {
+        name
+    } else {
+        format!("{}/{}.html", Locale::En.code(), template)
+    }
+}
+
+/// Gets the subject of an email in a locale, falling back to English.
+pub fn email_subject(email: &str, locale: Locale) -> String {
+    localized_subject(email, locale)
+        .or(localized_subject(email, Locale::En))
+        .unwrap_or("Archypix")
+        .to_string()
+}
+
+fn localized_subject(email: &str, locale: Locale) -> Option<&'static str> {
+    let subject = match (locale, email) {
+        (Locale::En, "confirm_signup") => "Confirm your email address",
+        (Locale::En, "confirm_signin") => "Confirm your email address",
+        (Locale::En, "recovery_code_used") => "A recovery code was used to sign in",
+        (Locale::En, "new_signin") => "New sign-in to your account",
+        (Locale::En, "password_reset") => "Reset your password",
+        (Locale::En, "confirm_change_email") => "Confirm your new email address",
+        (Locale::En, "email_change_requested") => "Your email address is being changed",
+        (Locale::En, "confirm_delete_account") => "Confirm the deletion of your account",
+        (Locale::Fr, "confirm_signup") => "Confirmez votre adresse email",
+        (Locale::Fr, "confirm_signin") => "Confirmez votre adresse email",
+        (Locale::Fr, "recovery_code_used") => "Un code de récupération a été utilisé pour vous connecter",
+        (Locale::Fr, "new_signin") => "Nouvelle connexion à votre compte",
+        (Locale::Fr, "password_reset") => "Réinitialisez votre mot de passe",
+        (Locale::Fr, "confirm_change_email") => "Confirmez votre nouvelle adresse email",
+        (Locale::Fr, "email_change_requested") => "Votre adresse email est en cours de modification",
+        (Locale::Fr, "confirm_delete_account") => "Confirmez la suppression de votre compte",
+        _ => return None,
+    };
+    Some(subject)
+}
+
+/// Checks that every email has a subject and renders (HTML and text) in every locale, without fallback.
+/// Run at startup so that a broken or missing translation prevents the launch instead of failing when sending.
+pub fn check_email_templates(tera: &Tera) -> Result<(), String> {
+    let mut context = Context::new();
+    for variable in ["name", "url", "code", "ip", "agent", "new_email", "archypix_url", "unsubscribe_url"] {
+        context.insert(variable, variable);
+    }
+    context.insert("remaining_codes", &0);
+    context.insert("grace_days", &0);
+
+    for locale in Locale::iter() {
+        for email in EMAIL_NAMES {
+            if localized_subject(email, locale).is_none() {
+                return Err(format!("Missing subject of email {} in locale {}", email, locale.code()));
+            }
+            for template in [email.to_string(), format!("text_{}", email)] {
+                let name = format!("{}/{}.html", locale.code(), template);
+                tera.render(&name, &context).map_err(|e| format!("Unable to render {}: {:?}", name, e))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_email_renders_in_every_locale() {
+        let tera = build_templates().expect("Unable to parse the email templates");
+        assert_eq!(check_email_templates(&tera), Ok(()));
+    }
+}
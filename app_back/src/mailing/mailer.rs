@@ -1,75 +1,163 @@
 use lazy_static::lazy_static;
-use std::env;
 
+use crate::database::auth_token::{CONFIRMATION_LIFETIME_MINUTES, REVOKE_TOKEN_LIFETIME_DAYS};
+use crate::database::database::{DBConn, DBPool};
+use crate::database::email_outbox::{OutboxEmail, EMAIL_MAX_ATTEMPTS};
+use crate::database::schema::Locale;
+use crate::database::user::User;
+use crate::mailing::locales::{build_templates, email_subject, template_name};
+use crate::mailing::transport::{Email, MailTransport};
+use crate::utils::errors_catcher::ErrorResponder;
+use crate::utils::metrics::{observe_email, start_job};
 use crate::utils::utils::get_frontend_host;
-use mail_send::mail_builder::MessageBuilder;
-use mail_send::SmtpClientBuilder;
-use rocket::serde::json::from_str;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use std::sync::Arc;
+use std::time::Duration;
 use tera::{Context, Tera};
 use tokio::task;
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info, warn};
 
 lazy_static! {
-    pub static ref TEMPLATES: Tera = {
-        let mut tera = match Tera::new("src/mailing/templates/**/*") {
-            Ok(t) => t,
-            Err(e) => {
-                println!("Parsing error(s): {}", e);
-                ::std::process::exit(1);
-            }
-        };
-        tera.autoescape_on(vec![".html"]);
-        tera
+    /// Email templates of all the locales, embedded into the binary (see [`build_templates`])
+    pub static ref TEMPLATES: Tera = match build_templates() {
+        Ok(tera) => tera,
+        Err(e) => {
+            error!(error = ?e, "Unable to parse the email templates");
+            ::std::process::exit(1);
+        }
     };
 }
 
-/// Sends an HTML email with the given template and context
-pub fn send_rendered_email(to: (String, String), subject: String, template: String, context: Context) {
-    let text = render_email_context(format!("text_{}", template), context.clone());
-    let html = render_email_context(template, context);
-    send_email(to, subject, text, html);
+/// Number of emails sent per run of the outbox worker, in a single batch of the mail transport.
+const OUTBOX_BATCH_SIZE: i64 = 50;
+/// Period at which the outbox worker looks for due emails.
+const OUTBOX_POLL_PERIOD: Duration = Duration::from_secs(5);
+
+/// Renders an HTML email with the given template and context in the locale of the user, and queues it in the outbox.
+/// The email is only sent if the current transaction is committed, and before its links and codes expire.
+pub fn send_rendered_email(conn: &mut DBConn, user_id: &u32, to: (String, String), template: String, context: Context) -> Result<(), ErrorResponder> {
+    let locale = User::get_locale_from_id(conn, user_id)?;
+    let subject = email_subject(&template, locale);
+    let text = render_email_context(&format!("text_{}", template), locale, context.clone());
+    let html = render_email_context(&template, locale, context);
+    OutboxEmail::insert(conn, user_id, &to, &subject, &text, &html, email_expiration_date(&template))
 }
-/// Renders an email template with the given context
+/// Date after which the links and codes of an email are not valid anymore, `None` if it has none.
+fn email_expiration_date(template: &str) -> Option<NaiveDateTime> {
+    let lifetime = match template {
+        "confirm_signup" | "confirm_signin" | "password_reset" | "confirm_change_email" | "confirm_delete_account" => TimeDelta::minutes(CONFIRMATION_LIFETIME_MINUTES),
+        "new_signin" => TimeDelta::days(REVOKE_TOKEN_LIFETIME_DAYS),
+        _ => return None,
+    };
+    Some(Utc::now().naive_utc() + lifetime)
+}
+/// Renders an email template in the given locale (or English if missing) with the given context
 /// Inserts the frontend url in the context
-fn render_email_context(template: String, mut context: Context) -> String {
+fn render_email_context(template: &str, locale: Locale, mut context: Context) -> String {
     context.insert("archypix_url", &get_frontend_host());
-    TEMPLATES.render(format!("{}.html", template).as_str(), &context)
+    TEMPLATES.render(&template_name(&TEMPLATES, template, locale), &context)
             .expect("Unable to render email template.")
 }
 
-/// Sends an email with the provided raw text and HTML content
-fn send_email(to: (String, String), subject: String, body_text: String, body_html: String) {
-    task::spawn(send_email_async(to, subject, body_text, body_html));
+/// Starts the worker sending the emails of the outbox through the given transport.
+pub fn start_outbox_worker(db: DBPool, transport: Arc<dyn MailTransport>) {
+    task::spawn(async move {
+        let mut interval = tokio::time::interval(OUTBOX_POLL_PERIOD);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            send_due_emails(&db, transport.as_ref()).await;
+        }
+    });
 }
 
-/// Sends an email with the provided raw text and HTML content asynchronously
-async fn send_email_async(to: (String, String), subject: String, body_text: String, body_html: String) {
-    let server: String = env::var("SMTP_SERVER").expect("SMTP_SERVER must be set");
-    let server_port: u16 = env::var("SMTP_SERVER_PORT")
-        .map(|port| from_str::<u16>(port.as_str()).unwrap_or(465)).unwrap_or(465);
-    let from_name: String = env::var("SMTP_FROM_NAME").expect("SMTP_FROM_NAME must be set");
-    let username: String = env::var("SMTP_USERNAME").expect("SMTP_USERNAME must be set");
-    let password: String = env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD must be set");
+/// Sends the due emails of the outbox in a single batch (i.e. a single SMTP connection).
+/// Failed emails are retried later with an exponential backoff, until marked as failed (see [`OutboxEmail::mark_attempt_failed`]).
+/// The emails are claimed first, so that other instances do not send them too.
+/// The database accesses run on the blocking thread pool, only the sending runs on the async runtime.
+async fn send_due_emails(db: &DBPool, transport: &dyn MailTransport) {
+    let pool = db.clone();
+    let emails = match task::spawn_blocking(move || get_due_emails(&pool)).await {
+        Ok(emails) if emails.is_empty() => return,
+        Ok(emails) => emails,
+        Err(e) => {
+            error!(error = ?e, "Unable to get the due emails");
+            return;
+        }
+    };
+    let _timer = start_job("email_outbox");
 
-    let message = MessageBuilder::new()
-        .from((from_name, username.clone()))
-        .to(vec![to.clone()])
-        .subject(subject)
-        .text_body(body_text)
-        .html_body(body_html);
+    let messages: Vec<Email> = emails.iter().map(|email| Email {
+        to: (email.to_name.clone(), email.to_email.clone()),
+        subject: email.subject.clone(),
+        body_text: email.body_text.clone(),
+        body_html: email.body_html.clone(),
+    }).collect();
+    let results = match transport.send_batch(&messages).await {
+        Ok(results) => {
+            results.iter().for_each(|result| observe_email(if result.is_ok() { "success" } else { "send_error" }));
+            results
+        }
+        Err(e) => {
+            error!(error = %e, "Mail transport unavailable");
+            emails.iter().map(|_| {
+                observe_email("connect_error");
+                Err(e.clone())
+            }).collect()
+        }
+    };
 
-    let connect = SmtpClientBuilder::new(server, server_port)
-        .implicit_tls(false)
-        .credentials((username, password))
-        .connect()
-        .await;
+    let pool = db.clone();
+    if let Err(e) = task::spawn_blocking(move || record_results(&pool, &emails, results)).await {
+        error!(error = ?e, "Unable to record the email sending results");
+    }
+}
+
+/// Claims the due emails of the outbox, empty if the database is unavailable.
+fn get_due_emails(db: &DBPool) -> Vec<OutboxEmail> {
+    let conn = &mut match db.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!(error = ?e, "Unable to get a database connection");
+            return Vec::new();
+        }
+    };
+    OutboxEmail::claim_due(conn, OUTBOX_BATCH_SIZE).unwrap_or_else(|e| {
+        error!(error = ?e, "Unable to get the due emails");
+        Vec::new()
+    })
+}
 
-    if connect.is_err() {
-        eprintln!("Failed to connect to SMTP server: {:?}", connect.err().unwrap());
-    }else{
-        if let Err(e) = connect.unwrap().send(message).await {
-            eprintln!("Failed to send email: {:?}", e);
-        }else{
-            println!("Email sent successfully to: {} [{}]", to.0, to.1);
+/// Marks the sent emails as sent, and records the failed attempts of the others.
+fn record_results(db: &DBPool, emails: &[OutboxEmail], results: Vec<Result<(), String>>) {
+    let conn = &mut match db.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!(error = ?e, "Unable to get a database connection, the sending results are not recorded");
+            return;
         }
+    };
+    for (email, result) in emails.iter().zip(results) {
+        match result {
+            Ok(_) => {
+                info!(email_id = email.id, to = %email.to_email, "Email sent");
+                if let Err(e) = email.mark_as_sent(conn) {
+                    error!(email_id = email.id, error = ?e, "Failed to mark email as sent");
+                }
+            }
+            Err(e) => record_failure(conn, email, &e),
+        }
+    }
+}
+
+/// Records a failed sending attempt of an email.
+fn record_failure(conn: &mut DBConn, email: &OutboxEmail, error: &str) {
+    if let Err(e) = email.mark_attempt_failed(conn, error) {
+        error!(email_id = email.id, error = ?e, "Failed to record email sending failure");
+    } else if email.attempts + 1 >= EMAIL_MAX_ATTEMPTS {
+        error!(email_id = email.id, to = %email.to_email, error, "Email sending failed, no more attempts");
+    } else {
+        warn!(email_id = email.id, to = %email.to_email, attempt = email.attempts + 1, error, "Email sending failed, will retry");
     }
 }
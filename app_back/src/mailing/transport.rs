@@ -0,0 +1,188 @@
+use crate::utils::config::{Config, MailTransportKind, SmtpConfig, SmtpTls};
+use crate::utils::utils::random_token;
+use mail_send::mail_builder::MessageBuilder;
+use mail_send::{SmtpClient, SmtpClientBuilder};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{info, warn};
+
+/// Email ready to be sent, rendered from the outbox.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    /// Name and address of the recipient
+    pub to: (String, String),
+    pub subject: String,
+    pub body_text: String,
+    pub body_html: String,
+}
+
+impl Email {
+    /// Builds the MIME message, sent from the given name and address.
+    fn message<'a>(&'a self, from: &'a (String, String)) -> MessageBuilder<'a> {
+        MessageBuilder::new()
+            .from((from.0.as_str(), from.1.as_str()))
+            .to(vec![(self.to.0.as_str(), self.to.1.as_str())])
+            .subject(self.subject.as_str())
+            .text_body(self.body_text.as_str())
+            .html_body(self.body_html.as_str())
+    }
+}
+
+/// Way of delivering the emails of the outbox (see [`build_mail_transport`]).
+#[rocket::async_trait]
+pub trait MailTransport: Send + Sync {
+    /// Sends a batch of emails, reusing the same connection if any.
+    /// Returns an error if the transport is unavailable (e.g. SMTP connection failure),
+    /// or the result of each email, in the same order.
+    async fn send_batch(&self, emails: &[Email]) -> Result<Vec<Result<(), String>>, String>;
+}
+
+/// Builds the transport selected by the `MAIL_TRANSPORT` configuration.
+/// Returns `None` if the SMTP transport is selected but not configured, leaving the emails in the outbox.
+pub fn build_mail_transport(config: &Config) -> Option<Arc<dyn MailTransport>> {
+    let from = (
+        config.smtp_from_name.clone().unwrap_or("Archypix".to_string()),
+        config.smtp_username.clone().unwrap_or("noreply@localhost".to_string()),
+    );
+    match config.mail_transport {
+        MailTransportKind::Smtp => config.smtp().map(|smtp| Arc::new(SmtpTransport::new(&smtp)) as Arc<dyn MailTransport>),
+        MailTransportKind::File => Some(Arc::new(FileTransport { directory: config.mail_directory.clone(), from })),
+        MailTransportKind::Memory => Some(Arc::new(MemoryTransport::default())),
+    }
+}
+
+/// Transport sending the emails through an SMTP server, with a single connection per batch.
+pub struct SmtpTransport {
+    server: String,
+    port: u16,
+    tls: SmtpTls,
+    credentials: (String, String),
+    from: (String, String),
+}
+
+impl SmtpTransport {
+    pub fn new(smtp: &SmtpConfig) -> SmtpTransport {
+        SmtpTransport {
+            server: smtp.server.to_string(),
+            port: smtp.port,
+            tls: smtp.tls,
+            credentials: (smtp.username.to_string(), smtp.password.to_string()),
+            from: (smtp.from_name.to_string(), smtp.username.to_string()),
+        }
+    }
+
+    async fn send_all<T: AsyncRead + AsyncWrite + Unpin>(&self, client: &mut SmtpClient<T>, emails: &[Email]) -> Vec<Result<(), String>> {
+        let mut results = Vec::with_capacity(emails.len());
+        for email in emails {
+            results.push(client.send(email.message(&self.from)).await.map_err(|e| e.to_string()));
+        }
+        results
+    }
+}
+
+#[rocket::async_trait]
+impl MailTransport for SmtpTransport {
+    async fn send_batch(&self, emails: &[Email]) -> Result<Vec<Result<(), String>>, String> {
+        let builder = SmtpClientBuilder::new(self.server.as_str(), self.port)
+            .implicit_tls(self.tls == SmtpTls::Implicit)
+            .credentials((self.credentials.0.as_str(), self.credentials.1.as_str()));
+        let connect_error = |e: mail_send::Error| format!("Failed to connect to SMTP server {}:{}: {}", self.server, self.port, e);
+        match self.tls {
+            SmtpTls::None => {
+                let mut client = builder.connect_plain().await.map_err(connect_error)?;
+                Ok(self.send_all(&mut client, emails).await)
+            }
+            SmtpTls::Implicit | SmtpTls::Starttls => {
+                let mut client = builder.connect().await.map_err(connect_error)?;
+                Ok(self.send_all(&mut client, emails).await)
+            }
+        }
+    }
+}
+
+/// Transport writing each email as an `.eml` file in a directory, for development.
+pub struct FileTransport {
+    directory: PathBuf,
+    from: (String, String),
+}
+
+#[rocket::async_trait]
+impl MailTransport for FileTransport {
+    async fn send_batch(&self, emails: &[Email]) -> Result<Vec<Result<(), String>>, String> {
+        tokio::fs::create_dir_all(&self.directory).await
+            .map_err(|e| format!("Unable to create the mail directory {}: {}", self.directory.display(), e))?;
+
+        let mut results = Vec::with_capacity(emails.len());
+        for email in emails {
+            let path = self.directory.join(format!("{}_{}.eml", chrono::Utc::now().format("%Y%m%d%H%M%S"), hex::encode(random_token(4))));
+            let result = match email.message(&self.from).write_to_vec() {
+                Ok(content) => tokio::fs::write(&path, content).await.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            match &result {
+                Ok(_) => info!(to = %email.to.1, subject = %email.subject, path = %path.display(), "Email written to file"),
+                Err(e) => warn!(to = %email.to.1, path = %path.display(), error = %e, "Unable to write email file"),
+            }
+            results.push(result);
+        }
+        Ok(results)
+    }
+}
+
+/// Transport keeping the emails in memory, for integration tests to assert on the sent codes and links.
+#[derive(Default)]
+pub struct MemoryTransport {
+    emails: Mutex<Vec<Email>>,
+}
+
+impl MemoryTransport {
+    /// Gets the emails sent so far, the oldest first.
+    pub fn sent_emails(&self) -> Vec<Email> {
+        self.emails.lock().unwrap().clone()
+    }
+    /// Gets the last email sent to an address.
+    pub fn last_email_to(&self, address: &str) -> Option<Email> {
+        self.emails.lock().unwrap().iter().rev().find(|email| email.to.1 == address).cloned()
+    }
+    pub fn clear(&self) {
+        self.emails.lock().unwrap().clear();
+    }
+}
+
+#[rocket::async_trait]
+impl MailTransport for MemoryTransport {
+    async fn send_batch(&self, emails: &[Email]) -> Result<Vec<Result<(), String>>, String> {
+        self.emails.lock().unwrap().extend_from_slice(emails);
+        Ok(emails.iter().map(|_| Ok(())).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(to: &str, subject: &str) -> Email {
+        Email {
+            to: ("User".to_string(), to.to_string()),
+            subject: subject.to_string(),
+            body_text: format!("{} text", subject),
+            body_html: format!("<p>{}</p>", subject),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn memory_transport_keeps_the_sent_emails() {
+        let memory = Arc::new(MemoryTransport::default());
+        let transport: Arc<dyn MailTransport> = memory.clone();
+
+        let results = transport.send_batch(&[email("a@archypix.com", "First"), email("b@archypix.com", "Second"), email("a@archypix.com", "Third")]).await.unwrap();
+        assert_eq!(results, vec![Ok(()), Ok(()), Ok(())]);
+        assert_eq!(memory.sent_emails().len(), 3);
+        assert_eq!(memory.last_email_to("a@archypix.com"), Some(email("a@archypix.com", "Third")));
+        assert_eq!(memory.last_email_to("c@archypix.com"), None);
+
+        memory.clear();
+        assert!(memory.sent_emails().is_empty());
+    }
+}
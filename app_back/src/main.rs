@@ -2,24 +2,62 @@
 extern crate rocket;
 extern crate tera;
 
+use crate::api::account::credentials::{account_email_change, account_password_change, okapi_add_operation_for_account_email_change_, okapi_add_operation_for_account_password_change_};
+use crate::api::account::preferences::{account_locale, okapi_add_operation_for_account_locale_};
+use crate::api::account::security::{account_security, okapi_add_operation_for_account_security_};
+use crate::api::account::delete::{account_delete, account_delete_cancel, okapi_add_operation_for_account_delete_, okapi_add_operation_for_account_delete_cancel_};
+use crate::api::admin::admin::{admin_user, admin_user_ban, admin_user_resend_confirmation, admin_user_signout, admin_user_storage_limit, admin_user_unban, admin_users, admin_audit_events, admin_email_retry, admin_emails_failed, okapi_add_operation_for_admin_audit_events_, okapi_add_operation_for_admin_email_retry_, okapi_add_operation_for_admin_emails_failed_, okapi_add_operation_for_admin_user_, okapi_add_operation_for_admin_user_ban_, okapi_add_operation_for_admin_user_resend_confirmation_, okapi_add_operation_for_admin_user_signout_, okapi_add_operation_for_admin_user_storage_limit_, okapi_add_operation_for_admin_user_unban_, okapi_add_operation_for_admin_users_};
+use crate::api::auth::api_tokens::{auth_api_tokens_create, auth_api_tokens_delete, auth_api_tokens_list, okapi_add_operation_for_auth_api_tokens_create_, okapi_add_operation_for_auth_api_tokens_delete_, okapi_add_operation_for_auth_api_tokens_list_};
 use crate::api::auth::confirm::{auth_confirm_code, auth_confirm_token, okapi_add_operation_for_auth_confirm_code_, okapi_add_operation_for_auth_confirm_token_};
+use crate::api::auth::oidc::{auth_oidc_callback, auth_oidc_providers, auth_oidc_start, okapi_add_operation_for_auth_oidc_callback_, okapi_add_operation_for_auth_oidc_providers_, okapi_add_operation_for_auth_oidc_start_};
+use crate::api::auth::passkey::{auth_passkey_delete, auth_passkey_list, auth_passkey_passwordless_finish, auth_passkey_passwordless_start, auth_passkey_register_finish, auth_passkey_register_start, auth_passkey_signin_start, okapi_add_operation_for_auth_passkey_delete_, okapi_add_operation_for_auth_passkey_list_, okapi_add_operation_for_auth_passkey_passwordless_finish_, okapi_add_operation_for_auth_passkey_passwordless_start_, okapi_add_operation_for_auth_passkey_register_finish_, okapi_add_operation_for_auth_passkey_register_start_, okapi_add_operation_for_auth_passkey_signin_start_};
+use crate::api::auth::password::{auth_password_forgot, auth_password_reset, okapi_add_operation_for_auth_password_forgot_, okapi_add_operation_for_auth_password_reset_};
+use crate::api::auth::sessions::{auth_sessions, auth_sessions_delete, auth_sessions_revoke, auth_sessions_signout_others, auth_signout, okapi_add_operation_for_auth_sessions_, okapi_add_operation_for_auth_sessions_delete_, okapi_add_operation_for_auth_sessions_revoke_, okapi_add_operation_for_auth_sessions_signout_others_, okapi_add_operation_for_auth_signout_};
 use crate::api::auth::signin::{auth_signin, auth_signin_email, okapi_add_operation_for_auth_signin_, okapi_add_operation_for_auth_signin_email_};
 use crate::api::auth::signup::{auth_signup, okapi_add_operation_for_auth_signup_};
 use crate::api::auth::status::{auth_status, okapi_add_operation_for_auth_status_};
-use crate::database::database::{get_connection, get_connection_pool};
-use crate::utils::errors_catcher::{bad_request, internal_error, not_found, unauthorized, unprocessable_entity};
+use crate::api::auth::totp::{auth_recovery_codes_regenerate, auth_recovery_codes_status, auth_tfa_login, auth_totp_confirm, auth_totp_delete, auth_totp_enroll, auth_totp_list, okapi_add_operation_for_auth_recovery_codes_regenerate_, okapi_add_operation_for_auth_recovery_codes_status_, okapi_add_operation_for_auth_tfa_login_, okapi_add_operation_for_auth_totp_confirm_, okapi_add_operation_for_auth_totp_delete_, okapi_add_operation_for_auth_totp_enroll_, okapi_add_operation_for_auth_totp_list_};
+use crate::api::monitoring::health::{health, okapi_add_operation_for_health_, okapi_add_operation_for_ready_, ready};
+use crate::api::monitoring::metrics::{metrics, okapi_add_operation_for_metrics_};
+use crate::api::share::accept::{okapi_add_operation_for_share_accept_, share_accept};
+use crate::cli::cli::{run_admin_command, Cli, Command};
+use crate::database::database::{get_connection, get_connection_pool, DBPool};
+use crate::jobs::jobs::start_jobs;
+use crate::mailing::locales::check_email_templates;
+use crate::mailing::mailer::{start_outbox_worker, TEMPLATES};
+use crate::mailing::transport::{build_mail_transport, MailTransport, MemoryTransport};
+use crate::utils::errors_catcher::{bad_request, internal_error, not_found, too_many_requests, unauthorized, unprocessable_entity};
+use crate::utils::config::{config, init_config, Config, MailTransportKind};
+use crate::utils::logging::{init_logging, traced_routes, RequestTracing};
+use crate::utils::metrics::init_metrics;
+use crate::utils::oidc::{OidcLogins, OidcProviders};
+use crate::utils::rate_limit::{MemoryRateLimitStore, RateLimiter};
+use crate::utils::webauthn::{build_webauthn, WebauthnChallenges};
 use crate::utils::utils::{get_backend_host, get_frontend_host};
+use clap::Parser;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use dotenvy::dotenv;
+use rocket::fairing::AdHoc;
 use rocket::http::Method;
+use rocket::config::LogLevel;
+use rocket::{Build, Rocket};
 use rocket_cors::{AllowedHeaders, AllowedOrigins, Cors, CorsOptions};
 use rocket_okapi::openapi_get_routes;
 use rocket_okapi::rapidoc::{make_rapidoc, GeneralConfig, HideShowConfig, RapiDocConfig};
 use rocket_okapi::settings::UrlObject;
 use rocket_okapi::swagger_ui::{make_swagger_ui, SwaggerUIConfig};
+use std::sync::Arc;
+use tracing::{error, info, warn};
 use user_agent_parser::UserAgentParser;
 
 mod api {
+    pub mod account {
+        pub mod delete;
+        pub mod credentials;
+        pub mod security;
+        pub mod preferences;
+    }
+
     pub mod admin {
         pub mod admin;
     }
@@ -29,6 +67,20 @@ mod api {
         pub mod signin;
         pub mod status;
         pub mod confirm;
+        pub mod totp;
+        pub mod sessions;
+        pub mod password;
+        pub mod passkey;
+        pub mod oidc;
+        pub mod api_tokens;
+    }
+
+    pub mod monitoring {
+        pub mod health;
+        pub mod metrics;
+    }
+    pub mod share {
+        pub mod accept;
     }
 }
 mod database {
@@ -37,6 +89,8 @@ mod database {
     pub mod schema;
     pub mod user;
     pub mod auth_token;
+    pub mod audit;
+    pub mod email_outbox;
     pub mod tags;
     pub mod picture;
     pub mod group;
@@ -56,30 +110,97 @@ mod utils {
     pub mod errors_catcher;
     pub mod validation;
     pub mod auth;
+    pub mod rate_limit;
+    pub mod webauthn;
+    pub mod oidc;
+    pub mod logging;
+    pub mod config;
+    pub mod metrics;
 }
 mod mailing {
     pub mod mailer;
+    pub mod locales;
+    pub mod transport;
+}
+mod jobs {
+    pub mod jobs;
+}
+mod cli {
+    pub mod cli;
 }
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
 
 /// Entry point of Archypix app backend
-#[launch]
-#[tokio::main]
-async fn rocket() -> _ {
+/// Launches the API, or runs an admin command (see [`Cli`]).
+#[rocket::main]
+async fn main() {
     dotenv().ok();
+    init_logging();
+    match Config::load() {
+        Ok(config) => init_config(config),
+        Err(e) => {
+            error!(error = %e, "Invalid configuration");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(Command::Admin { command }) = Cli::parse().command {
+        std::process::exit(run_admin_command(command));
+    }
+
+    // check that every email renders in every locale
+    if let Err(e) = check_email_templates(&TEMPLATES) {
+        error!(error = %e, "Invalid email templates");
+        std::process::exit(1);
+    }
 
     // migrate database
     let mut conn = get_connection();
     let res = conn.run_pending_migrations(MIGRATIONS).unwrap();
-    println!("Migrations result: {:?}", res);
+    info!(migrations = ?res, "Database migrated");
+
+    if let Err(e) = rocket().launch().await {
+        error!(error = ?e, "Rocket failed to launch");
+    }
+}
 
-    rocket::build()
+/// Builds the Rocket instance serving the API, with the mail transport of the configuration.
+/// The `memory` transport is also managed as `Arc<MemoryTransport>`, for integration tests to read the sent emails.
+fn rocket() -> Rocket<Build> {
+    if config().mail_transport == MailTransportKind::Memory {
+        let transport = Arc::new(MemoryTransport::default());
+        return build_rocket(Some(transport.clone() as Arc<dyn MailTransport>)).manage(transport);
+    }
+    build_rocket(build_mail_transport(config()))
+}
+
+/// Builds the Rocket instance serving the API, sending the emails with `mail_transport`
+/// (e.g. a [`MemoryTransport`](crate::mailing::transport::MemoryTransport) in integration tests).
+/// Emails stay in the outbox if there is no mail transport.
+fn build_rocket(mail_transport: Option<Arc<dyn MailTransport>>) -> Rocket<Build> {
+    init_metrics();
+    // Rocket logs are collected by the tracing subscriber (see `init_logging`) instead of Rocket's own logger
+    rocket::custom(rocket::Config::figment().merge(("log_level", LogLevel::Off)))
+        .attach(RequestTracing)
         .attach(cors_options())
+        .attach(AdHoc::on_liftoff("Background jobs", move |rocket| Box::pin(async move {
+            let db = rocket.state::<DBPool>().unwrap();
+            start_jobs(db.clone());
+            match mail_transport {
+                Some(mail_transport) => start_outbox_worker(db.clone(), mail_transport),
+                None => warn!("No mail transport configured, emails will stay in the outbox"),
+            }
+        })))
         .manage(get_connection_pool())
+        .manage(RateLimiter::new(MemoryRateLimitStore::default()))
+        .manage(build_webauthn())
+        .manage(WebauthnChallenges::default())
+        .manage(OidcProviders::from_config(config()))
+        .manage(OidcLogins::default())
         .manage(UserAgentParser::from_path("./static/user_agent_regexes.yaml").unwrap())
-        .mount("/", openapi_get_routes![auth_signup, auth_signin, auth_signin_email, auth_status, auth_confirm_code, auth_confirm_token])
-        .register("/", catchers![bad_request, unauthorized, not_found, unprocessable_entity, internal_error])
+        .mount("/", traced_routes(openapi_get_routes![auth_signup, auth_signin, auth_signin_email, auth_status, auth_confirm_code, auth_confirm_token, auth_totp_enroll, auth_totp_confirm, auth_totp_list, auth_totp_delete, auth_tfa_login, auth_recovery_codes_status, auth_recovery_codes_regenerate, auth_passkey_register_start, auth_passkey_register_finish, auth_passkey_list, auth_passkey_delete, auth_passkey_signin_start, auth_passkey_passwordless_start, auth_passkey_passwordless_finish, auth_oidc_providers, auth_oidc_start, auth_oidc_callback, auth_api_tokens_create, auth_api_tokens_list, auth_api_tokens_delete, auth_sessions, auth_sessions_delete, auth_sessions_signout_others, auth_sessions_revoke, auth_signout, auth_password_forgot, auth_password_reset, account_delete, account_delete_cancel, account_password_change, account_email_change, account_security, account_locale, admin_users, admin_user, admin_user_ban, admin_user_unban, admin_user_storage_limit, admin_user_signout, admin_user_resend_confirmation, admin_audit_events, admin_emails_failed, admin_email_retry, share_accept, health, ready, metrics]))
+        .register("/", catchers![bad_request, unauthorized, not_found, unprocessable_entity, too_many_requests, internal_error])
         .mount(
             "/swagger-ui/",
             make_swagger_ui(&SwaggerUIConfig {
@@ -121,6 +242,67 @@ fn cors_options() -> Cors {
         .expect("Error while building CORS")
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::asynchronous::Client;
+    use serde_json::{json, Value};
+    use std::sync::Once;
+    use std::time::Duration;
 
+    static INIT: Once = Once::new();
 
+    /// Loads the configuration from the environment with the `memory` mail transport, and migrates the test database.
+    fn init_test_environment() {
+        INIT.call_once(|| {
+            dotenv().ok();
+            std::env::set_var("MAIL_TRANSPORT", "memory");
+            init_config(Config::load().expect("Invalid test configuration"));
+            get_connection().run_pending_migrations(MIGRATIONS).expect("Failed to migrate the test database");
+        });
+    }
 
+    /// Waits for the outbox worker to send an email to `address`, and extracts its 4-digit code.
+    async fn wait_for_code(transport: &MemoryTransport, address: &str) -> u16 {
+        for _ in 0..50 {
+            if let Some(email) = transport.last_email_to(address) {
+                let code = email.body_text.split("code:").nth(1).expect("No code in the email")
+                    .split_whitespace().next().expect("No code in the email");
+                return code.parse().expect("Invalid code in the email");
+            }
+            rocket::tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        panic!("No email sent to {}", address);
+    }
+
+    #[rocket::async_test]
+    #[ignore = "requires a MySQL database (DATABASE_URL)"]
+    async fn signup_is_confirmed_with_the_emailed_code() {
+        init_test_environment();
+        let client = Client::tracked(rocket()).await.unwrap();
+        let transport = client.rocket().state::<Arc<MemoryTransport>>().expect("Memory transport not managed").clone();
+        let email = format!("signup-{}@archypix.com", chrono::Utc::now().timestamp_micros());
+
+        let response = client.post("/auth/signup")
+            .header(ContentType::JSON)
+            .body(json!({"name": "Test user", "email": email, "password": "Password123"}).to_string())
+            .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let signup: Value = response.into_json().await.unwrap();
+        let user_id = signup["user_id"].as_u64().unwrap();
+
+        let code = wait_for_code(&transport, &email).await;
+
+        let response = client.post("/auth/confirm/code")
+            .header(ContentType::JSON)
+            .header(Header::new("X-User-Id", user_id.to_string()))
+            .body(json!({"action": "Signup", "code_token": signup["code_token"], "code": code}).to_string())
+            .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let confirm: Value = response.into_json().await.unwrap();
+        assert_eq!(confirm["user_id"].as_u64(), Some(user_id));
+        assert_eq!(confirm["email"].as_str(), Some(email.as_str()));
+        assert!(!confirm["auth_token"].as_str().unwrap().is_empty());
+    }
+}
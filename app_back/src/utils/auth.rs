@@ -9,11 +9,74 @@ use rocket_okapi::okapi::openapi3::{Parameter, ParameterValue, SecurityRequireme
 use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
 use user_agent_parser::{Device, Engine, OS};
 
-use crate::database::auth_token::AuthToken;
+use crate::database::auth_token::{ApiScope, ApiToken, AuthToken};
 use crate::database::database::DBPool;
 use crate::database::schema::*;
 use crate::database::user::User;
 use crate::utils::errors_catcher::{ErrorResponder, ErrorType};
+use crate::utils::logging::RequestSpan;
+use crate::utils::config::config;
+use crate::utils::utils::constant_time_eq;
+use tracing::warn;
+
+/// Result of the authentication of a request from the headers X-User-Id and X-Auth-Token.
+/// It is cached in the request to be shared between the [`User`] and [`AuthToken`] request guards.
+enum Authentication {
+    LoggedIn(User, AuthToken),
+    UserNotFound,
+    UserUnconfirmed,
+    UserBanned,
+    SessionExpired,
+}
+impl Authentication {
+    /// Authenticates the request once, and returns the cached result for subsequent calls.
+    /// Deletes the auth token if it is expired, otherwise updates its last use date.
+    async fn from_request<'r>(request: &'r Request<'_>) -> &'r Authentication {
+        request.local_cache_async(async {
+            let user_id = User::get_id_from_headers(request);
+            let auth_token = AuthToken::get_auth_token_from_headers(request);
+            if user_id.is_none() || auth_token.is_none() {
+                return Authentication::UserNotFound;
+            }
+
+            let db: &DBPool = request.rocket().state::<DBPool>().unwrap();
+            let conn = &mut db.get().unwrap();
+
+            let result = User::find_logged_in_opt(conn, user_id.unwrap(), auth_token.unwrap());
+
+            if let Some((user, auth)) = result.ok().flatten() {
+                if user.status == UserStatus::Unconfirmed {
+                    return Authentication::UserUnconfirmed;
+                }
+                if user.status == UserStatus::Banned {
+                    return Authentication::UserBanned;
+                }
+                if auth.is_expired() {
+                    let _ = auth.delete(conn);
+                    return Authentication::SessionExpired;
+                }
+
+                // The response stays successful if the last use date cannot be updated
+                if let Err(e) = auth.update_last_use_date(conn) {
+                    warn!(user_id = user.id, session_id = auth.id, error = ?e, "Failed to update the auth token last use date");
+                }
+                RequestSpan::record_user_id(request, user.id);
+                return Authentication::LoggedIn(user, auth);
+            }
+            Authentication::UserNotFound
+        }).await
+    }
+    /// Converts a failed authentication to a request guard error outcome.
+    fn error_outcome<T>(&self) -> Outcome<T, ErrorResponder> {
+        let error = match self {
+            Authentication::UserUnconfirmed => ErrorType::UserUnconfirmed,
+            Authentication::UserBanned => ErrorType::UserBanned,
+            Authentication::SessionExpired => ErrorType::SessionExpired,
+            _ => ErrorType::UserNotFound,
+        };
+        Outcome::Error((Status::Unauthorized, error.res()))
+    }
+}
 
 /// Request Guard for an authenticated user that is not banned nor unconfirmed.
 /// Uses the headers X-User-Id and X-Auth-Token, return the user object.
@@ -21,37 +84,29 @@ use crate::utils::errors_catcher::{ErrorResponder, ErrorType};
 /// - Throw `UserNotFound` if the credentials are invalid.
 /// - Throw `UserUnconfirmed` if the user is unconfirmed (account not email verified).
 /// - Throw `UserBanned` if the user is banned.
+/// - Throw `SessionExpired` if the auth token exceeded its absolute or idle lifetime.
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for User {
     type Error = ErrorResponder;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let user_id = User::get_id_from_headers(request);
-        let auth_token = AuthToken::get_auth_token_from_headers(request);
-        if user_id.is_none() || auth_token.is_none() {
-            return Outcome::Error((Status::Unauthorized, ErrorType::UserNotFound.res()));
+        match Authentication::from_request(request).await {
+            Authentication::LoggedIn(user, _) => Outcome::Success(user.clone()),
+            failure => failure.error_outcome(),
         }
+    }
+}
+/// Request Guard for the auth token (session) used to authenticate the request.
+/// Same behaviour and errors as the [`User`] request guard.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthToken {
+    type Error = ErrorResponder;
 
-        let db: &DBPool = request.rocket().state::<DBPool>().unwrap();
-        let conn = &mut db.get().unwrap();
-
-        let result = User::find_logged_in_opt(conn, user_id.unwrap(), auth_token.unwrap());
-
-        if let Some((user, auth)) = result.ok().flatten() {
-            if user.status == UserStatus::Unconfirmed {
-                return Outcome::Error((Status::Unauthorized, ErrorType::UserUnconfirmed.res()));
-            }
-            if user.status == UserStatus::Banned {
-                return Outcome::Error((Status::Unauthorized, ErrorType::UserBanned.res()));
-            }
-
-            let result = auth.update_last_use_date(conn);
-            if result.is_err() {
-                // TODO: log the error but keep the response as successful
-            }
-            return Outcome::Success(user);
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match Authentication::from_request(request).await {
+            Authentication::LoggedIn(_, auth) => Outcome::Success(auth.clone()),
+            failure => failure.error_outcome(),
         }
-        Outcome::Error((Status::Unauthorized, ErrorType::UserNotFound.res()))
     }
 }
 /// OpenAPI documentation for the User request guard.
@@ -75,6 +130,143 @@ impl OpenApiFromRequest<'_> for User {
             requirement))
     }
 }
+/// OpenAPI documentation for the AuthToken request guard, same as the [`User`] one.
+impl OpenApiFromRequest<'_> for AuthToken {
+    fn from_request_input(gen: &mut OpenApiGenerator, name: String, required: bool) -> rocket_okapi::Result<RequestHeaderInput> {
+        <User as OpenApiFromRequest>::from_request_input(gen, name, required)
+    }
+}
+/// Request Guard accepting either a session (X-User-Id and X-Auth-Token headers, see [`User`])
+/// or a personal API token (X-API-Token header).
+/// A session grants all the scopes, an API token only the scopes it was created with:
+/// endpoints must check the scope they need with [`ApiUser::require_scope`].
+/// - Throw `UserNotFound` if the credentials are invalid.
+/// - Throw `UserUnconfirmed` if the user is unconfirmed (account not email verified).
+/// - Throw `UserBanned` if the user is banned.
+/// - Throw `SessionExpired` if the session or the API token is expired.
+pub struct ApiUser {
+    pub user: User,
+    /// Bit field of [`ApiScope`]
+    scopes: u8,
+}
+impl ApiUser {
+    /// - Throw `InsufficientScope` if the request is authenticated with an API token lacking the scope.
+    pub fn require_scope(&self, scope: ApiScope) -> Result<(), ErrorResponder> {
+        if self.scopes & scope.bit() == 0 {
+            return ErrorType::InsufficientScope.res_err();
+        }
+        Ok(())
+    }
+}
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiUser {
+    type Error = ErrorResponder;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let api_token = match ApiToken::get_api_token_from_headers(request) {
+            Some(api_token) => api_token,
+            None => {
+                return match Authentication::from_request(request).await {
+                    Authentication::LoggedIn(user, _) => Outcome::Success(ApiUser { user: user.clone(), scopes: ApiScope::ALL }),
+                    failure => failure.error_outcome(),
+                };
+            }
+        };
+
+        let db: &DBPool = request.rocket().state::<DBPool>().unwrap();
+        let conn = &mut db.get().unwrap();
+
+        let token = match ApiToken::find_from_token_opt(conn, &api_token) {
+            Ok(Some(token)) => token,
+            _ => return Authentication::UserNotFound.error_outcome(),
+        };
+        if token.is_expired() {
+            return Authentication::SessionExpired.error_outcome();
+        }
+        let user = match User::from_id_opt(conn, &token.user_id) {
+            Ok(Some(user)) => user,
+            _ => return Authentication::UserNotFound.error_outcome(),
+        };
+        match user.status {
+            UserStatus::Unconfirmed => return Authentication::UserUnconfirmed.error_outcome(),
+            UserStatus::Banned => return Authentication::UserBanned.error_outcome(),
+            _ => {}
+        }
+
+        // The response stays successful if the last use date cannot be updated
+        if let Err(e) = token.update_last_use_date(conn) {
+            warn!(user_id = user.id, api_token_id = token.id, error = ?e, "Failed to update the API token last use date");
+        }
+        RequestSpan::record_user_id(request, user.id);
+        Outcome::Success(ApiUser { user, scopes: token.scopes })
+    }
+}
+/// OpenAPI documentation for the ApiUser request guard.
+/// rocket_okapi supports adding only a single header requirement for a request guard,
+/// then this one only requires the X-API-Token header (see [`User`] for session authentication).
+impl OpenApiFromRequest<'_> for ApiUser {
+    fn from_request_input(_: &mut OpenApiGenerator, _: String, _: bool) -> rocket_okapi::Result<RequestHeaderInput> {
+        let mut requirement = SecurityRequirement::new();
+        requirement.insert("X-API-Token".to_string(), Vec::new());
+        Ok(RequestHeaderInput::Security(
+            "X-API-Token".to_string(),
+            SecurityScheme {
+                description: Some("Requires a valid personal API token (X-API-Token), or a session: user id (X-User-Id) and auth token (X-Auth-Token).".to_string()),
+                data: SecuritySchemeData::ApiKey {
+                    name: "X-API-Token".to_string(),
+                    location: "header".to_string(),
+                },
+                extensions: Default::default(),
+            },
+            requirement))
+    }
+}
+/// Request Guard for an admin user, authenticated with a session or an API token having the `Admin` scope.
+/// Same behaviour and errors as the [`ApiUser`] request guard.
+/// - Throw `UserNotAdmin` if the user is not an admin, or the API token lacks the `Admin` scope.
+pub struct AdminUser {
+    pub user: User,
+}
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = ErrorResponder;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let api_user = match ApiUser::from_request(request).await {
+            Outcome::Success(api_user) => api_user,
+            Outcome::Error(error) => return Outcome::Error(error),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        if api_user.user.status != UserStatus::Admin || api_user.require_scope(ApiScope::Admin).is_err() {
+            return Outcome::Error((Status::Unauthorized, ErrorType::UserNotAdmin.res()));
+        }
+        Outcome::Success(AdminUser { user: api_user.user })
+    }
+}
+/// OpenAPI documentation for the AdminUser request guard, same as the [`ApiUser`] one.
+impl OpenApiFromRequest<'_> for AdminUser {
+    fn from_request_input(gen: &mut OpenApiGenerator, name: String, required: bool) -> rocket_okapi::Result<RequestHeaderInput> {
+        <ApiUser as OpenApiFromRequest>::from_request_input(gen, name, required)
+    }
+}
+/// Request Guard checking the `Authorization: Bearer <token>` header against the `METRICS_TOKEN` configuration.
+/// The metrics are not exposed (404) when `METRICS_TOKEN` is not set.
+pub struct MetricsAuth;
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsAuth {
+    type Error = ErrorResponder;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(metrics_token) = &config().metrics_token else {
+            return Outcome::Error((Status::NotFound, ErrorType::NotFound(request.uri().to_string()).res()));
+        };
+        let token = request.headers().get_one("Authorization").and_then(|header| header.strip_prefix("Bearer "));
+        match token {
+            Some(token) if constant_time_eq(token.as_bytes(), metrics_token.as_bytes()) => Outcome::Success(MetricsAuth),
+            _ => Outcome::Error((Status::Unauthorized, ErrorType::Unauthorized.res())),
+        }
+    }
+}
 /// Request Guard with the only purpose of extracting the user id and auth token from the headers.
 pub struct UserAuthInfo {
     pub user_id: Option<u32>,
@@ -175,6 +367,41 @@ impl OpenApiFromRequest<'_> for DeviceInfo {
     }
 }
 
+/// Request Guard extracting the preferred supported locale from the `Accept-Language` header (English by default).
+pub struct AcceptLanguage(pub Locale);
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptLanguage {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let locale = request.headers().get_one("Accept-Language")
+            .and_then(Locale::from_accept_language)
+            .unwrap_or_default();
+        Outcome::Success(AcceptLanguage(locale))
+    }
+}
+/// OpenAPI documentation for the AcceptLanguage request guard.
+impl OpenApiFromRequest<'_> for AcceptLanguage {
+    fn from_request_input(gen: &mut OpenApiGenerator, _: String, _: bool) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::Parameter(Parameter {
+            name: "Accept-Language".to_string(),
+            location: "header".to_string(),
+            description: Some("Preferred languages, used to choose the language of the emails".to_string()),
+            required: false,
+            deprecated: false,
+            allow_empty_value: false,
+            value: ParameterValue::Schema {
+                style: None,
+                explode: None,
+                allow_reserved: false,
+                schema: gen.json_schema::<String>(),
+                example: None,
+                examples: None,
+            },
+            extensions: Default::default(),
+        }))
+    }
+}
+
 /// Helper function to create a device string from the device, os and engine information.
 fn device_str(device: Device, os: OS, engine: Engine) -> String {
     let mut device_str = String::new();
@@ -0,0 +1,236 @@
+use rocket::figment::providers::{Env, Format, Toml};
+use rocket::figment::Figment;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tracing::warn;
+use webauthn_rs::prelude::Url;
+
+/// Configuration keys, read from the environment variables of the same name in uppercase (e.g. `DATABASE_URL`),
+/// which take precedence over the TOML file.
+const CONFIG_KEYS: [&str; 18] = [
+    "database_url",
+    "frontend_host",
+    "backend_host",
+    "token_hash_key",
+    "mail_transport",
+    "mail_directory",
+    "smtp_server",
+    "smtp_tls",
+    "smtp_server_port",
+    "smtp_from_name",
+    "smtp_username",
+    "smtp_password",
+    "totp_skew_steps",
+    "auth_token_max_age_days",
+    "auth_token_idle_days",
+    "metrics_token",
+    "storage_path",
+    "oidc_providers",
+];
+
+/// Settings of an OIDC provider `<name>`, read from the `[oidc.<name>]` TOML table, or from the
+/// `OIDC_<NAME>_ISSUER`, `OIDC_<NAME>_CLIENT_ID` and `OIDC_<NAME>_CLIENT_SECRET` environment variables.
+const OIDC_PROVIDER_KEYS: [&str; 3] = ["issuer", "client_id", "client_secret"];
+
+/// Length in bytes of `TOKEN_HASH_KEY`, the key size of HMAC-SHA256
+const TOKEN_HASH_KEY_LENGTH: usize = 32;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Configuration of the backend, loaded and validated once at startup (see [`Config::load`]).
+/// Available everywhere (endpoints, jobs, CLI) through [`config`], its single source.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    pub database_url: String,
+    /// Frontend URL, used in the emails links and for the CORS policy
+    pub frontend_host: String,
+    /// Backend URL, used for the CORS policy
+    pub backend_host: String,
+    /// Secret key used to hash the tokens stored in database (hex encoded)
+    pub token_hash_key: String,
+    /// Way of delivering the emails
+    #[serde(default = "default_mail_transport")]
+    pub mail_transport: MailTransportKind,
+    /// Directory where the emails are written with the `file` mail transport
+    #[serde(default = "default_mail_directory")]
+    pub mail_directory: PathBuf,
+    pub smtp_server: Option<String>,
+    #[serde(default = "default_smtp_server_port")]
+    pub smtp_server_port: u16,
+    #[serde(default = "default_smtp_tls")]
+    pub smtp_tls: SmtpTls,
+    pub smtp_from_name: Option<String>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    /// Number of TOTP time steps accepted before and after the current one
+    #[serde(default = "default_totp_skew_steps")]
+    pub totp_skew_steps: u8,
+    /// Absolute lifetime of an auth token, in days
+    #[serde(default = "default_auth_token_max_age_days")]
+    pub auth_token_max_age_days: i64,
+    /// Idle lifetime of an auth token (maximum time between two uses), in days
+    #[serde(default = "default_auth_token_idle_days")]
+    pub auth_token_idle_days: i64,
+    /// Bearer token required by `/metrics`, the endpoint is disabled if not set
+    pub metrics_token: Option<String>,
+    /// Directory where the pictures are stored
+    #[serde(default = "default_storage_path")]
+    pub storage_path: PathBuf,
+    /// Names of the enabled OIDC providers (comma separated), each one must be configured in `oidc`
+    #[serde(default)]
+    pub oidc_providers: String,
+    /// Settings of the OIDC providers, by name
+    #[serde(default)]
+    pub oidc: HashMap<String, OidcProviderConfig>,
+}
+
+fn default_mail_transport() -> MailTransportKind { MailTransportKind::Smtp }
+fn default_mail_directory() -> PathBuf { PathBuf::from("./emails") }
+fn default_smtp_server_port() -> u16 { 587 }
+fn default_smtp_tls() -> SmtpTls { SmtpTls::Starttls }
+fn default_totp_skew_steps() -> u8 { 1 }
+fn default_auth_token_max_age_days() -> i64 { 90 }
+fn default_auth_token_idle_days() -> i64 { 14 }
+fn default_storage_path() -> PathBuf { PathBuf::from("./storage") }
+
+/// Mail transports, see [`crate::mailing::transport`].
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransportKind {
+    /// Sends the emails through the configured SMTP server
+    Smtp,
+    /// Writes the emails as `.eml` files in `mail_directory`, for development
+    File,
+    /// Keeps the emails in memory, for integration tests
+    Memory,
+}
+
+/// TLS mode of the SMTP connection.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// TLS from the start of the connection (usually port 465)
+    Implicit,
+    /// Upgrade of a plain connection with STARTTLS (usually port 587)
+    Starttls,
+    /// Plain connection, only for local development servers
+    None,
+}
+
+/// Settings of an external OpenID Connect provider.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct OidcProviderConfig {
+    #[serde(default)]
+    pub issuer: String,
+    #[serde(default)]
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: String,
+}
+
+/// SMTP settings used by the mailer, only available when all of them are configured.
+pub struct SmtpConfig<'a> {
+    pub server: &'a str,
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub from_name: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+}
+
+impl Config {
+    /// Loads the configuration from the TOML file at `CONFIG_FILE` (defaults to `config.toml`, ignored if missing),
+    /// overridden by the environment variables, and validates it.
+    pub fn load() -> Result<Config, String> {
+        let file = env::var("CONFIG_FILE").unwrap_or("config.toml".to_string());
+        let config: Config = Figment::new()
+            .merge(Toml::file(&file))
+            .merge(Env::raw().only(&CONFIG_KEYS))
+            .merge(Env::prefixed("OIDC_").filter_map(|key| oidc_env_key(key.as_str()).map(Into::into)))
+            .extract()
+            .map_err(|e| e.to_string())?;
+        config.validate()
+    }
+
+    /// Checks the values that would otherwise only fail when used, and treats empty optional values as unset.
+    fn validate(mut self) -> Result<Config, String> {
+        for (key, value) in [("DATABASE_URL", &self.database_url), ("FRONTEND_HOST", &self.frontend_host), ("BACKEND_HOST", &self.backend_host)] {
+            if value.is_empty() {
+                return Err(format!("{} must be set", key));
+            }
+        }
+        let frontend_url = Url::parse(&self.frontend_host).map_err(|e| format!("FRONTEND_HOST must be a valid URL: {}", e))?;
+        if frontend_url.host_str().is_none() {
+            return Err("FRONTEND_HOST must have a host".to_string());
+        }
+        if hex::decode(&self.token_hash_key).map_or(true, |key| key.len() != TOKEN_HASH_KEY_LENGTH) {
+            return Err(format!("TOKEN_HASH_KEY must be {} bytes, hex encoded (e.g. openssl rand -hex {})", TOKEN_HASH_KEY_LENGTH, TOKEN_HASH_KEY_LENGTH));
+        }
+        if self.auth_token_max_age_days <= 0 || self.auth_token_idle_days <= 0 {
+            return Err("AUTH_TOKEN_MAX_AGE_DAYS and AUTH_TOKEN_IDLE_DAYS must be positive".to_string());
+        }
+
+        let names: Vec<String> = self.oidc_providers.split(',')
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        self.oidc = self.oidc.into_iter().map(|(name, provider)| (name.to_lowercase(), provider)).collect();
+        for name in &names {
+            let provider = self.oidc.get(name).ok_or_else(|| format!("OIDC provider {} is enabled but not configured", name))?;
+            let prefix = format!("OIDC_{}", name.to_uppercase());
+            for (suffix, value) in [("ISSUER", &provider.issuer), ("CLIENT_ID", &provider.client_id), ("CLIENT_SECRET", &provider.client_secret)] {
+                if value.is_empty() {
+                    return Err(format!("{}_{} must be set", prefix, suffix));
+                }
+            }
+            Url::parse(&provider.issuer).map_err(|e| format!("{}_ISSUER must be a valid URL: {}", prefix, e))?;
+        }
+        self.oidc.retain(|name, _| names.contains(name));
+        self.oidc_providers = names.join(",");
+
+        for value in [&mut self.smtp_server, &mut self.smtp_from_name, &mut self.smtp_username, &mut self.smtp_password, &mut self.metrics_token] {
+            if value.as_ref().is_some_and(|value| value.is_empty()) {
+                *value = None;
+            }
+        }
+        let smtp_values = [&self.smtp_server, &self.smtp_from_name, &self.smtp_username, &self.smtp_password];
+        if self.mail_transport == MailTransportKind::Smtp && smtp_values.iter().any(|value| value.is_none()) {
+            warn!("SMTP_SERVER, SMTP_FROM_NAME, SMTP_USERNAME and SMTP_PASSWORD are not all set, emails will not be sent");
+        }
+        Ok(self)
+    }
+
+    /// Gets the SMTP settings, `None` if the sending of emails is not configured.
+    pub fn smtp(&self) -> Option<SmtpConfig> {
+        Some(SmtpConfig {
+            server: self.smtp_server.as_deref()?,
+            port: self.smtp_server_port,
+            tls: self.smtp_tls,
+            from_name: self.smtp_from_name.as_deref()?,
+            username: self.smtp_username.as_deref()?,
+            password: self.smtp_password.as_deref()?,
+        })
+    }
+}
+
+/// Maps an `OIDC_<NAME>_<KEY>` environment variable (without its prefix) to the `oidc.<name>.<key>` configuration key.
+fn oidc_env_key(key: &str) -> Option<String> {
+    let key = key.to_lowercase();
+    OIDC_PROVIDER_KEYS.iter().find_map(|provider_key| {
+        let name = key.strip_suffix(provider_key)?.strip_suffix('_')?;
+        (!name.is_empty()).then(|| format!("oidc.{}.{}", name, provider_key))
+    })
+}
+
+/// Sets the global configuration, must be called once at startup before any call to [`config`].
+pub fn init_config(config: Config) {
+    CONFIG.set(config).expect("The configuration is already initialized");
+}
+
+/// Gets the global configuration.
+pub fn config() -> &'static Config {
+    CONFIG.get().expect("The configuration is not initialized")
+}
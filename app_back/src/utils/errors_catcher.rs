@@ -2,6 +2,7 @@ use crate::database::database::DBConn;
 use diesel::result::Error;
 use diesel::Connection;
 use enum_kinds::EnumKind;
+use rocket::http::Header;
 use rocket::serde::json::Json;
 use rocket::Request;
 use rocket_okapi::gen::OpenApiGenerator;
@@ -11,6 +12,7 @@ use schemars::JsonSchema;
 use serde::Serialize;
 use strum::IntoEnumIterator;
 use strum_macros::{Display, EnumIter};
+use tracing::error;
 
 /// Rocket Responder for all errors
 #[derive(Responder, Debug)]
@@ -23,6 +25,9 @@ pub enum ErrorResponder {
     NotFound(Json<ErrorResponse>),
     #[response(status = 422, content_type = "json")]
     UnprocessableEntity(Json<ErrorResponse>),
+    /// Sent with the `Retry-After` header
+    #[response(status = 429, content_type = "json")]
+    TooManyRequests(Json<ErrorResponse>, Header<'static>),
     #[response(status = 500, content_type = "json")]
     InternalError(Json<ErrorResponse>),
 }
@@ -34,15 +39,24 @@ impl From<Error> for ErrorResponder {
     }
 }
 impl ErrorResponder {
-    /// Extract the rollback boolean value from the inner [`ErrorResponse`] struct.
-    pub fn do_rollback(&self) -> bool {
+    /// Extract the inner [`ErrorResponse`] struct.
+    fn response(&self) -> &ErrorResponse {
         match self {
             ErrorResponder::BadRequest(json) => json,
             ErrorResponder::Unauthorized(json) => json,
             ErrorResponder::NotFound(json) => json,
             ErrorResponder::UnprocessableEntity(json) => json,
+            ErrorResponder::TooManyRequests(json, _) => json,
             ErrorResponder::InternalError(json) => json,
-        }.rollback
+        }
+    }
+    /// Extract the rollback boolean value from the inner [`ErrorResponse`] struct.
+    pub fn do_rollback(&self) -> bool {
+        self.response().rollback
+    }
+    /// Extract the error type from the inner [`ErrorResponse`] struct.
+    pub fn error_type(&self) -> &ErrorTypeKind {
+        &self.response().error_type
     }
 }
 /// Dummy implementation for OpenApi
@@ -69,6 +83,8 @@ pub enum ErrorType {
     Unauthorized,
     NotFound(String),
     UnprocessableEntity,
+    /// Number of seconds before the next attempt is allowed
+    TooManyRequests(u64),
     InternalError(String),
     // Form validation (see UnprocessableEntity for type check related errors)
     InvalidInput(String),
@@ -76,11 +92,27 @@ pub enum ErrorType {
     UserNotFound,
     UserBanned,
     UserUnconfirmed,
+    // Sessions
+    SessionNotFound,
+    SessionExpired,
     // Sign in types
     InvalidEmailOrPassword,
     TFARequiredOverEmail, // Only email confirm available
-    TFARequired, // TOTP or email confirm available
+    TFARequired, // TOTP, passkey or email confirm available
     InvalidTOTPCode,
+    InvalidRecoveryCode,
+    InvalidPasskey,
+    // TOTP management
+    TOTPNotFound,
+    // Passkey management
+    PasskeyNotFound,
+    // API tokens
+    ApiTokenNotFound,
+    InsufficientScope,
+    // OIDC sign in
+    OIDCProviderNotFound,
+    OIDCLoginFailed,
+    OIDCEmailNotVerified,
     // Sign up types
     EmailAlreadyExists,
     // Confirm
@@ -88,8 +120,21 @@ pub enum ErrorType {
     ConfirmationExpired,
     ConfirmationTooManyAttempts,
     ConfirmationNotFound,
+    // Account
+    AccountDeletionNotScheduled,
+    InvalidPassword,
+    ReauthenticationRequired,
+    EmailChangeNotRequested,
     // Admin
     UserNotAdmin,
+    UserAlreadyConfirmed,
+    CannotBanSelf,
+    EmailNotFound,
+    // Pictures and sharing
+    PictureNotFound,
+    ShareNotFound,
+    ShareAlreadyAccepted,
+    StorageLimitExceeded,
     // Database error
     DatabaseError(String, Error),
 }
@@ -121,18 +166,41 @@ impl ErrorType {
             ErrorType::Unauthorized => ErrorResponder::Unauthorized(Self::create_response("Unauthorized".to_string(), kind, rollback)),
             ErrorType::NotFound(path) => ErrorResponder::NotFound(Self::create_response(format!("Not found: {}", path), kind, rollback)),
             ErrorType::UnprocessableEntity => ErrorResponder::UnprocessableEntity(Self::create_response("Unprocessable entity".to_string(), kind, rollback)),
-            ErrorType::InternalError(msg) => ErrorResponder::InternalError(Self::create_response(format!("Internal error: {}", msg).to_string(), kind, rollback)),
+            ErrorType::TooManyRequests(retry_after) => ErrorResponder::TooManyRequests(
+                Self::create_response(format!("Too many attempts, retry in {} seconds", retry_after), kind, rollback),
+                Header::new("Retry-After", retry_after.to_string()),
+            ),
+            ErrorType::InternalError(msg) => {
+                error!(error = %msg, "Internal error");
+                ErrorResponder::InternalError(Self::create_response(format!("Internal error: {}", msg).to_string(), kind, rollback))
+            }
             // Form validation (see UnprocessableEntity for type check related errors)
             ErrorType::InvalidInput(msg) => ErrorResponder::UnprocessableEntity(Self::create_response(msg, kind, rollback)),
             // Sign in / status types
             ErrorType::UserNotFound => ErrorResponder::Unauthorized(Self::create_response("User not found".to_string(), kind, rollback)),
             ErrorType::UserBanned => ErrorResponder::Unauthorized(Self::create_response("User is banned".to_string(), kind, rollback)),
             ErrorType::UserUnconfirmed => ErrorResponder::Unauthorized(Self::create_response("User is not confirmed".to_string(), kind, rollback)),
+            // Sessions
+            ErrorType::SessionNotFound => ErrorResponder::NotFound(Self::create_response("Session not found".to_string(), kind, rollback)),
+            ErrorType::SessionExpired => ErrorResponder::Unauthorized(Self::create_response("Session expired".to_string(), kind, rollback)),
             // Sign in types
             ErrorType::InvalidEmailOrPassword => ErrorResponder::Unauthorized(Self::create_response("Invalid email or password".to_string(), kind, rollback)),
             ErrorType::TFARequiredOverEmail => ErrorResponder::Unauthorized(Self::create_response("2FA required over email".to_string(), kind, rollback)),
             ErrorType::TFARequired => ErrorResponder::Unauthorized(Self::create_response("2FA required".to_string(), kind, rollback)),
             ErrorType::InvalidTOTPCode => ErrorResponder::Unauthorized(Self::create_response("Invalid TOTP code".to_string(), kind, rollback)),
+            ErrorType::InvalidRecoveryCode => ErrorResponder::Unauthorized(Self::create_response("Invalid or already used recovery code".to_string(), kind, rollback)),
+            ErrorType::InvalidPasskey => ErrorResponder::Unauthorized(Self::create_response("Invalid passkey or expired challenge".to_string(), kind, rollback)),
+            // TOTP management
+            ErrorType::TOTPNotFound => ErrorResponder::NotFound(Self::create_response("TOTP authenticator not found".to_string(), kind, rollback)),
+            // Passkey management
+            ErrorType::PasskeyNotFound => ErrorResponder::NotFound(Self::create_response("Passkey not found".to_string(), kind, rollback)),
+            // API tokens
+            ErrorType::ApiTokenNotFound => ErrorResponder::NotFound(Self::create_response("API token not found".to_string(), kind, rollback)),
+            ErrorType::InsufficientScope => ErrorResponder::Unauthorized(Self::create_response("The API token does not have the required scope".to_string(), kind, rollback)),
+            // OIDC sign in
+            ErrorType::OIDCProviderNotFound => ErrorResponder::NotFound(Self::create_response("OIDC provider not found".to_string(), kind, rollback)),
+            ErrorType::OIDCLoginFailed => ErrorResponder::Unauthorized(Self::create_response("OIDC sign in failed or expired".to_string(), kind, rollback)),
+            ErrorType::OIDCEmailNotVerified => ErrorResponder::Unauthorized(Self::create_response("Email not verified by the OIDC provider".to_string(), kind, rollback)),
             // Sign up types
             ErrorType::EmailAlreadyExists => ErrorResponder::Unauthorized(Self::create_response("Email already exists".to_string(), kind, rollback)),
             // Confirm
@@ -140,10 +208,26 @@ impl ErrorType {
             ErrorType::ConfirmationExpired => ErrorResponder::Unauthorized(Self::create_response("Confirmation code/token expired".to_string(), kind, rollback)),
             ErrorType::ConfirmationTooManyAttempts => ErrorResponder::Unauthorized(Self::create_response("Too many attempts".to_string(), kind, rollback)),
             ErrorType::ConfirmationNotFound => ErrorResponder::Unauthorized(Self::create_response("Invalid code/token".to_string(), kind, rollback)),
+            // Account
+            ErrorType::AccountDeletionNotScheduled => ErrorResponder::BadRequest(Self::create_response("No account deletion is scheduled".to_string(), kind, rollback)),
+            ErrorType::InvalidPassword => ErrorResponder::Unauthorized(Self::create_response("Invalid password".to_string(), kind, rollback)),
+            ErrorType::ReauthenticationRequired => ErrorResponder::Unauthorized(Self::create_response("Password or TOTP code required".to_string(), kind, rollback)),
+            ErrorType::EmailChangeNotRequested => ErrorResponder::BadRequest(Self::create_response("No email change is pending".to_string(), kind, rollback)),
             // Admin
             ErrorType::UserNotAdmin => ErrorResponder::Unauthorized(Self::create_response("User is not an admin".to_string(), kind, rollback)),
+            ErrorType::UserAlreadyConfirmed => ErrorResponder::BadRequest(Self::create_response("User is already confirmed".to_string(), kind, rollback)),
+            ErrorType::CannotBanSelf => ErrorResponder::BadRequest(Self::create_response("An admin cannot ban themselves".to_string(), kind, rollback)),
+            ErrorType::EmailNotFound => ErrorResponder::NotFound(Self::create_response("Failed email not found".to_string(), kind, rollback)),
+            // Pictures and sharing
+            ErrorType::PictureNotFound => ErrorResponder::NotFound(Self::create_response("Picture not found".to_string(), kind, rollback)),
+            ErrorType::ShareNotFound => ErrorResponder::NotFound(Self::create_response("Share not found".to_string(), kind, rollback)),
+            ErrorType::ShareAlreadyAccepted => ErrorResponder::BadRequest(Self::create_response("Share already accepted".to_string(), kind, rollback)),
+            ErrorType::StorageLimitExceeded => ErrorResponder::UnprocessableEntity(Self::create_response("Storage limit exceeded".to_string(), kind, rollback)),
             // Database error
-            ErrorType::DatabaseError(msg, err) => ErrorResponder::InternalError(Self::create_response(format!("Database error: {} - {}", msg, err), kind, rollback)),
+            ErrorType::DatabaseError(msg, err) => {
+                error!(context = %msg, error = %err, rollback, "Database error");
+                ErrorResponder::InternalError(Self::create_response(format!("Database error: {} - {}", msg, err), kind, rollback))
+            }
         }
     }
     /// Converts to an [`ErrorResponse`] struct
@@ -170,6 +254,10 @@ pub fn not_found(req: &Request) -> ErrorResponder {
 pub fn unprocessable_entity() -> ErrorResponder {
     ErrorType::UnprocessableEntity.res()
 }
+#[catch(429)]
+pub fn too_many_requests() -> ErrorResponder {
+    ErrorType::TooManyRequests(0).res()
+}
 #[catch(500)]
 pub fn internal_error() -> ErrorResponder {
     ErrorType::InternalError(String::from("Internal Error")).res()
@@ -0,0 +1,102 @@
+use crate::utils::metrics::observe_request;
+use crate::utils::utils::random_token;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::route::{Handler, Outcome, Route};
+use rocket::{Data, Request, Response};
+use std::env;
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::{info, info_span, Instrument, Span};
+use tracing_subscriber::EnvFilter;
+
+/// Initializes the global tracing subscriber, also collecting the `log` records (e.g. from Rocket).
+/// The level is configured with `RUST_LOG` (defaults to `info`), and `LOG_FORMAT=json` switches to JSON output.
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    if env::var("LOG_FORMAT").map(|format| format == "json").unwrap_or(false) {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
+
+/// Span of a request, cached in the request by the [`RequestTracing`] fairing.
+pub struct RequestSpan {
+    pub request_id: String,
+    pub span: Span,
+    start: Instant,
+}
+
+impl RequestSpan {
+    /// Gets the span of the request (a disabled span if the fairing is not attached).
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r RequestSpan {
+        request.local_cache(|| RequestSpan {
+            request_id: String::new(),
+            span: Span::none(),
+            start: Instant::now(),
+        })
+    }
+    /// Records the id of the authenticated user in the span of the request.
+    pub fn record_user_id(request: &Request<'_>, user_id: u32) {
+        RequestSpan::of(request).span.record("user_id", user_id);
+    }
+}
+
+/// Route handler running the wrapped handler inside the span of the request, so that the logs emitted
+/// while handling the request (request guards, database errors...) carry its request id and user id.
+#[derive(Clone)]
+struct TracedHandler(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for TracedHandler {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let span = RequestSpan::of(request).span.clone();
+        self.0.handle(request, data).instrument(span).await
+    }
+}
+
+/// Wraps the handlers of the routes so that they run inside the span of the request (see [`RequestTracing`]).
+pub fn traced_routes(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter().map(|mut route| {
+        route.handler = Box::new(TracedHandler(route.handler));
+        route
+    }).collect()
+}
+
+/// Fairing opening a span per request with a request id (from the `X-Request-Id` header, or generated),
+/// and logging (and recording in the metrics) the route, status and latency of each response.
+/// The request id is sent back in the `X-Request-Id` response header.
+pub struct RequestTracing;
+
+#[rocket::async_trait]
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let request_id = request.headers().get_one("X-Request-Id")
+            .filter(|id| !id.is_empty() && id.len() <= 64)
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| hex::encode(random_token(8)));
+        let span = info_span!("request", request_id = %request_id, method = %request.method(), uri = %request.uri(), user_id = Empty);
+        request.local_cache(|| RequestSpan { request_id, span, start: Instant::now() });
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_span = RequestSpan::of(request);
+        let route = request.route().map(|route| route.uri.to_string()).unwrap_or_default();
+        let latency = request_span.start.elapsed();
+        let latency_ms = latency.as_millis() as u64;
+        observe_request(request.method().as_str(), &route, response.status().code, latency);
+        request_span.span.in_scope(|| {
+            info!(route = %route, status = response.status().code, latency_ms, "Request completed");
+        });
+        response.set_header(Header::new("X-Request-Id", request_span.request_id.clone()));
+    }
+}
@@ -0,0 +1,94 @@
+use crate::database::database::DBPool;
+use lazy_static::lazy_static;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::time::Duration;
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new_custom(Some("archypix".to_string()), None)
+        .expect("Unable to create the metrics registry");
+
+    static ref HTTP_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("http_requests_total", "Number of HTTP requests by route and status"),
+        &["method", "route", "status"],
+    ).unwrap());
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("http_request_duration_seconds", "Latency of the HTTP requests by route and status"),
+        &["method", "route", "status"],
+    ).unwrap());
+    static ref DB_POOL_CONNECTIONS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("db_pool_connections", "Connections of the database pool by state (idle, in_use, max)"),
+        &["state"],
+    ).unwrap());
+    static ref EMAILS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("emails_total", "Number of email sending attempts, by result (success, connect_error, send_error)"),
+        &["result"],
+    ).unwrap());
+    static ref JOBS_RUNNING: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("jobs_running", "Number of background jobs currently running"),
+        &["job"],
+    ).unwrap());
+    static ref JOB_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("job_duration_seconds", "Duration of the background job runs"),
+        &["job"],
+    ).unwrap());
+}
+
+/// Registers all the metrics, so that they are exported before their first observation.
+pub fn init_metrics() {
+    lazy_static::initialize(&HTTP_REQUESTS);
+    lazy_static::initialize(&HTTP_REQUEST_DURATION);
+    lazy_static::initialize(&DB_POOL_CONNECTIONS);
+    lazy_static::initialize(&EMAILS);
+    lazy_static::initialize(&JOBS_RUNNING);
+    lazy_static::initialize(&JOB_DURATION);
+}
+
+/// Registers a metric in [`REGISTRY`], panicking if a metric with the same name is already registered.
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY.register(Box::new(metric.clone())).expect("Unable to register metric");
+    metric
+}
+
+/// Records a handled HTTP request. `route` is the route URI template, keeping the cardinality bounded.
+pub fn observe_request(method: &str, route: &str, status: u16, duration: Duration) {
+    let status = status.to_string();
+    let labels = [method, route, status.as_str()];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_REQUEST_DURATION.with_label_values(&labels).observe(duration.as_secs_f64());
+}
+
+/// Records the result of an email sending attempt (`success`, `connect_error` or `send_error`).
+pub fn observe_email(result: &str) {
+    EMAILS.with_label_values(&[result]).inc();
+}
+
+/// Marks a background job as started, returning a guard recording its duration when dropped.
+pub fn start_job(job: &'static str) -> JobTimer {
+    JOBS_RUNNING.with_label_values(&[job]).inc();
+    JobTimer { job, start: std::time::Instant::now() }
+}
+
+/// Guard returned by [`start_job`].
+pub struct JobTimer {
+    job: &'static str,
+    start: std::time::Instant,
+}
+
+impl Drop for JobTimer {
+    fn drop(&mut self) {
+        JOBS_RUNNING.with_label_values(&[self.job]).dec();
+        JOB_DURATION.with_label_values(&[self.job]).observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+/// Renders all the metrics in the Prometheus text format, refreshing the database pool gauges first.
+pub fn render_metrics(db: &DBPool) -> String {
+    let state = db.state();
+    DB_POOL_CONNECTIONS.with_label_values(&["idle"]).set(state.idle_connections as i64);
+    DB_POOL_CONNECTIONS.with_label_values(&["in_use"]).set((state.connections - state.idle_connections) as i64);
+    DB_POOL_CONNECTIONS.with_label_values(&["max"]).set(db.max_size() as i64);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer).expect("Unable to encode the metrics");
+    String::from_utf8(buffer).expect("Metrics are not valid UTF-8")
+}
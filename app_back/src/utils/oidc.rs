@@ -0,0 +1,83 @@
+use crate::utils::config::Config;
+use crate::utils::utils::get_frontend_host;
+use openidconnect::{Nonce, PkceCodeVerifier};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time allowed to the user to sign in on the provider.
+const LOGIN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// External OpenID Connect provider, enabled by `oidc_providers` and configured in `oidc` (see [`Config`]).
+#[derive(Clone, Debug)]
+pub struct OidcProvider {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl OidcProvider {
+    /// URL of the frontend page receiving the authorization code, to be registered on the provider.
+    pub fn redirect_url(&self) -> String {
+        format!("{}/oidc/{}/callback", get_frontend_host(), self.name)
+    }
+}
+
+/// Configured OIDC providers, managed by Rocket.
+pub struct OidcProviders {
+    providers: HashMap<String, OidcProvider>,
+}
+
+impl OidcProviders {
+    /// Gets the enabled providers of the configuration, already validated by [`Config::load`].
+    pub fn from_config(config: &Config) -> OidcProviders {
+        let providers = config.oidc.iter()
+            .map(|(name, provider)| (name.clone(), OidcProvider {
+                name: name.clone(),
+                issuer: provider.issuer.clone(),
+                client_id: provider.client_id.clone(),
+                client_secret: provider.client_secret.clone(),
+            }))
+            .collect();
+        OidcProviders { providers }
+    }
+    pub fn get(&self, name: &str) -> Option<&OidcProvider> {
+        self.providers.get(name)
+    }
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.providers.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+/// Server side state of a started OIDC login.
+pub struct OidcLogin {
+    pub provider: String,
+    pub nonce: Nonce,
+    pub pkce_verifier: PkceCodeVerifier,
+    pub redirect_url: Option<String>,
+}
+
+/// Started OIDC logins, identified by their CSRF state sent to the provider.
+/// Managed by Rocket, used in endpoints through `&rocket::State<OidcLogins>`.
+#[derive(Default)]
+pub struct OidcLogins {
+    logins: Mutex<HashMap<String, (Instant, OidcLogin)>>,
+}
+
+impl OidcLogins {
+    pub fn insert(&self, state: String, login: OidcLogin) {
+        let mut logins = self.logins.lock().unwrap();
+        logins.retain(|_, (date, _)| date.elapsed() < LOGIN_LIFETIME);
+        logins.insert(state, (Instant::now(), login));
+    }
+    /// Removes and returns the login, if it exists and is not expired.
+    pub fn take(&self, state: &str) -> Option<OidcLogin> {
+        self.logins.lock().unwrap()
+            .remove(state)
+            .filter(|(date, _)| date.elapsed() < LOGIN_LIFETIME)
+            .map(|(_, login)| login)
+    }
+}
@@ -0,0 +1,195 @@
+use crate::utils::auth::DeviceInfo;
+use crate::utils::errors_catcher::{ErrorResponder, ErrorType, ErrorTypeKind};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of failures allowed before the backoff starts.
+const FREE_ATTEMPTS: u32 = 5;
+/// Delay after the first throttled failure, doubled at each subsequent failure.
+const BASE_DELAY: Duration = Duration::from_secs(2);
+/// Maximum delay between two attempts.
+const MAX_DELAY: Duration = Duration::from_secs(15 * 60);
+/// Failures are forgotten after this period without any new failure.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Failures recorded for a rate limit key.
+#[derive(Clone, Debug)]
+pub struct RateLimitEntry {
+    pub failures: u32,
+    pub last_failure: Instant,
+}
+
+impl RateLimitEntry {
+    /// Instant before which no new attempt is allowed, if the backoff started.
+    fn locked_until(&self) -> Option<Instant> {
+        if self.failures < FREE_ATTEMPTS {
+            return None;
+        }
+        let exponent = (self.failures - FREE_ATTEMPTS).min(16);
+        Some(self.last_failure + (BASE_DELAY * 2u32.pow(exponent)).min(MAX_DELAY))
+    }
+}
+
+/// Storage of the rate limit entries.
+/// The in-memory store is only valid for a single backend instance, a shared store
+/// (e.g. backed by Redis or the database) can be used instead by implementing this trait.
+pub trait RateLimitStore: Send + Sync {
+    /// Atomically checks that none of the keys is throttled and records an attempt, counted as a failure, on all of them,
+    /// so that concurrent attempts cannot all pass the check before their failures are recorded.
+    /// Returns the instant before which no new attempt is allowed if one of the keys is throttled, recording nothing.
+    fn reserve(&self, keys: &[String]) -> Result<(), Instant>;
+    /// Cancels the attempt reserved on the key, which did not fail.
+    fn release(&self, key: &str);
+    fn reset(&self, key: &str);
+}
+
+/// Process-local [`RateLimitStore`].
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    entries: Mutex<HashMap<String, RateLimitEntry>>,
+}
+
+impl MemoryRateLimitStore {
+    /// Number of entries above which forgotten entries are purged when recording a failure.
+    const PURGE_THRESHOLD: usize = 10_000;
+}
+
+impl RateLimitStore for MemoryRateLimitStore {
+    fn reserve(&self, keys: &[String]) -> Result<(), Instant> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() > Self::PURGE_THRESHOLD {
+            entries.retain(|_, entry| entry.last_failure.elapsed() < FORGET_AFTER);
+        }
+        let now = Instant::now();
+        let locked_until = keys.iter()
+            .filter_map(|key| entries.get(key))
+            .filter(|entry| entry.last_failure.elapsed() < FORGET_AFTER)
+            .filter_map(|entry| entry.locked_until())
+            .filter(|locked_until| *locked_until > now)
+            .max();
+        if let Some(locked_until) = locked_until {
+            return Err(locked_until);
+        }
+        for key in keys {
+            let entry = entries.entry(key.clone()).or_insert(RateLimitEntry { failures: 0, last_failure: now });
+            if entry.last_failure.elapsed() >= FORGET_AFTER {
+                entry.failures = 0;
+            }
+            entry.failures += 1;
+            entry.last_failure = now;
+        }
+        Ok(())
+    }
+    fn release(&self, key: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+            entry.failures = entry.failures.saturating_sub(1);
+        }
+    }
+    fn reset(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+/// Key on which attempts of an action are throttled.
+pub enum RateLimitKey {
+    /// Attempts from an IP address, never reset by a success
+    /// (a successful attempt on an account must not unlock the attempts on other accounts).
+    Ip(&'static str, String),
+    /// Attempts on an account (user id or email), reset by a success.
+    Account(&'static str, String),
+}
+
+impl RateLimitKey {
+    /// Per-IP key of an action, or None if the IP address is unknown.
+    pub fn ip(action: &'static str, device_info: &DeviceInfo) -> Option<RateLimitKey> {
+        device_info.ip_address.clone().map(|ip| RateLimitKey::Ip(action, ip))
+    }
+    pub fn account(action: &'static str, account: impl ToString) -> RateLimitKey {
+        RateLimitKey::Account(action, account.to_string())
+    }
+    /// Per-account key of an action identified by an email, normalized (trimmed and lowercased)
+    /// so that variants of the same email share the same counter.
+    pub fn email(action: &'static str, email: &str) -> RateLimitKey {
+        RateLimitKey::Account(action, email.trim().to_lowercase())
+    }
+    fn to_key(&self) -> String {
+        match self {
+            RateLimitKey::Ip(action, ip) => format!("{}:ip:{}", action, ip),
+            RateLimitKey::Account(action, account) => format!("{}:account:{}", action, account),
+        }
+    }
+}
+
+/// Throttles authentication attempts with an exponential backoff.
+/// Managed by Rocket, used in endpoints through `&rocket::State<RateLimiter>`.
+pub struct RateLimiter {
+    store: Box<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    pub fn new(store: impl RateLimitStore + 'static) -> RateLimiter {
+        RateLimiter { store: Box::new(store) }
+    }
+
+    /// Runs `f` if none of the keys is throttled.
+    /// The attempt is recorded as a failure on all the keys before running `f`, and kept only if it failed (see [`is_failure`]):
+    /// a success resets the account keys and releases the IP keys, other errors release all the keys.
+    /// - Throw `TooManyRequests` if one of the keys is throttled.
+    pub fn limit<T, F>(&self, keys: &[Option<RateLimitKey>], f: F) -> Result<T, ErrorResponder>
+    where
+        F: FnOnce() -> Result<T, ErrorResponder>,
+    {
+        let keys: Vec<&RateLimitKey> = keys.iter().flatten().collect();
+        self.reserve(&keys)?;
+
+        let result = f();
+        match &result {
+            Err(err) if is_failure(err) => {}
+            Ok(_) => {
+                keys.iter().for_each(|key| match key {
+                    RateLimitKey::Account(..) => self.store.reset(&key.to_key()),
+                    RateLimitKey::Ip(..) => self.store.release(&key.to_key()),
+                });
+            }
+            Err(_) => keys.iter().for_each(|key| self.store.release(&key.to_key())),
+        }
+        result
+    }
+
+    /// Runs `f` if none of the keys is throttled, recording every attempt, successful or not, on all the keys.
+    /// Used for unauthenticated actions that allocate server resources, where a success is not a proof of legitimacy.
+    /// - Throw `TooManyRequests` if one of the keys is throttled.
+    pub fn limit_every_attempt<T, F>(&self, keys: &[Option<RateLimitKey>], f: F) -> Result<T, ErrorResponder>
+    where
+        F: FnOnce() -> Result<T, ErrorResponder>,
+    {
+        let keys: Vec<&RateLimitKey> = keys.iter().flatten().collect();
+        self.reserve(&keys)?;
+        f()
+    }
+
+    /// Reserves an attempt on all the keys (see [`RateLimitStore::reserve`]).
+    /// - Throw `TooManyRequests` if one of the keys is throttled.
+    fn reserve(&self, keys: &[&RateLimitKey]) -> Result<(), ErrorResponder> {
+        let keys: Vec<String> = keys.iter().map(|key| key.to_key()).collect();
+        self.store.reserve(&keys).or_else(|locked_until| {
+            ErrorType::TooManyRequests(locked_until.saturating_duration_since(Instant::now()).as_secs() + 1).res_err()
+        })
+    }
+}
+
+/// Errors counted as a failed attempt: wrong credentials, codes or tokens, or an already used email.
+fn is_failure(err: &ErrorResponder) -> bool {
+    matches!(
+        err.error_type(),
+        ErrorTypeKind::InvalidEmailOrPassword
+            | ErrorTypeKind::InvalidPassword
+            | ErrorTypeKind::InvalidTOTPCode
+            | ErrorTypeKind::InvalidRecoveryCode
+            | ErrorTypeKind::InvalidPasskey
+            | ErrorTypeKind::EmailAlreadyExists
+            | ErrorTypeKind::ConfirmationNotFound
+            | ErrorTypeKind::ConfirmationTooManyAttempts
+    )
+}
@@ -1,5 +1,18 @@
+use crate::utils::config::config;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
 use rand::rngs::OsRng;
 use rand::RngCore;
+use sha2::Sha256;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use subtle::ConstantTimeEq;
+
+lazy_static! {
+    /// Secret key used to hash the tokens stored in database, from the `TOKEN_HASH_KEY` configuration (hex encoded)
+    static ref TOKEN_HASH_KEY: Vec<u8> = {
+        hex::decode(&config().token_hash_key).expect("TOKEN_HASH_KEY must be hex encoded")
+    };
+}
 
 /// Generates a random hex token of the given length (one byte = two characters)
 pub fn random_token(bytes: usize) -> Vec<u8> {
@@ -8,6 +21,19 @@ pub fn random_token(bytes: usize) -> Vec<u8> {
     auth_token
 }
 
+/// Computes the keyed hash (HMAC-SHA256 with `TOKEN_HASH_KEY`) of a token.
+/// Only this hash is stored in database, so that a database leak does not expose usable tokens.
+pub fn hash_token(token: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&TOKEN_HASH_KEY).expect("HMAC accepts keys of any size");
+    mac.update(token);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Compares two byte slices in constant time (regarding their content)
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
 /// Generates a random integer code of the given number of digits
 pub fn random_code(digits: u32) -> u32 {
     OsRng.next_u32() % 10u32.pow(digits)
@@ -23,11 +49,34 @@ pub fn left_pad(string: &str, char: char, target_length: usize) -> String {
     res
 }
 
-/// Gets the frontend host from the environment variable `FRONTEND_HOST`
+/// Converts an IP address stored in binary form (with MySQL `INET6_ATON`) to an [`IpAddr`]
+pub fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => <[u8; 4]>::try_from(bytes).ok().map(|b| IpAddr::V4(Ipv4Addr::from(b))),
+        16 => <[u8; 16]>::try_from(bytes).ok().map(|b| IpAddr::V6(Ipv6Addr::from(b))),
+        _ => None,
+    }
+}
+
+/// Gets the frontend host from the `FRONTEND_HOST` configuration
 pub fn get_frontend_host() -> String {
-    std::env::var("FRONTEND_HOST").expect("FRONTEND_HOST must be set")
+    config().frontend_host.clone()
 }
-/// Gets the backend host from the environment variables `BACKEND_HOST`
+/// Gets the backend host from the `BACKEND_HOST` configuration
 pub fn get_backend_host() -> String {
-    std::env::var("BACKEND_HOST").expect("BACKEND_HOST must be set")
+    config().backend_host.clone()
+}
+/// Gets the number of TOTP time steps accepted before and after the current one from the
+/// `TOTP_SKEW_STEPS` configuration (defaults to 1, i.e. ±30 seconds)
+pub fn get_totp_skew_steps() -> u8 {
+    config().totp_skew_steps
+}
+/// Gets the absolute lifetime of an auth token, in days, from the `AUTH_TOKEN_MAX_AGE_DAYS` configuration (defaults to 90)
+pub fn get_auth_token_max_age_days() -> i64 {
+    config().auth_token_max_age_days
+}
+/// Gets the idle lifetime of an auth token (maximum time between two uses), in days, from the
+/// `AUTH_TOKEN_IDLE_DAYS` configuration (defaults to 14)
+pub fn get_auth_token_idle_days() -> i64 {
+    config().auth_token_idle_days
 }
@@ -3,6 +3,8 @@ use std::borrow::Cow;
 use validator::{Validate, ValidationError};
 
 use crate::utils::errors_catcher::{ErrorResponder, ErrorType};
+use crate::utils::utils::get_frontend_host;
+use webauthn_rs::prelude::Url;
 
 /// Validate request data using the [`Validate`] trait from the `validator` crate.
 /// If the data is invalid, return an [`ErrorResponder`] with the proper error message.
@@ -49,3 +51,14 @@ pub fn validate_password(value: &str) -> Result<(), ValidationError> {
     }
     Ok(())
 }
+
+/// Custom validator for a redirect URL, to prevent open redirects
+/// - Must be an absolute URL on the origin of `FRONTEND_HOST` (same scheme, host and port)
+pub fn validate_redirect_url(value: &str) -> Result<(), ValidationError> {
+    let frontend_origin = Url::parse(&get_frontend_host()).map(|url| url.origin());
+    match (Url::parse(value), frontend_origin) {
+        (Ok(url), Ok(frontend_origin)) if url.origin() == frontend_origin => Ok(()),
+        _ => Err(ValidationError::new("redirect_url_origin")
+            .with_message(Cow::from("Redirect URL must be on the frontend host"))),
+    }
+}
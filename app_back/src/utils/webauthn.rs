@@ -0,0 +1,72 @@
+use crate::utils::errors_catcher::{ErrorResponder, ErrorType};
+use crate::utils::utils::{get_frontend_host, random_token};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use webauthn_rs::prelude::{DiscoverableAuthentication, PasskeyAuthentication, PasskeyRegistration, Url, Uuid};
+use webauthn_rs::{Webauthn, WebauthnBuilder};
+
+/// Time allowed to the browser to complete a WebAuthn ceremony.
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+/// Maximum number of started ceremonies kept in memory, to bound the memory used by unauthenticated requests.
+const MAX_CHALLENGES: usize = 10_000;
+
+/// Builds the WebAuthn relying party from `FRONTEND_HOST`: the RP id is the host name, the origin is the full URL.
+pub fn build_webauthn() -> Webauthn {
+    let origin = Url::parse(&get_frontend_host()).expect("FRONTEND_HOST must be a valid URL");
+    let rp_id = origin.host_str().expect("FRONTEND_HOST must have a host").to_string();
+    WebauthnBuilder::new(&rp_id, &origin)
+        .expect("Invalid WebAuthn relying party configuration")
+        .rp_name("Archypix")
+        .build()
+        .expect("Invalid WebAuthn relying party configuration")
+}
+
+/// WebAuthn user handle of a user, derived from its id.
+pub fn user_handle(user_id: &u32) -> Uuid {
+    Uuid::from_u64_pair(0, *user_id as u64)
+}
+/// User id from a WebAuthn user handle (see [`user_handle`]).
+pub fn user_id_from_handle(handle: &Uuid) -> u32 {
+    handle.as_u64_pair().1 as u32
+}
+
+/// Server side state of a started WebAuthn ceremony.
+pub enum WebauthnChallenge {
+    Registration { user_id: u32, name: String, state: PasskeyRegistration },
+    /// Passkey used as a second factor, after the password check
+    Authentication { user_id: u32, state: PasskeyAuthentication },
+    /// Passwordless sign in, the user is identified by the passkey
+    Discoverable { state: DiscoverableAuthentication },
+}
+
+/// Started WebAuthn ceremonies, identified by a random challenge id sent to the browser.
+/// Managed by Rocket, used in endpoints through `&rocket::State<WebauthnChallenges>`.
+#[derive(Default)]
+pub struct WebauthnChallenges {
+    challenges: Mutex<HashMap<String, (Instant, WebauthnChallenge)>>,
+}
+
+impl WebauthnChallenges {
+    /// Stores the challenge state, returning its id.
+    /// - Throw `TooManyRequests` if [`MAX_CHALLENGES`] ceremonies are already started and not expired.
+    pub fn insert(&self, challenge: WebauthnChallenge) -> Result<String, ErrorResponder> {
+        let id = hex::encode(random_token(16));
+        let mut challenges = self.challenges.lock().unwrap();
+        challenges.retain(|_, (date, _)| date.elapsed() < CHALLENGE_LIFETIME);
+        if challenges.len() >= MAX_CHALLENGES {
+            let oldest = challenges.values().map(|(date, _)| *date).min().unwrap_or(Instant::now());
+            return ErrorType::TooManyRequests(CHALLENGE_LIFETIME.saturating_sub(oldest.elapsed()).as_secs() + 1).res_err();
+        }
+        challenges.insert(id.clone(), (Instant::now(), challenge));
+        Ok(id)
+    }
+    /// Removes and returns the challenge state, if it exists and is not expired.
+    /// A challenge can only be used once.
+    pub fn take(&self, id: &str) -> Option<WebauthnChallenge> {
+        self.challenges.lock().unwrap()
+            .remove(id)
+            .filter(|(date, _)| date.elapsed() < CHALLENGE_LIFETIME)
+            .map(|(_, challenge)| challenge)
+    }
+}